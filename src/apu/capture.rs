@@ -0,0 +1,89 @@
+use std::mem;
+
+use super::resampler::Resampler;
+use super::filter::{FilterChain, FilterProfile};
+
+// A copy of the output kept aside for recording, separate from the
+// samples the frontend takes so playing and recording don't fight over
+// them. Channels get a resampler and filters of their own, each one as it
+// would sound with the others silent.
+#[derive(Debug)]
+pub struct Capture {
+    mix: Vec<f32>,
+    channels: Vec<ChannelTap>,
+}
+
+#[derive(Debug)]
+struct ChannelTap {
+    level: f32,
+    resampler: Resampler,
+    filters: FilterChain,
+    samples: Vec<f32>,
+}
+
+impl Capture {
+    // count is how many channels to capture on their own, 0 for just the mix
+    pub fn new(count: usize, profile: FilterProfile, rate: u32) -> Capture {
+        let channels = (0..count)
+            .map(|_| {
+                ChannelTap {
+                    level: 0.0,
+                    resampler: Resampler::new(rate),
+                    filters: FilterChain::new(profile, rate),
+                    samples: Vec::new(),
+                }
+            })
+            .collect();
+        Capture {
+            mix: Vec::new(),
+            channels: channels,
+        }
+    }
+
+    pub fn has_channels(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    pub fn set_rate(&mut self, rate: u32) {
+        for tap in self.channels.iter_mut() {
+            tap.resampler.set_rate(rate);
+            tap.filters.set_rate(rate);
+        }
+    }
+
+    pub fn set_filter_profile(&mut self, profile: FilterProfile, rate: u32) {
+        for tap in self.channels.iter_mut() {
+            tap.filters = FilterChain::new(profile, rate);
+        }
+    }
+
+    pub fn push_mix(&mut self, samples: &[f32]) {
+        self.mix.extend_from_slice(samples);
+    }
+
+    // Once per CPU cycle with each channel's level.
+    pub fn clock_channels(&mut self, levels: &[f32]) {
+        for (tap, &level) in self.channels.iter_mut().zip(levels) {
+            if level != tap.level {
+                tap.resampler.add_delta(level - tap.level);
+                tap.level = level;
+            }
+
+            let start = tap.samples.len();
+            tap.resampler.clock(&mut tap.samples);
+            for sample in tap.samples[start..].iter_mut() {
+                *sample = tap.filters.process(*sample);
+            }
+        }
+    }
+
+    // The mix and the channels captured since the last call.
+    pub fn take(&mut self) -> (Vec<f32>, Vec<Vec<f32>>) {
+        let mix = mem::replace(&mut self.mix, Vec::new());
+        let channels = self.channels
+            .iter_mut()
+            .map(|tap| mem::replace(&mut tap.samples, Vec::new()))
+            .collect();
+        (mix, channels)
+    }
+}
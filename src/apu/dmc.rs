@@ -0,0 +1,212 @@
+use state::{StateError, StateReader, StateWriter};
+
+// NTSC rates in CPU cycles per output bit.
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// $4010-$4013: plays 1 bit delta encoded samples straight out of PRG
+// memory. The bytes are fetched by stealing cycles from the CPU, which goes
+// through CPU::tick since only the CPU can see the whole bus.
+// http://wiki.nesdev.com/w/index.php/APU_DMC
+#[derive(Debug)]
+pub struct Dmc {
+    irq_enable: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+
+    // the 7 bit DAC, also loaded directly through $4011
+    output_level: u8,
+
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+
+    sample_buffer: Option<u8>,
+    // a fetch has been handed to the CPU but hasn't come back yet
+    fetching: bool,
+
+    shift_reg: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enable: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+
+            output_level: 0,
+
+            sample_addr: 0xC000,
+            sample_len: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+
+            sample_buffer: None,
+            fetching: false,
+
+            shift_reg: 0,
+            bits_remaining: 8,
+            silence: true,
+
+            irq: false,
+        }
+    }
+
+    // reg is the register's offset from $4010
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.irq_enable = value & 0x80 != 0;
+                if !self.irq_enable {
+                    self.irq = false;
+                }
+                self.looping = value & 0x40 != 0;
+                self.timer_period = RATE_TABLE[value as usize & 0x0F];
+            }
+            1 => self.output_level = value & 0x7F,
+            2 => self.sample_addr = 0xC000 + value as u16 * 64,
+            _ => self.sample_len = value as u16 * 16 + 1,
+        }
+    }
+
+    // from $4015, enabling only restarts a sample that has finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // The address of the next sample byte if the buffer wants refilling.
+    // The CPU reads it and hands it back through fill.
+    pub fn take_fetch(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && !self.fetching {
+            self.fetching = true;
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, value: u8) {
+        self.fetching = false;
+        self.sample_buffer = Some(value);
+        // wraps around to $8000, not $0000
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enable {
+                self.irq = true;
+            }
+        }
+    }
+
+    // every CPU cycle, the rate table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_reg & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_reg >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift_reg = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.irq_enable);
+        out.bool(self.looping);
+        out.u16(self.timer_period);
+        out.u16(self.timer);
+        out.u8(self.output_level);
+        out.u16(self.sample_addr);
+        out.u16(self.sample_len);
+        out.u16(self.current_addr);
+        out.u16(self.bytes_remaining);
+        out.bool(self.sample_buffer.is_some());
+        out.u8(self.sample_buffer.unwrap_or(0));
+        out.bool(self.fetching);
+        out.u8(self.shift_reg);
+        out.u8(self.bits_remaining);
+        out.bool(self.silence);
+        out.bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enable = try!(input.bool());
+        self.looping = try!(input.bool());
+        self.timer_period = try!(input.u16());
+        self.timer = try!(input.u16());
+        self.output_level = try!(input.u8()) & 0x7F;
+        self.sample_addr = try!(input.u16());
+        self.sample_len = try!(input.u16());
+        self.current_addr = try!(input.u16());
+        self.bytes_remaining = try!(input.u16());
+        let buffered = try!(input.bool());
+        let buffer = try!(input.u8());
+        self.sample_buffer = if buffered { Some(buffer) } else { None };
+        self.fetching = try!(input.bool());
+        self.shift_reg = try!(input.u8());
+        self.bits_remaining = try!(input.u8());
+        self.silence = try!(input.bool());
+        self.irq = try!(input.bool());
+        if self.timer_period == 0 || self.bits_remaining == 0 {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::f32::consts::PI;
+
+// Which filters the output goes through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterProfile {
+    // what the console's own output stage does: high-pass at 90Hz and
+    // 440Hz, low-pass at 14kHz
+    // http://wiki.nesdev.com/w/index.php/APU_Mixer
+    Hardware,
+    // only enough high-pass to take the DC offset out, keeps the bass the
+    // 440Hz filter thins out
+    Clean,
+    // the mixer levels as they are, 0.0 to 1.0
+    Raw,
+}
+
+impl FilterProfile {
+    pub fn from_name(name: &str) -> Option<FilterProfile> {
+        match name {
+            "hardware" => Some(FilterProfile::Hardware),
+            "clean" => Some(FilterProfile::Clean),
+            "raw" => Some(FilterProfile::Raw),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+// A first order RC filter.
+#[derive(Debug)]
+struct Filter {
+    kind: Kind,
+    cutoff: f32,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(kind: Kind, cutoff: f32) -> Filter {
+        Filter {
+            kind: kind,
+            cutoff: cutoff,
+            alpha: 0.0,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn set_rate(&mut self, rate: u32) {
+        let rc = 1.0 / (2.0 * PI * self.cutoff);
+        let dt = 1.0 / rate as f32;
+        self.alpha = match self.kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            Kind::HighPass => self.alpha * (self.prev_out + input - self.prev_in),
+            Kind::LowPass => self.prev_out + self.alpha * (input - self.prev_out),
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+// The filters of a profile, applied in order.
+#[derive(Debug)]
+pub struct FilterChain {
+    filters: Vec<Filter>,
+}
+
+impl FilterChain {
+    pub fn new(profile: FilterProfile, rate: u32) -> FilterChain {
+        let filters = match profile {
+            FilterProfile::Hardware => vec![
+                Filter::new(Kind::HighPass, 90.0),
+                Filter::new(Kind::HighPass, 440.0),
+                Filter::new(Kind::LowPass, 14000.0),
+            ],
+            FilterProfile::Clean => vec![Filter::new(Kind::HighPass, 20.0)],
+            FilterProfile::Raw => vec![],
+        };
+        let mut chain = FilterChain { filters: filters };
+        chain.set_rate(rate);
+        chain
+    }
+
+    pub fn set_rate(&mut self, rate: u32) {
+        for filter in self.filters.iter_mut() {
+            filter.set_rate(rate);
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.filters.iter_mut().fold(sample, |s, filter| filter.process(s))
+    }
+}
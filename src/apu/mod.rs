@@ -0,0 +1,606 @@
+use std::cmp;
+use std::mem;
+
+mod units;
+mod pulse;
+mod triangle;
+mod noise;
+mod dmc;
+mod resampler;
+mod filter;
+mod capture;
+
+use self::pulse::Pulse;
+use self::triangle::Triangle;
+use self::noise::Noise;
+use self::dmc::Dmc;
+use self::resampler::Resampler;
+use self::filter::FilterChain;
+use self::capture::Capture;
+
+pub use self::filter::FilterProfile;
+
+use state::{StateError, StateReader, StateWriter};
+
+const APU_STATUS_REG: u16 = 0x4015;
+const FRAME_COUNTER_REG: u16 = 0x4017;
+
+// NTSC CPU clock in Hz, the APU runs off the same clock.
+pub const CPU_CLOCK: u32 = 1789773;
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Output rates we can produce. The resampler only works downwards from
+// CPU_CLOCK, and the filters need a sensible Nyquist frequency.
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+pub const MAX_SAMPLE_RATE: u32 = 192000;
+
+// The channels in the order captures hand them out, and the bits for
+// them in the channel mask. Expansion is the cartridge's sound chip.
+pub const CHANNEL_NAMES: [&'static str; 6] = ["pulse1", "pulse2", "triangle", "noise", "dmc",
+                                              "expansion"];
+pub const ALL_CHANNELS: u8 = 0x3F;
+
+// A comma separated list of CHANNEL_NAMES as a channel mask.
+pub fn parse_channel_list(list: &str) -> Option<u8> {
+    let mut mask = 0;
+    for name in list.split(',') {
+        match CHANNEL_NAMES.iter().position(|&n| n == name) {
+            Some(channel) => mask |= 1 << channel,
+            None => return None,
+        }
+    }
+    Some(mask)
+}
+
+// What a pulse channel at full volume puts out on its own, expansion audio
+// comes in measured against it. pulse_out(15)
+const PULSE_FULL_VOLUME: f32 = 0.14937;
+
+// The scope takes a point every this many CPU cycles, about 1000 a frame.
+const SCOPE_INTERVAL: u64 = 29;
+// and keeps a few frames' worth if nobody takes them
+const SCOPE_MAX_LEN: usize = 4096;
+
+// Frame sequencer steps, in CPU cycles since the sequence started. Both
+// modes share the first three steps.
+// http://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+const STEP_1: u32 = 7457;
+const STEP_2: u32 = 14913;
+const STEP_3: u32 = 22371;
+const STEP_4_IRQ: u32 = 29828;
+const STEP_4: u32 = 29829;
+const FOUR_STEP_LEN: u32 = 29830;
+const STEP_5: u32 = 37281;
+const FIVE_STEP_LEN: u32 = 37282;
+
+// The 2A03's sound hardware. Channels are clocked every CPU cycle and the
+// mixed output is resampled down to sample_rate, then filtered.
+// http://wiki.nesdev.com/w/index.php/APU
+#[derive(Debug)]
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    // the cartridge's audio, in pulses at full volume
+    expansion: f32,
+
+    cycle: u64,
+
+    // $4017 - MI------
+    five_step: bool,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // CPU cycles until a $4017 write restarts the sequence, 0 if none is due
+    frame_reset_delay: u8,
+
+    sample_rate: u32,
+    last_mix: f32,
+    resampler: Resampler,
+    filter_profile: FilterProfile,
+    filters: FilterChain,
+    samples: Vec<f32>,
+    capture: Option<Capture>,
+
+    // channels with their bit clear are left out of the mix
+    channel_mask: u8,
+    scope: Option<Vec<[u8; 5]>>,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            expansion: 0.0,
+
+            cycle: 0,
+
+            five_step: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            last_mix: 0.0,
+            resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            filter_profile: FilterProfile::Hardware,
+            filters: FilterChain::new(FilterProfile::Hardware, DEFAULT_SAMPLE_RATE),
+            samples: Vec::new(),
+            capture: None,
+
+            channel_mask: ALL_CHANNELS,
+            scope: None,
+        }
+
+    }
+
+    // Frontends may nudge this every frame to match their audio clock.
+    // Rates outside MIN_SAMPLE_RATE to MAX_SAMPLE_RATE are clamped.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        let rate = cmp::max(MIN_SAMPLE_RATE, cmp::min(rate, MAX_SAMPLE_RATE));
+        self.sample_rate = rate;
+        self.resampler.set_rate(rate);
+        self.filters.set_rate(rate);
+        if let Some(ref mut capture) = self.capture {
+            capture.set_rate(rate);
+        }
+    }
+
+    pub fn set_filter_profile(&mut self, profile: FilterProfile) {
+        self.filter_profile = profile;
+        self.filters = FilterChain::new(profile, self.sample_rate);
+        if let Some(ref mut capture) = self.capture {
+            capture.set_filter_profile(profile, self.sample_rate);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Hands over the samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::replace(&mut self.samples, Vec::new())
+    }
+
+    // Starts keeping a copy of the output for recording, with each channel
+    // on its own as well if channels is set. Restarts any capture running.
+    pub fn start_capture(&mut self, channels: bool) {
+        let count = if channels { CHANNEL_NAMES.len() } else { 0 };
+        self.capture = Some(Capture::new(count, self.filter_profile, self.sample_rate));
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    // What was captured since the last call, the mix and then the channels
+    // in CHANNEL_NAMES order if those are being captured. None if no
+    // capture is running.
+    pub fn take_capture(&mut self) -> Option<(Vec<f32>, Vec<Vec<f32>>)> {
+        self.capture.as_mut().map(|capture| capture.take())
+    }
+
+    // Mutes the channels whose bit is clear, bit n being CHANNEL_NAMES[n].
+    // Only what's played and recorded as the mix changes, channels
+    // recorded on their own and the scope still get everything.
+    pub fn set_channel_mask(&mut self, mask: u8) {
+        self.channel_mask = mask & ALL_CHANNELS;
+    }
+
+    pub fn channel_mask(&self) -> u8 {
+        self.channel_mask
+    }
+
+    // Starts or stops sampling each channel's level for a visualiser.
+    pub fn set_scope(&mut self, enabled: bool) {
+        self.scope = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // The levels sampled since the last call, each point holding the
+    // APU's own channels in CHANNEL_NAMES order. The DMC goes up to 127,
+    // the others to 15.
+    pub fn take_scope(&mut self) -> Vec<[u8; 5]> {
+        match self.scope {
+            Some(ref mut points) => mem::replace(points, Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    // Clocked once per CPU cycle, along with the level of the cartridge's
+    // sound chip.
+    pub fn tick(&mut self, expansion: f32) {
+        self.cycle += 1;
+        self.expansion = expansion;
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.clock_frame_sequencer();
+
+        let mix = self.mix();
+        if mix != self.last_mix {
+            self.resampler.add_delta(mix - self.last_mix);
+            self.last_mix = mix;
+        }
+
+        let start = self.samples.len();
+        self.resampler.clock(&mut self.samples);
+        for sample in self.samples[start..].iter_mut() {
+            *sample = self.filters.process(*sample);
+        }
+
+        if self.cycle % SCOPE_INTERVAL == 0 && self.scope.is_some() {
+            let outputs = self.outputs();
+            let points = self.scope.as_mut().unwrap();
+            points.push(outputs);
+            if points.len() > SCOPE_MAX_LEN {
+                points.drain(..SCOPE_MAX_LEN / 2);
+            }
+        }
+
+        if self.capture.is_some() {
+            let levels = self.channel_levels();
+            let capture = self.capture.as_mut().unwrap();
+            capture.push_mix(&self.samples[start..]);
+            if capture.has_channels() {
+                capture.clock_channels(&levels);
+            }
+        }
+
+        // frontends without audio never take these, so don't hold on to
+        // more than a second
+        let max_len = self.sample_rate as usize;
+        if self.samples.len() > max_len {
+            let excess = self.samples.len() - max_len;
+            self.samples.drain(..excess);
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                // the 5 step mode clocks everything straight away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+        }
+
+        self.frame_cycle += 1;
+        match (self.frame_cycle, self.five_step) {
+            (STEP_1, _) | (STEP_3, _) => self.quarter_frame(),
+            (STEP_2, _) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+
+            // the IRQ flag is set over three cycles around the last step
+            (STEP_4_IRQ, false) => self.set_frame_irq(),
+            (STEP_4, false) => {
+                self.quarter_frame();
+                self.half_frame();
+                self.set_frame_irq();
+            }
+            (FOUR_STEP_LEN, false) => {
+                self.set_frame_irq();
+                self.frame_cycle = 0;
+            }
+
+            (STEP_5, true) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (FIVE_STEP_LEN, true) => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.frame_irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    // $4017 - the sequencer restarts 3 or 4 CPU cycles after the write,
+    // depending on whether it lands on an APU cycle
+    fn write_frame_counter(&mut self, value: u8) {
+        self.five_step = value & 0x80 != 0;
+        self.frame_irq_inhibit = value & 0x40 != 0;
+        if self.frame_irq_inhibit {
+            self.frame_irq = false;
+        }
+        self.frame_reset_delay = if self.cycle % 2 == 0 { 3 } else { 4 };
+    }
+
+    // envelopes and the triangle's linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear();
+        self.noise.envelope.clock();
+    }
+
+    // length counters and sweeps
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // The channels go through two resistor networks, which makes the mix
+    // non-linear. These are the approximations from the wiki, 0.0 to 1.0.
+    // http://wiki.nesdev.com/w/index.php/APU_Mixer
+    // Cartridge audio is mixed in after the APU's own resistor networks, so
+    // it simply adds on.
+    fn mix(&self) -> f32 {
+        let mut out = self.outputs();
+        for (i, level) in out.iter_mut().enumerate() {
+            if self.channel_mask & (1 << i) == 0 {
+                *level = 0;
+            }
+        }
+        let mut mix = pulse_out(out[0] + out[1]) + tnd_out(out[2], out[3], out[4]);
+        if self.channel_mask & (1 << 5) != 0 {
+            mix += self.expansion * PULSE_FULL_VOLUME;
+        }
+        mix
+    }
+
+    // What each channel would put out with the others silent, in
+    // CHANNEL_NAMES order.
+    fn channel_levels(&self) -> [f32; 6] {
+        let out = self.outputs();
+        [pulse_out(out[0]),
+         pulse_out(out[1]),
+         tnd_out(out[2], 0, 0),
+         tnd_out(0, out[3], 0),
+         tnd_out(0, 0, out[4]),
+         self.expansion * PULSE_FULL_VOLUME]
+    }
+
+    // Each channel's DAC input, in CHANNEL_NAMES order.
+    fn outputs(&self) -> [u8; 5] {
+        [self.pulse1.output(),
+         self.pulse2.output(),
+         self.triangle.output(),
+         self.noise.output(),
+         self.dmc.output()]
+    }
+
+    // Only the sound hardware is saved, not where the output is going or
+    // what's been produced already.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        self.pulse1.save_state(out);
+        self.pulse2.save_state(out);
+        self.triangle.save_state(out);
+        self.noise.save_state(out);
+        self.dmc.save_state(out);
+        out.u64(self.cycle);
+        out.bool(self.five_step);
+        out.bool(self.frame_irq_inhibit);
+        out.bool(self.frame_irq);
+        out.u32(self.frame_cycle);
+        out.u8(self.frame_reset_delay);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        try!(self.pulse1.load_state(input));
+        try!(self.pulse2.load_state(input));
+        try!(self.triangle.load_state(input));
+        try!(self.noise.load_state(input));
+        try!(self.dmc.load_state(input));
+        self.cycle = try!(input.u64());
+        self.five_step = try!(input.bool());
+        self.frame_irq_inhibit = try!(input.bool());
+        self.frame_irq = try!(input.bool());
+        self.frame_cycle = try!(input.u32());
+        self.frame_reset_delay = try!(input.u8());
+        Ok(())
+    }
+
+    // A DMC sample fetch for the CPU to do, see Dmc::take_fetch.
+    pub fn take_dmc_fetch(&mut self) -> Option<u16> {
+        self.dmc.take_fetch()
+    }
+
+    pub fn fill_dmc(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000...0x4003 => self.pulse1.write(addr - 0x4000, value),
+            0x4004...0x4007 => self.pulse2.write(addr - 0x4004, value),
+            0x4008...0x400B => self.triangle.write(addr - 0x4008, value),
+            0x400C...0x400F => self.noise.write(addr - 0x400C, value),
+            0x4010...0x4013 => self.dmc.write(addr - 0x4010, value),
+            APU_STATUS_REG => self.write_status_reg(value),
+            FRAME_COUNTER_REG => self.write_frame_counter(value),
+            _ => {}
+        }
+    }
+
+    // $4015 - IF-DNT21, which channels are still playing and the IRQ flags.
+    // Reading acknowledges the frame IRQ.
+    pub fn read_status_reg(&mut self) -> u8 {
+        let mut value = 0;
+        if self.pulse1.length.active() {
+            value |= 1 << 0;
+        }
+        if self.pulse2.length.active() {
+            value |= 1 << 1;
+        }
+        if self.triangle.length.active() {
+            value |= 1 << 2;
+        }
+        if self.noise.length.active() {
+            value |= 1 << 3;
+        }
+        if self.dmc.active() {
+            value |= 1 << 4;
+        }
+        if self.frame_irq {
+            value |= 1 << 6;
+            self.frame_irq = false;
+        }
+        if self.dmc.irq {
+            value |= 1 << 7;
+        }
+        value
+    }
+
+    fn write_status_reg(&mut self, value: u8) {
+        self.dmc.set_enabled((value & (1 << 4)) != 0);             //D
+        self.noise.length.set_enabled((value & (1 << 3)) != 0);    //N
+        self.triangle.length.set_enabled((value & (1 << 2)) != 0); //T
+        self.pulse2.length.set_enabled((value & (1 << 1)) != 0);   //2
+        self.pulse1.length.set_enabled((value & (1 << 0)) != 0);   //1
+    }
+}
+
+fn pulse_out(pulse: u8) -> f32 {
+    if pulse == 0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse as f32 + 100.0)
+    }
+}
+
+fn tnd_out(triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let tnd = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+    if tnd == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd + 100.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes $4017 on the APU's current cycle, then starts pulse 1 with a
+    // length of 2 so the half frame clocks show up in $4015.
+    fn start(apu: &mut APU, frame_counter: u8) {
+        apu.write(FRAME_COUNTER_REG, frame_counter);
+        apu.write(APU_STATUS_REG, 0x01);
+        apu.write(0x4000, 0x10);
+        apu.write(0x4003, 0x18);
+    }
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick(0.0);
+        }
+    }
+
+    fn pulse1_playing(apu: &mut APU) -> bool {
+        apu.read_status_reg() & 0x01 != 0
+    }
+
+    #[test]
+    fn four_step_irq_comes_after_the_fourth_step() {
+        let mut apu = APU::new();
+        start(&mut apu, 0x00);
+        // the write on an even cycle takes effect after 3
+        run(&mut apu, 3 + STEP_4_IRQ - 2);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+    }
+
+    #[test]
+    fn four_step_irq_is_raised_for_three_cycles() {
+        let mut apu = APU::new();
+        start(&mut apu, 0x00);
+        run(&mut apu, 3 + STEP_4_IRQ - 1);
+        // acknowledging it on the first of them doesn't stick
+        apu.read_status_reg();
+        assert!(!apu.frame_irq());
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+        apu.read_status_reg();
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+        apu.read_status_reg();
+        run(&mut apu, STEP_1);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn write_on_an_odd_cycle_takes_a_cycle_longer() {
+        let mut apu = APU::new();
+        run(&mut apu, 1);
+        start(&mut apu, 0x00);
+        run(&mut apu, 4 + STEP_4_IRQ - 2);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+    }
+
+    #[test]
+    fn four_step_clocks_half_frames_at_steps_2_and_4() {
+        let mut apu = APU::new();
+        start(&mut apu, 0x00);
+        run(&mut apu, 3 + STEP_2 - 1);
+        assert!(pulse1_playing(&mut apu));
+        run(&mut apu, STEP_4 - STEP_2 - 1);
+        assert!(pulse1_playing(&mut apu));
+        run(&mut apu, 1);
+        assert!(!pulse1_playing(&mut apu));
+    }
+
+    #[test]
+    fn five_step_clocks_straight_away_and_never_raises_the_irq() {
+        let mut apu = APU::new();
+        start(&mut apu, 0x80);
+        // the immediate half frame, then the one at step 2
+        run(&mut apu, 3 + STEP_2 - 2);
+        assert!(pulse1_playing(&mut apu));
+        run(&mut apu, 1);
+        assert!(!pulse1_playing(&mut apu));
+
+        run(&mut apu, FIVE_STEP_LEN * 2);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn inhibit_clears_and_blocks_the_irq() {
+        let mut apu = APU::new();
+        start(&mut apu, 0x00);
+        run(&mut apu, 3 + FOUR_STEP_LEN);
+        assert!(apu.frame_irq());
+
+        apu.write(FRAME_COUNTER_REG, 0x40);
+        assert!(!apu.frame_irq());
+        run(&mut apu, FOUR_STEP_LEN * 2);
+        assert!(!apu.frame_irq());
+    }
+}
@@ -0,0 +1,97 @@
+use super::units::{Envelope, LengthCounter};
+use state::{StateError, StateReader, StateWriter};
+
+// NTSC periods in CPU cycles.
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// $400C-$400F: pseudo-random output from a 15 bit linear feedback shift
+// register. Mode 1 taps bit 6 instead of bit 1 for a short, metallic loop.
+// http://wiki.nesdev.com/w/index.php/APU_Noise
+#[derive(Debug)]
+pub struct Noise {
+    mode: bool,
+    shift_reg: u16,
+    timer_period: u16,
+    timer: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            mode: false,
+            // loaded with 1 on power up
+            shift_reg: 1,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // reg is the register's offset from $400C, $400D is unused
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.length.set_halt(value & 0x20 != 0);
+                self.envelope.write(value);
+            }
+            2 => {
+                self.mode = value & 0x80 != 0;
+                self.timer_period = PERIOD_TABLE[value as usize & 0x0F];
+            }
+            3 => {
+                self.length.load(value >> 3);
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    // every CPU cycle, the period table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_reg ^ (self.shift_reg >> tap)) & 1;
+            self.shift_reg = (self.shift_reg >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.mode);
+        out.u16(self.shift_reg);
+        out.u16(self.timer_period);
+        out.u16(self.timer);
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.mode = try!(input.bool());
+        self.shift_reg = try!(input.u16());
+        self.timer_period = try!(input.u16());
+        self.timer = try!(input.u16());
+        if self.timer_period == 0 {
+            return Err(StateError::Corrupt);
+        }
+        try!(self.envelope.load_state(input));
+        self.length.load_state(input)
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift_reg & 1 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use super::units::{Envelope, LengthCounter};
+use state::{StateError, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// $4000-$4003 and $4004-$4007: a square wave with a sweep unit that can
+// bend its period up or down.
+// http://wiki.nesdev.com/w/index.php/APU_Pulse
+#[derive(Debug)]
+pub struct Pulse {
+    // pulse 1 negates with one's complement in the sweep unit, so its
+    // downward sweeps end up one lower
+    ones_complement: bool,
+
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+
+    // $4001/$4005 - EPPPNSSS
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement: ones_complement,
+
+            duty: 0,
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // reg is the register's offset from the channel's first one
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.duty = value >> 6;
+                self.length.set_halt(value & 0x20 != 0);
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0xFF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    // every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let extra = if self.ones_complement { 1 } else { 0 };
+            self.timer_period.saturating_sub(change + extra)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit mutes the channel whenever the period is too low or
+    // the next one would overflow, even while it's disabled.
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.target_period() > 0x7FF
+    }
+
+    // every half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.duty);
+        out.u8(self.step);
+        out.u16(self.timer_period);
+        out.u16(self.timer);
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+        out.bool(self.sweep_enabled);
+        out.u8(self.sweep_period);
+        out.bool(self.sweep_negate);
+        out.u8(self.sweep_shift);
+        out.u8(self.sweep_divider);
+        out.bool(self.sweep_reload);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.duty = try!(input.u8()) & 0b11;
+        self.step = try!(input.u8()) & 0b111;
+        self.timer_period = try!(input.u16());
+        self.timer = try!(input.u16());
+        try!(self.envelope.load_state(input));
+        try!(self.length.load_state(input));
+        self.sweep_enabled = try!(input.bool());
+        self.sweep_period = try!(input.u8());
+        self.sweep_negate = try!(input.bool());
+        self.sweep_shift = try!(input.u8()) & 0b111;
+        self.sweep_divider = try!(input.u8());
+        self.sweep_reload = try!(input.bool());
+        Ok(())
+    }
+
+    pub fn output(&self) -> u8 {
+        let high = DUTY_TABLE[self.duty as usize][self.step as usize] != 0;
+        if !high || !self.length.active() || self.muted() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A pulse playing duty 3 at constant volume 15, on a step that's high.
+    fn pulse(ones_complement: bool, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(ones_complement);
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0xFF);
+        pulse.write(2, period as u8);
+        pulse.write(3, (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn periods_under_8_are_muted() {
+        assert_eq!(pulse(false, 7).output(), 0);
+        assert_eq!(pulse(false, 8).output(), 15);
+    }
+
+    #[test]
+    fn overflowing_target_mutes_even_with_the_sweep_off() {
+        let mut pulse = pulse(false, 0x400);
+        // shift 0 doubles the period
+        pulse.write(1, 0x00);
+        assert_eq!(pulse.output(), 0);
+        pulse.write(1, 0x01);
+        assert_eq!(pulse.output(), 15);
+        // negated it can't overflow
+        pulse.write(1, 0x08);
+        assert_eq!(pulse.output(), 15);
+    }
+
+    #[test]
+    fn muted_sweep_leaves_the_period_alone() {
+        let mut pulse = pulse(false, 0x7F0);
+        pulse.write(1, 0x81);
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x7F0);
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn pulse_1_negates_one_lower() {
+        let mut pulse1 = pulse(true, 0x100);
+        let mut pulse2 = pulse(false, 0x100);
+        for pulse in [&mut pulse1, &mut pulse2].iter_mut() {
+            // enabled, divider period 0, negate, shift 1
+            pulse.write(1, 0x89);
+            pulse.clock_sweep();
+        }
+        assert_eq!(pulse1.timer_period, 0x7F);
+        assert_eq!(pulse2.timer_period, 0x80);
+    }
+}
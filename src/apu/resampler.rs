@@ -0,0 +1,109 @@
+use std::f64::consts::PI;
+
+use super::CPU_CLOCK;
+
+// Sub-sample positions a step can land on.
+const PHASES: usize = 64;
+// Taps per step, half on each side of it.
+const WIDTH: usize = 16;
+// Finished samples are handed out in batches of this size.
+const BATCH_LEN: usize = 32;
+// Cutoff as a fraction of the output rate, a little under Nyquist so the
+// kernel's roll-off doesn't let images through.
+const CUTOFF: f64 = 0.45;
+
+// Band-limited synthesis from the APU's clock rate down to the output rate.
+// The mixer's output only ever changes in steps, so instead of filtering
+// 1.79 million samples a second each step is drawn straight into the
+// output as a band-limited one. We store the derivative, a windowed sinc
+// impulse per step, and integrate it when samples are read out.
+// http://www.slack.net/~ant/bl-synth/
+#[derive(Debug)]
+pub struct Resampler {
+    rate: u32,
+    // the position of the current CPU cycle, in output samples times
+    // CPU_CLOCK, relative to buffer[0]
+    offset: u64,
+    buffer: Vec<f64>,
+    integrator: f64,
+    kernel: Vec<[f64; WIDTH]>,
+}
+
+impl Resampler {
+    pub fn new(rate: u32) -> Resampler {
+        Resampler {
+            rate: rate,
+            offset: 0,
+            buffer: vec![0.0; BATCH_LEN + WIDTH + 1],
+            integrator: 0.0,
+            kernel: build_kernel(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+    }
+
+    // The mixer output moved by delta during the current CPU cycle.
+    pub fn add_delta(&mut self, delta: f32) {
+        let pos = (self.offset / CPU_CLOCK as u64) as usize;
+        let phase = ((self.offset % CPU_CLOCK as u64) * PHASES as u64 / CPU_CLOCK as u64) as usize;
+        let taps = &self.kernel[phase];
+        for i in 0..WIDTH {
+            self.buffer[pos + i] += delta as f64 * taps[i];
+        }
+    }
+
+    // Moves on to the next CPU cycle. Once a batch of samples can no longer
+    // change they're appended to out.
+    pub fn clock(&mut self, out: &mut Vec<f32>) {
+        self.offset += self.rate as u64;
+
+        let done = (self.offset / CPU_CLOCK as u64) as usize;
+        if done < BATCH_LEN {
+            return;
+        }
+
+        for i in 0..done {
+            self.integrator += self.buffer[i];
+            out.push(self.integrator as f32);
+        }
+        for i in 0..self.buffer.len() {
+            self.buffer[i] = if i + done < self.buffer.len() {
+                self.buffer[i + done]
+            } else {
+                0.0
+            };
+        }
+        self.offset -= done as u64 * CPU_CLOCK as u64;
+    }
+}
+
+// One row of taps per phase, each a Blackman windowed sinc that sums to 1
+// so a step of delta always ends up exactly delta higher.
+fn build_kernel() -> Vec<[f64; WIDTH]> {
+    let half = (WIDTH / 2) as f64;
+    let mut kernel = Vec::with_capacity(PHASES);
+    for phase in 0..PHASES {
+        let frac = phase as f64 / PHASES as f64;
+        let mut taps = [0.0; WIDTH];
+        let mut sum = 0.0;
+        for i in 0..WIDTH {
+            let x = i as f64 - (half - 1.0) - frac;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                let t = PI * 2.0 * CUTOFF * x;
+                t.sin() / t
+            };
+            let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+            taps[i] = sinc * window;
+            sum += taps[i];
+        }
+        for tap in taps.iter_mut() {
+            *tap /= sum;
+        }
+        kernel.push(taps);
+    }
+    kernel
+}
@@ -0,0 +1,112 @@
+use super::units::LengthCounter;
+use state::{StateError, StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// $4008-$400B: a fixed volume triangle wave. Besides the length counter
+// there's a finer grained linear counter, and either one running out
+// freezes the wave where it is rather than silencing it.
+// http://wiki.nesdev.com/w/index.php/APU_Triangle
+#[derive(Debug)]
+pub struct Triangle {
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+
+    pub length: LengthCounter,
+
+    // $4008 - CRRRRRRR, C doubles as the length counter halt flag
+    linear_control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+
+            length: LengthCounter::new(),
+
+            linear_control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+        }
+    }
+
+    // reg is the register's offset from $4008, $4009 is unused
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.linear_control = value & 0x80 != 0;
+                self.length.set_halt(self.linear_control);
+                self.linear_reload_value = value & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x700) | value as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    // every CPU cycle, twice as fast as the pulses
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // every quarter frame
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.linear_control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.step);
+        out.u16(self.timer_period);
+        out.u16(self.timer);
+        self.length.save_state(out);
+        out.bool(self.linear_control);
+        out.u8(self.linear_reload_value);
+        out.u8(self.linear_counter);
+        out.bool(self.linear_reload);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.step = try!(input.u8()) & 0x1F;
+        self.timer_period = try!(input.u16());
+        self.timer = try!(input.u16());
+        try!(self.length.load_state(input));
+        self.linear_control = try!(input.bool());
+        self.linear_reload_value = try!(input.u8());
+        self.linear_counter = try!(input.u8());
+        self.linear_reload = try!(input.bool());
+        Ok(())
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
@@ -0,0 +1,152 @@
+use state::{StateError, StateReader, StateWriter};
+
+// Pieces shared by several channels.
+
+// Length counter load values, indexed by the top 5 bits of a channel's
+// fourth register.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Either a constant volume or a sawtooth decaying from 15, clocked every
+// quarter frame.
+// http://wiki.nesdev.com/w/index.php/APU_Envelope
+#[derive(Debug)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // the constant volume, or the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LCVVVV, the L bit doubles as the length counter halt flag
+    pub fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.start);
+        out.bool(self.looping);
+        out.bool(self.constant);
+        out.u8(self.volume);
+        out.u8(self.divider);
+        out.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.start = try!(input.bool());
+        self.looping = try!(input.bool());
+        self.constant = try!(input.bool());
+        self.volume = try!(input.u8());
+        self.divider = try!(input.u8());
+        self.decay = try!(input.u8());
+        Ok(())
+    }
+}
+
+// Silences a channel once it counts down to 0, clocked every half frame.
+// http://wiki.nesdev.com/w/index.php/APU_Length_Counter
+#[derive(Debug)]
+pub struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    // from $4015, disabling clears the counter right away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize & 0x1F];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.enabled);
+        out.bool(self.halt);
+        out.u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = try!(input.bool());
+        self.halt = try!(input.bool());
+        self.counter = try!(input.u8());
+        Ok(())
+    }
+}
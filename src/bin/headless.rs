@@ -0,0 +1,104 @@
+extern crate oxidenes;
+
+#[path = "../cli.rs"]
+mod cli;
+
+use std::env;
+use std::path::PathBuf;
+
+use oxidenes::Nes;
+use oxidenes::movie::Movie;
+use oxidenes::rom;
+use oxidenes::apu::{self, FilterProfile};
+
+use cli::{fail, parse_arg, parse_arg_in, parse_channels, parse_filter, warn};
+
+// Runs as fast as it can with no window or sound device, for recording
+// audio and checking movies in scripts and CI. Only needs the core, so it
+// builds without SDL2. The battery save is never read or written, so runs
+// don't depend on or change what's on disk.
+//
+// Without --frames it runs until the movie being played is over. The CRC
+// of the final save state is the only thing printed to stdout so runs can
+// be compared, everything else goes to stderr.
+fn main() {
+    let mut rompath: Option<String> = None;
+    let mut sample_rate = 44100;
+    let mut filter_profile = FilterProfile::Hardware;
+    let mut record_path: Option<PathBuf> = None;
+    let mut record_channels = false;
+    let mut max_frames: Option<u32> = None;
+    let mut channel_mask = apu::ALL_CHANNELS;
+    let mut movie_play_path: Option<PathBuf> = None;
+    let mut sprite_limit = true;
+    let mut trace = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sample-rate" => {
+                sample_rate = parse_arg_in(&arg,
+                                           args.next(),
+                                           apu::MIN_SAMPLE_RATE,
+                                           apu::MAX_SAMPLE_RATE)
+            }
+            "--audio-filter" => filter_profile = parse_filter(args.next()),
+            "--record-audio" => record_path = args.next().map(PathBuf::from),
+            "--record-channels" => record_channels = true,
+            "--frames" => max_frames = Some(parse_arg(&arg, args.next())),
+            "--play-movie" => movie_play_path = args.next().map(PathBuf::from),
+            "--no-sprite-limit" => sprite_limit = false,
+            "--trace" => trace = true,
+            "--channels" => channel_mask = parse_channels(args.next()),
+            _ => rompath = Some(arg),
+        }
+    }
+
+    let rompath = match rompath {
+        Some(rompath) => rompath,
+        None => fail(format_args!("usage: oxidenes-headless ROM (--frames N | --play-movie FILE)")),
+    };
+    let mut nes = match Nes::with_options(&rompath, None, false) {
+        Ok(nes) => nes,
+        Err(e) => fail(format_args!("Couldn't load {}: {}", rompath, e)),
+    };
+    nes.set_sample_rate(sample_rate);
+    nes.set_filter_profile(filter_profile);
+    nes.set_channel_mask(channel_mask);
+    nes.set_sprite_limit(sprite_limit);
+    nes.set_trace(trace);
+
+    if let Some(ref path) = movie_play_path {
+        let result = Movie::load(path).and_then(|movie| nes.play_movie(movie));
+        if let Err(e) = result {
+            fail(format_args!("Couldn't play {}: {}", path.display(), e));
+        }
+    }
+    // a movie runs to its end unless told otherwise
+    if max_frames.is_none() && !nes.is_playing_movie() {
+        fail(format_args!("needs --frames or --play-movie"));
+    }
+
+    if let Some(ref path) = record_path {
+        if let Err(e) = nes.start_recording(path, record_channels) {
+            fail(format_args!("Couldn't start recording to {}: {}", path.display(), e));
+        }
+    }
+
+    let mut frame = 0;
+    while max_frames.map_or(nes.is_playing_movie(), |frames| frame < frames) {
+        if !nes.run_frame() {
+            warn(format_args!("CPU jammed at {:#06X}", nes.cpu.program_counter));
+            break;
+        }
+        nes.take_samples();
+        frame += 1;
+    }
+
+    if let Err(e) = nes.stop_recording() {
+        fail(format_args!("Couldn't finish the audio recording: {}", e));
+    }
+    println!("Ran {} frames, state CRC32 {:08X}",
+             frame,
+             rom::crc32(&nes.save_state()));
+}
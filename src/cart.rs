@@ -1,207 +1,272 @@
 use std::fmt;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
-use mem_map::*;
-const INES_OFFSET: u16 = 0x10;
+use mapper::{self, SharedMapper};
+use rom::{Rom, RomError, RomHeader};
+use state::{StateError, StateReader, StateWriter};
 
+// Pattern table memory on the cartridge. ROM ignores writes, which games
+// sometimes do by accident; RAM is whatever size the header asks for.
 #[derive(Debug)]
-pub struct ChrRom {
-    rom: Box<[u8]>,
-
-    prg_rom_banks: u8,
-    chr_rom_banks: u8,
-    pub horizontal_mirroring: bool,
-    pub vertical_mirroring: bool,
-    pub four_screen_vram: bool,
-
-    _mapper: u8,
+pub struct Chr {
+    data: Box<[u8]>,
+    ram: bool,
 }
 
-// TODO: separate rom_file reads to read only the relevant parts
-impl ChrRom {
-    pub fn new(rompath: &String) -> ChrRom {
-
-        let romfile = read_rom_file(rompath);
+impl Chr {
+    pub fn rom(data: Box<[u8]>) -> Chr {
+        Chr {
+            data: data,
+            ram: false,
+        }
+    }
 
-        let mut chr = ChrRom {
-            prg_rom_banks: romfile[4],
-            chr_rom_banks: romfile[5],
+    pub fn ram(len: usize) -> Chr {
+        Chr {
+            data: vec![0; len].into_boxed_slice(),
+            ram: true,
+        }
+    }
 
-            horizontal_mirroring: romfile[6] & (1 << 0) == 0,
-            vertical_mirroring: romfile[6] & (1 << 0) != 0,
-            four_screen_vram: romfile[6] & (2 << 3) != 0,
-            //           prg_ram_present: false,
-            //           trainer: false,
-            _mapper: (romfile[6] & 0b11110000) >> 4 | romfile[7] & 0b11110000,
+    pub fn is_ram(&self) -> bool {
+        self.ram
+    }
 
-            rom: vec![0; 0x2000].into_boxed_slice(),
-        };
+    pub fn read_u8(&self, addr: u16) -> u8 {
+        self.data[addr as usize % self.data.len()]
+    }
 
-        if chr.chr_rom_banks != 0 {
-            let offset = INES_OFFSET as usize + (1024 * 16 * chr.prg_rom_banks as usize);
-            let mut rom = Vec::new();
-            rom.extend_from_slice(&romfile[offset..]);
-            chr.rom = rom.into_boxed_slice();
+    pub fn write_u8(&mut self, addr:u16, data: u8) {
+        if self.ram {
+            let len = self.data.len();
+            self.data[addr as usize % len] = data;
         }
+    }
 
-        chr
+    // For mappers that switch CHR in bank_len sized chunks: addr is the PPU
+    // address inside the window, bank is the bank selected for it.
+    pub fn read_banked_u8(&self, bank: usize, bank_len: usize, addr: u16) -> u8 {
+        let pos = self.banked_pos(bank, bank_len, addr);
+        self.data[pos]
     }
 
-    pub fn read_u8(&self, addr: u16) -> u8 {
-        // TODO: MAPPERS!
-        self.rom[addr as usize]
+    pub fn write_banked_u8(&mut self, bank: usize, bank_len: usize, addr: u16, data: u8) {
+        if self.ram {
+            let pos = self.banked_pos(bank, bank_len, addr);
+            self.data[pos] = data;
+        }
     }
 
-    pub fn write_u8(&mut self, addr:u16, data: u8) {
+    // Only RAM is saved, ROM is the same every time.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        if self.ram {
+            out.bytes(&self.data);
+        }
+    }
 
-        self.rom[addr as usize] = data;
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        if self.ram {
+            input.bytes(&mut self.data)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn banked_pos(&self, bank: usize, bank_len: usize, addr: u16) -> usize {
+        // bank numbers past the end of the chip wrap, as the high lines
+        // just aren't connected
+        (bank * bank_len + (addr as usize % bank_len)) % self.data.len()
     }
 }
 
 
 
 pub struct Cart {
-    rom: Box<[u8]>,
-
-    prg_rom_banks: u8,
-    chr_rom_banks: u8,
-    prg_ram_chunks: u8,
-
-    horizontal_mirroring: bool,
-    vertical_mirroring: bool,
-    four_screen_vram: bool,
-    //   prg_ram_present: bool,
-    //   trainer: bool,
-    pub mapper: u8,
-
-    pub low_prg_bank: u8,
-
-    /*
-    _x8000_bank: u8,
-    _xA000_bank: u8,
-    _xC000_bank: u8,
-    _xE000_bank: u8,
-    */
+    pub header: RomHeader,
+    // see Rom::hash and Rom::md5
+    pub hash: u32,
+    pub md5: [u8; 16],
+
+    mapper: SharedMapper,
+
+    save_path: PathBuf,
+    // what the save file holds, so unchanged RAM isn't written again
+    saved_ram: Vec<u8>,
+    // off when the save file must be left alone, like for movie playback
+    battery_enabled: bool,
+    // PRG RAM as the console powers on with it, before any save went in
+    power_on_ram: Vec<u8>,
 }
 
 impl Cart {
-    pub fn new(rompath: &String) -> Cart {
-        let romfile = read_rom_file(rompath);
+    pub fn new(rompath: &String) -> Result<Cart, RomError> {
+        let rom = try!(Rom::load(rompath));
+        let hash = rom.hash();
+        let md5 = rom.md5();
+        let header = rom.header;
+
+        let mapper = try!(mapper::new_mapper(&header, rom.prg_rom, rom.chr_rom));
+
+        // the trainer is loaded into PRG RAM at $7000
+        if let Some(trainer) = rom.trainer {
+            if let Some(ram) = mapper.borrow_mut().prg_ram_mut() {
+                if ram.len() >= 0x1000 + trainer.len() {
+                    ram[0x1000..0x1000 + trainer.len()].copy_from_slice(&trainer);
+                }
+            }
+        }
+
+        let power_on_ram = mapper.borrow().prg_ram().map_or(Vec::new(), |ram| ram.to_vec());
+
+        Ok(Cart {
+            header: header,
+            hash: hash,
+            md5: md5,
+
+            mapper: mapper,
+
+            save_path: Path::new(rompath).with_extension("sav"),
+            saved_ram: Vec::new(),
+            battery_enabled: true,
+            power_on_ram: power_on_ram,
+        })
+    }
+
+    // A cart around a board a test put together, with nothing on disk.
+    #[cfg(test)]
+    pub fn with_mapper(header: RomHeader, mapper: SharedMapper) -> Cart {
         Cart {
-            prg_rom_banks: romfile[4],
-            chr_rom_banks: romfile[5],
-            prg_ram_chunks: romfile[8],
-
-            horizontal_mirroring: romfile[6] & (1 << 0) == 0,
-            vertical_mirroring: romfile[6] & (1 << 0) != 0,
-            four_screen_vram: romfile[6] & (2 << 3) != 0,
-            //           prg_ram_present: false,
-            //           trainer: false,
-            mapper: (romfile[6] & 0b11110000) >> 4 | romfile[7] & 0b11110000,
-            low_prg_bank: 0,
-
-            rom: romfile,
+            header: header,
+            hash: 0,
+            md5: [0; 16],
+
+            mapper: mapper,
+
+            save_path: PathBuf::new(),
+            saved_ram: Vec::new(),
+            battery_enabled: false,
+            power_on_ram: Vec::new(),
         }
     }
 
-    pub fn write_cart_u8(&mut self, addr: u16, value: u8) {
-        if self.mapper == 0 {
-            // mapper 0 doesn't do anything afaik.
+    // Keeps the save file in dir instead of next to the ROM.
+    pub fn set_save_dir(&mut self, dir: &Path) {
+        let name = self.save_path.file_name().unwrap().to_owned();
+        self.save_path = dir.join(name);
+    }
+
+    // With the battery off the save file is never read or written.
+    pub fn set_battery_enabled(&mut self, enabled: bool) {
+        self.battery_enabled = enabled;
+    }
+
+    // Puts PRG RAM back the way it was at power on, throwing away anything
+    // loaded from the save file.
+    pub fn reset_prg_ram(&mut self) {
+        if let Some(ram) = self.mapper.borrow_mut().prg_ram_mut() {
+            if ram.len() == self.power_on_ram.len() {
+                ram.copy_from_slice(&self.power_on_ram);
+            }
         }
-        else if self.mapper == 2 {
-            self.low_prg_bank = value & 0xF;
-        } else {
-            panic!("Mapper {} is unimplemented", self.mapper);
+    }
+
+    // Restores battery backed PRG RAM from the save file, if there is one.
+    pub fn load_battery(&mut self) -> io::Result<()> {
+        if !self.header.battery || !self.battery_enabled {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        match File::open(&self.save_path) {
+            Ok(mut file) => try!(file.read_to_end(&mut data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut mapper = self.mapper.borrow_mut();
+        if let Some(ram) = mapper.prg_ram_mut() {
+            let len = if data.len() < ram.len() { data.len() } else { ram.len() };
+            ram[..len].copy_from_slice(&data[..len]);
+            self.saved_ram = ram.to_vec();
+        }
+        Ok(())
+    }
+
+    // Writes battery backed PRG RAM out if it changed since the last save.
+    pub fn save_battery(&mut self) -> io::Result<()> {
+        if !self.header.battery || !self.battery_enabled {
+            return Ok(());
         }
 
+        let mapper = self.mapper.borrow();
+        let ram = match mapper.prg_ram() {
+            Some(ram) => ram,
+            None => return Ok(()),
+        };
+        if ram == &self.saved_ram[..] {
+            return Ok(());
+        }
+
+        // write next to the real file first so a crash can't leave half a save
+        let tmp_path = self.save_path.with_extension("sav.tmp");
+        {
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write_all(ram));
+        }
+        try!(fs::rename(&tmp_path, &self.save_path));
+        self.saved_ram = ram.to_vec();
+        Ok(())
+    }
+
+    // The PPU gets its own handle on the mapper for CHR and nametable access.
+    pub fn mapper(&self) -> SharedMapper {
+        self.mapper.clone()
+    }
+
+    pub fn write_cart_u8(&mut self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().cpu_write(addr, value);
     }
 
     pub fn read_cart_u8(&self, addr: u16) -> u8 {
-        let read_pos = self.map_rom(addr);
-        // println!("Read position {:#x}", read_pos)
-        let value = self.rom[read_pos];
-        // println!("Read byte: {:#x} from {:#x}", value, read_pos);
-        value
+        self.mapper.borrow().cpu_read(addr)
     }
 
-    pub fn read_cart_u16(&self, addr: u16) -> u16 {
-        let read_pos = self.map_rom(addr);
-        let value = ((self.rom[read_pos + 1] as u16) << 8 | (self.rom[read_pos] as u16)) as u16;
-        // println!("Read 2 bytes: {:#x}", value);
-        value
-    }
-
-    fn map_rom(&self, addr: u16) -> usize {
-
-        //        println!("Read Address: {:#x}", addr);
-
-        let read_pos: usize;
-
-        if addr >= PRG_ROM_LOWER_START && addr < PRG_ROM_LOWER_START + PRG_ROM_LOWER_LEN {
-            let block:usize = if self.mapper == 0 {
-                0
-            } else if self.mapper == 2 {
-                (1024 * 16) * self.low_prg_bank as usize
-            } else {
-                0
-            };
-
-            read_pos = ((addr as usize - PRG_ROM_LOWER_START as usize) + block + INES_OFFSET as usize) as usize;
-        } else if addr >= PRG_ROM_UPPER_START && addr <= PRG_ROM_UPPER_START + (PRG_ROM_UPPER_LEN - 1) {
-            // UPPER BLOCK
-            let mut block = 0;
-            if self.prg_rom_banks > 1 {
-                // TODO: MAPPERS!!
-                if self.mapper == 0 {
-                    block = 1024 * 16;
-                } else if self.mapper == 2 {
-                    block = 1024 * 16 * (self.prg_rom_banks as usize - 1);
-                }
-            }
-            read_pos = ((addr as usize - PRG_ROM_UPPER_START as usize) + block + INES_OFFSET as usize) as usize;
-        } else {
-            //panic!("virtual memory address {:#X} is not in the PRG rom space",
-            //       addr)
-            read_pos = 0;
-        }
+    pub fn tick(&mut self) {
+        self.mapper.borrow_mut().tick();
+    }
 
-        read_pos
+    pub fn irq(&self) -> bool {
+        self.mapper.borrow().irq()
     }
-}
 
-// TODO: Read rom file path from args
-fn read_rom_file(rompath: &String) -> Box<[u8]> {
-    let mut rom_file = File::open(rompath).unwrap();
-    let mut rom_buffer = Vec::new();
-    rom_file.read_to_end(&mut rom_buffer).unwrap();
-    rom_buffer.into_boxed_slice()
-}
+    pub fn save_state(&self, out: &mut StateWriter) {
+        self.mapper.borrow().save_state(out);
+    }
 
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.borrow_mut().load_state(input)
+    }
+
+    // Where save state slot goes, next to the battery save.
+    pub fn state_path(&self, slot: u8) -> PathBuf {
+        self.save_path.with_extension(format!("ss{}", slot))
+    }
+
+    pub fn audio_output(&self) -> f32 {
+        self.mapper.borrow().audio_output()
+    }
+
+    pub fn read_cart_u16(&self, addr: u16) -> u16 {
+        let mapper = self.mapper.borrow();
+        (mapper.cpu_read(addr + 1) as u16) << 8 | mapper.cpu_read(addr) as u16
+    }
+}
 
 // impl this myself because I don't want to print the actual rom every time
 impl fmt::Debug for Cart {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f,
-                 "Cart: (
-        prg_rom_banks: {:#02}
-        chr_rom_banks: {:#02}
-        \
-                  prg_ram_chunks: {:#02}
-        horizontal_mirroring: {:#?}
-        \
-                  vertical_mirroring: {:#?}
-        four_screen_VRAM: {:#?}
-        mapper: \
-                  {:#02}
-     )",
-                 self.prg_rom_banks,
-                 self.chr_rom_banks,
-                 self.prg_ram_chunks,
-                 self.horizontal_mirroring,
-                 self.vertical_mirroring,
-                 self.four_screen_vram,
-                 self.mapper)
-
+        write!(f, "Cart: {:#?}", self.header)
     }
 }
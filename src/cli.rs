@@ -0,0 +1,54 @@
+// Command line handling shared by the SDL frontend and the headless
+// binary. Both include this file as a module of their own.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::process;
+
+use oxidenes::apu::{self, FilterProfile};
+
+pub fn warn(args: fmt::Arguments) {
+    let _ = writeln!(io::stderr(), "{}", args);
+}
+
+pub fn fail(args: fmt::Arguments) -> ! {
+    warn(args);
+    process::exit(1);
+}
+
+// The number following flag.
+pub fn parse_arg(flag: &str, value: Option<String>) -> u32 {
+    match value.and_then(|v| v.parse().ok()) {
+        Some(n) => n,
+        None => fail(format_args!("{} needs a number", flag)),
+    }
+}
+
+pub fn parse_arg_in(flag: &str, value: Option<String>, min: u32, max: u32) -> u32 {
+    let n = parse_arg(flag, value);
+    if n < min || n > max {
+        fail(format_args!("{} needs a number from {} to {}", flag, min, max));
+    }
+    n
+}
+
+// --audio-filter's profile name.
+pub fn parse_filter(value: Option<String>) -> FilterProfile {
+    let name = value.unwrap_or(String::new());
+    match FilterProfile::from_name(&name) {
+        Some(profile) => profile,
+        None => fail(format_args!("--audio-filter takes hardware, clean or raw")),
+    }
+}
+
+// --channels' list of channel names.
+pub fn parse_channels(value: Option<String>) -> u8 {
+    let list = value.unwrap_or(String::new());
+    match apu::parse_channel_list(&list) {
+        Some(mask) => mask,
+        None => {
+            fail(format_args!("--channels takes a comma separated list of {}",
+                              apu::CHANNEL_NAMES.join(", ")))
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use super::*;
 use mem_map::*;
 use opcodes::*;
+use state::{StateError, StateReader, StateWriter};
 // use std::collections::HashSet;
 
 // pub HashMap: ops;
@@ -8,7 +9,8 @@ use opcodes::*;
 
 #[derive(Debug)]
 pub struct CPU {
-    pub cycle: isize,
+    // CPU cycles since power on
+    pub cycle: u64,
 
     pub accumulator: u8, // A
 
@@ -20,14 +22,35 @@ pub struct CPU {
     pub stack_pointer: u8, // S or SP
 
     pub bus: Bus,
+
+    // one bit per IRQ_* source currently holding the line
+    irq_line: u8,
+    // NMI is edge triggered, so an edge is remembered until it's serviced
+    nmi_pending: bool,
+    // interrupt polls of the last two cycles
+    prev_run_irq: bool,
+    run_irq: bool,
+    // the last value on the data bus, unmapped reads see it
+    open_bus: u8,
+    // set by the KIL opcodes, only a reset gets the CPU going again
+    jammed: bool,
+    // every bus access as (address, value, write), for the tests to check
+    #[cfg(test)]
+    accesses: Vec<(u16, u8, bool)>,
 }
 
+// Sources that can assert the IRQ line. The line stays asserted until
+// every one of them has released it.
+pub const IRQ_FRAME_COUNTER: u8 = 1 << 0;
+pub const IRQ_DMC: u8 = 1 << 1;
+pub const IRQ_MAPPER: u8 = 1 << 2;
+
 #[derive(Debug, Clone, Copy)]
 pub struct StatusReg {
     negative_sign: bool, // N (or sometimes S)
     overflow: bool, // V
     unused: bool, // always 1
-    break_flag: bool, // B
+    // B isn't a real flag, it only exists in copies of P pushed on the stack
     decimal_mode: bool, // D - unimplemented on NES but still sets/clears
     interrupt_disable: bool, // I
     zero: bool, // Z
@@ -44,6 +67,10 @@ enum RegType {
 
 pub const PPU_MULTIPLIER:isize = 3;
 
+// What XAA and LXA OR the accumulator with varies between chips, and even
+// with temperature. This is the most common value.
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 impl CPU {
     pub fn new(bus: Bus, pc: u16) -> CPU {
         CPU {
@@ -59,185 +86,233 @@ impl CPU {
             program_counter: pc,
             stack_pointer: 0xfd,
             bus: bus,
+
+            irq_line: 0,
+            nmi_pending: false,
+            prev_run_irq: false,
+            run_irq: false,
+            open_bus: 0,
+            jammed: false,
+            #[cfg(test)]
+            accesses: Vec::new(),
         }
     }
 
-    pub fn read_instruction(&mut self) -> (u8, Instruction) {
-        let pc = self.program_counter;
-        let op = self.cpu_read_u8(pc);
-        let mut instr = INSTRUCTIONS[op as usize];
-
-        let operand = if instr.bytes == 3 {
-            Some(self.cpu_read_u16(pc + 1))
-        } else if instr.bytes == 2 {
-            Some(self.cpu_read_u8(pc + 1) as u16)
-        } else {
-            None
-        };
-
-        if operand != None {
-            instr.operand = operand.unwrap();
+    // Runs one instruction. Every bus access below is a CPU cycle and the
+    // rest of the console is clocked along with it, so the PPU sees register
+    // reads and writes on the exact cycle they happen. An interrupt polled
+    // before the last cycle is serviced straight after.
+    pub fn step(&mut self) {
+        if self.jammed {
+            // the bus is stuck but the rest of the console keeps going
+            self.tick();
+            return;
         }
 
-        self.program_counter += instr.bytes as u16;
+        let op = self.fetch_u8();
+        self.execute_op(op);
+        // a KIL doesn't finish, so there's nothing for an interrupt to
+        // follow and PC stays on it
+        if self.jammed {
+            return;
+        }
 
-        let mut page_crossed = false;
-        let mut branching = false;
-        let dataaddr:Option<u16> = match instr.addr_mode {
-            AddressMode::Immediate => Some(pc + 1),
-            AddressMode::Absolute => Some(operand.unwrap()),
-            AddressMode::AbsoluteX => {
-                let addr = operand.unwrap().wrapping_add(self.index_x as u16);
-                if instr.page_boundary_cycle && (operand.unwrap() >> 8 != addr >> 8) {
-                     page_crossed = true;
-                }
-                Some(addr)
-            }
-            AddressMode::AbsoluteY => {
-                let addr = operand.unwrap().wrapping_add(self.index_y as u16);
-                if instr.page_boundary_cycle && (operand.unwrap() >> 8 != addr >> 8) {
-                     page_crossed = true;
-                }
-                Some(addr)
-            }
-            AddressMode::XIndirect => {
-                let mut tmp = operand.unwrap() as u8;
-                tmp = tmp.wrapping_add(self.index_x);
-                let lo = self.cpu_read_u8(tmp as u16) as u16;
-                let hi = self.cpu_read_u8(tmp.wrapping_add(1) as u16) as u16;
-                Some(hi << 8 | lo)
-            }
-            AddressMode::IndirectY => {
-                let tmp = operand.unwrap() as u8;
-                let lo = self.cpu_read_u8(tmp as u16) as u16;
-                let hi = self.cpu_read_u8(tmp.wrapping_add(1) as u16) as u16;
-                let value: u16 = hi << 8 | lo;
-                let addr = value.wrapping_add(self.index_y as u16);
-                if instr.page_boundary_cycle && (hi != addr >> 8) {
-                    page_crossed = true;
-                }
-                Some(addr)
-            }
-            AddressMode::Zeropage => Some(operand.unwrap()),
-            AddressMode::ZeropageX => {
-                let tmp = operand.unwrap() as u8;
-                Some(tmp.wrapping_add(self.index_x) as u16)
-            }
-            AddressMode::ZeropageY => {
-                let tmp = operand.unwrap() as u8;
-                Some(tmp.wrapping_add(self.index_y) as u16)
-            }
-            AddressMode::Indirect => Some(pc + 1),
-            AddressMode::Relative => {
-
-                // we have to evaluate the whole branch during this phase
-                // to accurately count the CPU ticks
-                branching = match op {
-                    // BVC - relative - 2 bytes
-                    0x50 => !self.status_reg.overflow,
-                    // BVS - relative - 2 bytes
-                    0x70 => self.status_reg.overflow,
-                    // BCS - relative - 2 bytes
-                    0xB0 => self.status_reg.carry,
-                    // BCC - relative - 2 bytes
-                    0x90 => !self.status_reg.carry,
-                    // BEQ - relative
-                    0xF0 => self.status_reg.zero,
-                    // BNE - relative
-                    0xD0 => !self.status_reg.zero,
-                    // BPL - relative
-                    0x10 => !self.status_reg.negative_sign,
-                    // BMI - rel
-                    0x30 => self.status_reg.negative_sign,
-
-                    _ => panic!("instruction {:#X} should not be relative", op)
-
-                };
-
-                // wrapping? not sure how signed deal with those...
-                if branching {
-                    let tmp = operand.unwrap() as i8 as i16;
-                    let addr = (self.program_counter as i16 + tmp) as u16;
-                    if addr >> 8 != (self.program_counter) >> 8 {
-                        page_crossed = true;
-                    };
-                    Some(addr)
-                } else {
-                    Some(self.program_counter)
-                }
+        if self.prev_run_irq {
+            self.interrupt(false);
+        }
+    }
 
-            }
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u64(self.cycle);
+        out.u8(self.accumulator);
+        out.u8(self.index_x);
+        out.u8(self.index_y);
+        out.u8(self.status_reg.into());
+        out.u16(self.program_counter);
+        out.u8(self.stack_pointer);
+
+        out.u8(self.irq_line);
+        out.bool(self.nmi_pending);
+        out.bool(self.prev_run_irq);
+        out.bool(self.run_irq);
+        out.u8(self.open_bus);
+        out.bool(self.jammed);
+
+        self.bus.save_state(out);
+    }
 
-            _ => None,
-        };
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.cycle = try!(input.u64());
+        self.accumulator = try!(input.u8());
+        self.index_x = try!(input.u8());
+        self.index_y = try!(input.u8());
+        self.status_reg = try!(input.u8()).into();
+        self.program_counter = try!(input.u16());
+        self.stack_pointer = try!(input.u8());
+
+        self.irq_line = try!(input.u8());
+        self.nmi_pending = try!(input.bool());
+        self.prev_run_irq = try!(input.bool());
+        self.run_irq = try!(input.bool());
+        self.open_bus = try!(input.u8());
+        self.jammed = try!(input.bool());
+
+        self.bus.load_state(input)
+    }
 
-        if dataaddr != None {
-            instr.dest_addr = dataaddr;
-        }
-        // increment the cycle counter as-needed.
-        // self.cycle += instr.ticks as isize* PPU_MULTIPLIER;
-        if page_crossed {
-            instr.ticks += 1;
-        }
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
 
-        if branching {
-            instr.ticks += 1;
+    pub fn set_irq(&mut self, source: u8, asserted: bool) {
+        if asserted {
+            self.irq_line |= source;
+        } else {
+            self.irq_line &= !source;
         }
+    }
 
-
-
-        (op, instr)
+    // Called when the PPU pulls /NMI low.
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
     }
 
-    pub fn nmi (&mut self){
+    // The sequence shared by BRK, IRQ and NMI. An NMI arriving before the
+    // vector is fetched hijacks it, but the pushed B flag still tells the
+    // handler whether a BRK got us here.
+    fn interrupt(&mut self, brk: bool) {
+        if !brk {
+            // the opcode and operand fetches of the instruction that
+            // doesn't get to run
+            self.dummy_read();
+            self.dummy_read();
+        }
+
         let hi = (self.program_counter >> 8) as u8;
         self.push_stack(hi);
         let lo = (0x00ff & self.program_counter) as u8;
         self.push_stack(lo);
-        let sr: u8 = self.status_reg.into();
+        let mut sr: u8 = self.status_reg.into();
+        if brk {
+            sr |= 1 << 4;
+        }
         self.push_stack(sr);
- //       println!("NMI");
-        let tmp = self.cpu_read_u16(NMI_VECTOR_LOC);
-        self.program_counter = tmp;
-        // need to add some cycles here..
-        self.cycle += 7  * PPU_MULTIPLIER;
+        self.status_reg.interrupt_disable = true;
+
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR_LOC
+        } else {
+            IRQ_BRK_VECTOR_LOC
+        };
+        let lo = self.cpu_read_u8(vector) as u16;
+        let hi = self.cpu_read_u8(vector + 1) as u16;
+        self.program_counter = hi << 8 | lo;
     }
 
+    // One CPU cycle worth of time for everything that isn't the CPU. The
+    // interrupt lines are polled at the end of every cycle, the poll from
+    // the second to last cycle of an instruction is the one that counts.
+    fn tick(&mut self) {
+        self.cycle += 1;
+
+        if self.bus.ppu.tick(PPU_MULTIPLIER) {
+            self.trigger_nmi();
+        }
+        let expansion_audio = self.bus.cart.audio_output();
+        self.bus.apu.tick(expansion_audio);
+        self.bus.cart.tick();
+        // sampled after the PPU, which clocks scanline counters
+        let mapper_irq = self.bus.cart.irq();
+        self.set_irq(IRQ_MAPPER, mapper_irq);
+        let frame_irq = self.bus.apu.frame_irq();
+        self.set_irq(IRQ_FRAME_COUNTER, frame_irq);
+        let dmc_irq = self.bus.apu.dmc_irq();
+        self.set_irq(IRQ_DMC, dmc_irq);
+
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = self.nmi_pending ||
+                       (self.irq_line != 0 && !self.status_reg.interrupt_disable);
+
+        if let Some(addr) = self.bus.apu.take_dmc_fetch() {
+            self.dmc_dma(addr);
+        }
+    }
 
-    pub fn execute_op(&mut self, op: &u8, instr: &Instruction) {
+    // The DMC halts the CPU to fetch a sample byte. Depending on what the
+    // CPU was doing that takes 1 to 4 cycles, we always take the usual 4.
+    fn dmc_dma(&mut self, addr: u16) {
+        self.tick();
+        self.tick();
+        self.tick();
+        let value = self.cpu_read_u8(addr);
+        self.bus.apu.fill_dmc(value);
+    }
 
-        let addr = instr.dest_addr.unwrap_or(0);
-        // self.program_counter += 1;
-        match *op {
+    fn execute_op(&mut self, op: u8) {
+        let mode = INSTRUCTIONS[op as usize].addr_mode;
+        match op {
 
             0x4C => {
                 // JMP-absolute
-                // let value = instr.operand;
-                self.program_counter = instr.operand;
+                self.program_counter = self.fetch_u16();
             }
 
             0x6C => {
                 // JMP-indirect
-                // let pc = self.program_counter;
-                let lotmp = self.cpu_read_u8(addr);
-                let hitmp = self.cpu_read_u8(addr + 1);
+                let addr = self.fetch_u16();
                 // because there is no carry the lo byte of effective addr wraps +1
                 // see http://www.6502.org/tutorials/6502opcodes.html under JMP
-
-                let mut tmp = (hitmp as u16) << 8 | lotmp as u16;
-                let lo = self.cpu_read_u8(tmp) as u16;
-                tmp = (hitmp as u16) << 8 | lotmp.wrapping_add(1) as u16;
-                let hi = self.cpu_read_u8(tmp) as u16;
-                //            println!("ind: {:#X} JMP ${:X}${:X}", tmp, hi, lo);
+                let lo = self.cpu_read_u8(addr) as u16;
+                let hi = self.cpu_read_u8((addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16;
                 self.program_counter = hi << 8 | lo;
             }
 
-//          BVC  | BVS  | BCS  | BCC  | BEQ  | BNE  | BPL  | BMI
-            0x50 | 0x70 | 0xB0 | 0x90 | 0xF0 | 0xD0 | 0x10 | 0x30 =>
-                self.program_counter = addr,
+            // BVC - relative - 2 bytes
+            0x50 => {
+                let condition = !self.status_reg.overflow;
+                self.branch(condition)
+            }
+            // BVS - relative - 2 bytes
+            0x70 => {
+                let condition = self.status_reg.overflow;
+                self.branch(condition)
+            }
+            // BCS - relative - 2 bytes
+            0xB0 => {
+                let condition = self.status_reg.carry;
+                self.branch(condition)
+            }
+            // BCC - relative - 2 bytes
+            0x90 => {
+                let condition = !self.status_reg.carry;
+                self.branch(condition)
+            }
+            // BEQ - relative
+            0xF0 => {
+                let condition = self.status_reg.zero;
+                self.branch(condition)
+            }
+            // BNE - relative
+            0xD0 => {
+                let condition = !self.status_reg.zero;
+                self.branch(condition)
+            }
+            // BPL - relative
+            0x10 => {
+                let condition = !self.status_reg.negative_sign;
+                self.branch(condition)
+            }
+            // BMI - rel
+            0x30 => {
+                let condition = self.status_reg.negative_sign;
+                self.branch(condition)
+            }
 
             // RTI - implied
             0x40 => {
+                self.dummy_read();
+                self.dummy_stack_read();
                 let tmp = self.pull_stack();
                 self.status_reg = tmp.into();
                 let lo = self.pull_stack() as u16;
@@ -249,161 +324,207 @@ impl CPU {
             // STX
             0x86 | 0x96 | 0x8E => {
                 let tmp = self.index_x;
-                self.cpu_write_u8(addr, tmp);
+                self.write_operand(mode, tmp);
             }
 
             // STA
             0x85 | 0x95 | 0x8D | 0x81 | 0x91 | 0x99 | 0x9D => {
                 let tmp = self.accumulator;
-                self.cpu_write_u8(addr, tmp);
+                self.write_operand(mode, tmp);
             }
 
             // STY - zeropage
             0x84 | 0x94 | 0x8C => {
                 let tmp = self.index_y;
-                self.cpu_write_u8(addr, tmp);
+                self.write_operand(mode, tmp);
             }
 
             // LDA
             0xA9 | 0xA5 | 0xB5 | 0xAD | 0xB9 | 0xBD | 0xA1 | 0xB1 => {
-                let value = self.cpu_read_u8(addr);
+                let value = self.read_operand(mode);
                 self.set_register(value, RegType::A);
             }
 
             // LDX
             0xA2 | 0xAE | 0xBE | 0xA6 | 0xB6 => {
-                let value = self.cpu_read_u8(addr);
+                let value = self.read_operand(mode);
                 self.set_register(value, RegType::X);
             }
 
             // LDY-immediate
             0xA0 | 0xA4 | 0xAC | 0xBC | 0xB4 => {
-                let value = self.cpu_read_u8(addr);
+                let value = self.read_operand(mode);
                 self.set_register(value, RegType::Y);
             }
 
             // LSR
-            0x4A | 0x46 | 0x56 | 0x4E | 0x5E => self.shift_right(instr.addr_mode, addr),
+            0x4A => self.modify_accumulator(CPU::shift_right),
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.modify(mode, CPU::shift_right);
+            }
 
             // ASL
-            0x0A | 0x06 | 0x16 | 0x0E | 0x1E => self.shift_left(instr.addr_mode, addr),
+            0x0A => self.modify_accumulator(CPU::shift_left),
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.modify(mode, CPU::shift_left);
+            }
 
             // ROR
-            0x6A | 0x66 | 0x76 | 0x6E | 0x7E => self.rotate_right(instr.addr_mode, addr),
+            0x6A => self.modify_accumulator(CPU::rotate_right),
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.modify(mode, CPU::rotate_right);
+            }
 
             // ROL
-            0x2A | 0x26 | 0x36 | 0x2E | 0x3E => self.rotate_left(instr.addr_mode, addr),
+            0x2A => self.modify_accumulator(CPU::rotate_left),
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.modify(mode, CPU::rotate_left);
+            }
 
             // ORA
-            0x09 | 0x05 | 0x15 | 0x01 | 0x11 | 0x0D | 0x19 | 0x1D =>
-                self.bitwise_op_to_a(|a, m| a | m, addr),
+            0x09 | 0x05 | 0x15 | 0x01 | 0x11 | 0x0D | 0x19 | 0x1D => {
+                let value = self.read_operand(mode);
+                self.bitwise_op_to_a(|a, m| a | m, value)
+            }
 
             // EOR
-            0x49 | 0x45 | 0x55 | 0x41 | 0x51 | 0x4D | 0x59 | 0x5D =>
-                self.bitwise_op_to_a(|a, m| a ^ m, addr),
+            0x49 | 0x45 | 0x55 | 0x41 | 0x51 | 0x4D | 0x59 | 0x5D => {
+                let value = self.read_operand(mode);
+                self.bitwise_op_to_a(|a, m| a ^ m, value)
+            }
 
             // AND
-            0x29 | 0x25 | 0x35 | 0x21 | 0x31 | 0x2D | 0x39 | 0x3D =>
-                self.bitwise_op_to_a(|a, m| a & m, addr),
+            0x29 | 0x25 | 0x35 | 0x21 | 0x31 | 0x2D | 0x39 | 0x3D => {
+                let value = self.read_operand(mode);
+                self.bitwise_op_to_a(|a, m| a & m, value)
+            }
 
             // ADC
-            0x69 | 0x65 | 0x75 | 0x61 | 0x71 | 0x6D | 0x79 | 0x7D =>
-                self.add_with_carry(addr),
+            0x69 | 0x65 | 0x75 | 0x61 | 0x71 | 0x6D | 0x79 | 0x7D => {
+                let value = self.read_operand(mode);
+                self.add_with_carry(value)
+            }
 
             // SBC
-            0xE9 | 0xE5 | 0xF5 | 0xE1 | 0xF1 | 0xED | 0xF9 | 0xFD =>
-                self.sub_with_carry(addr),
+            0xE9 | 0xE5 | 0xF5 | 0xE1 | 0xF1 | 0xED | 0xF9 | 0xFD => {
+                let value = self.read_operand(mode);
+                self.sub_with_carry(value)
+            }
 
             // JSR-Absolute
             0x20 => {
-                let hi = ((self.program_counter - 1) >> 8) as u8;
+                let lo = self.fetch_u8() as u16;
+                self.dummy_stack_read();
+
+                // PC points at the high byte of the target, which is what
+                // gets pushed as the return address
+                let hi = (self.program_counter >> 8) as u8;
                 self.push_stack(hi);
-                let lo = (0x00ff & (self.program_counter - 1)) as u8;
-                self.push_stack(lo);
+                let lo_pc = (0x00ff & self.program_counter) as u8;
+                self.push_stack(lo_pc);
 
-                self.program_counter = instr.operand;
+                let pc = self.program_counter;
+                let hi = self.cpu_read_u8(pc) as u16;
+                self.program_counter = hi << 8 | lo;
             }
 
             // RTS - implied
             0x60 => {
+                self.dummy_read();
+                self.dummy_stack_read();
                 let lo = self.pull_stack() as u16;
                 let hi = self.pull_stack() as u16;
-                let value: u16 = hi << 8 | lo;
-                self.program_counter = value + 1;
+                self.program_counter = hi << 8 | lo;
+                self.fetch_u8();
             }
 
             // NOP
-            0xEA => {}
+            0xEA => self.dummy_read(),
 
 
             // TAY - impl
             0xA8 => {
+                self.dummy_read();
                 let tmp = self.accumulator;
                 self.set_register(tmp, RegType::Y);
             }
 
             // TAX - impl
             0xAA => {
+                self.dummy_read();
                 let tmp = self.accumulator;
                 self.set_register(tmp, RegType::X);
             }
 
             // TYA - impl
             0x98 => {
+                self.dummy_read();
                 let tmp = self.index_y;
                 self.set_register(tmp, RegType::A)
             }
 
             // TXA - impl
             0x8A => {
+                self.dummy_read();
                 let tmp = self.index_x;
                 self.set_register(tmp, RegType::A)
             }
 
             // TSX -impl
             0xBA => {
+                self.dummy_read();
                 let tmp = self.stack_pointer;
                 self.set_register(tmp, RegType::X)
             }
 
             // TXS -impl
-            0x9A => self.stack_pointer = self.index_x,
+            0x9A => {
+                self.dummy_read();
+                self.stack_pointer = self.index_x
+            }
 
             // INY - impl
             0xC8 => {
+                self.dummy_read();
                 let tmp = self.index_y.wrapping_add(1);
                 self.set_register(tmp, RegType::Y)
             }
 
             // INX - impl
             0xE8 => {
+                self.dummy_read();
                 let tmp = self.index_x.wrapping_add(1);
                 self.set_register(tmp, RegType::X)
             }
 
             // DEY - impl
             0x88 => {
+                self.dummy_read();
                 let tmp = self.index_y.wrapping_sub(1);
                 self.set_register(tmp, RegType::Y)
             }
 
             // DEX - impl
             0xCA => {
+                self.dummy_read();
                 let tmp = self.index_x.wrapping_sub(1);
                 self.set_register(tmp, RegType::X)
             }
 
             // INC
-            0xE6 | 0xF6 | 0xEE | 0xFE => self.increment_memory(addr),
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.modify(mode, CPU::increment);
+            }
 
             // DEC - zeropage
-            0xC6 | 0xD6 | 0xCE | 0xDE => self.decrement_memory(addr),
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.modify(mode, CPU::decrement);
+            }
 
             // BIT
             0x24 | 0x2C => {
-                let value = self.cpu_read_u8(addr);
+                let value = self.read_operand(mode);
                 let result = self.accumulator & value;
-                //            println!("BIT {:#x} & {:#x}: {:#x}", self.accumulator, value, result);
                 self.status_reg.zero = result == 0;
                 self.status_reg.negative_sign = (value & (1 << 7)) != 0;
                 self.status_reg.overflow = (value & (1 << 6)) != 0;
@@ -411,28 +532,50 @@ impl CPU {
 
 
             // SEI - impl
-            0x78 => self.status_reg.interrupt_disable = true,
+            0x78 => {
+                self.dummy_read();
+                self.status_reg.interrupt_disable = true
+            }
 
             // CLI - impl
-            0x58 => self.status_reg.interrupt_disable = false,
+            0x58 => {
+                self.dummy_read();
+                self.status_reg.interrupt_disable = false
+            }
 
             // SED - impl
-            0xF8 => self.status_reg.decimal_mode = true,
+            0xF8 => {
+                self.dummy_read();
+                self.status_reg.decimal_mode = true
+            }
 
             // CLD - impl
-            0xD8 => self.status_reg.decimal_mode = false,
+            0xD8 => {
+                self.dummy_read();
+                self.status_reg.decimal_mode = false
+            }
 
             // SEC - implied
-            0x38 => self.status_reg.carry = true,
+            0x38 => {
+                self.dummy_read();
+                self.status_reg.carry = true
+            }
 
             // CLC - implied
-            0x18 => self.status_reg.carry = false,
+            0x18 => {
+                self.dummy_read();
+                self.status_reg.carry = false
+            }
 
             // CLV - impl
-            0xB8 => self.status_reg.overflow = false,
+            0xB8 => {
+                self.dummy_read();
+                self.status_reg.overflow = false
+            }
 
             // PHP - impl
             0x08 => {
+                self.dummy_read();
                 let mut tmp: u8 = self.status_reg.into();
                 tmp |= 1 << 4; // set the break flag before pushing
                 self.push_stack(tmp);
@@ -440,38 +583,45 @@ impl CPU {
 
             // PLP - impl
             0x28 => {
-                let mut value = self.pull_stack();
-                let brk = if self.status_reg.break_flag {
-                    1
-                } else {
-                    0
-                };
-                value ^= (brk ^ value) & (1 << 4);
+                self.dummy_read();
+                self.dummy_stack_read();
+                let value = self.pull_stack();
                 self.status_reg = value.into();
             }
 
             // PLA - impl
             0x68 => {
+                self.dummy_read();
+                self.dummy_stack_read();
                 let value = self.pull_stack();
                 self.set_register(value, RegType::A);
             }
 
             // PHA - impl
             0x48 => {
+                self.dummy_read();
                 let tmp = self.accumulator;
                 self.push_stack(tmp);
             }
 
 
             // CMP
-            0xC9 | 0xC5 | 0xD5 | 0xC1 | 0xD1 | 0xCD | 0xD9 | 0xDD =>
-                self.compare(RegType::A, addr),
+            0xC9 | 0xC5 | 0xD5 | 0xC1 | 0xD1 | 0xCD | 0xD9 | 0xDD => {
+                let value = self.read_operand(mode);
+                self.compare(RegType::A, value)
+            }
 
             // CPY
-            0xC0 | 0xC4 | 0xCC => self.compare(RegType::Y, addr),
+            0xC0 | 0xC4 | 0xCC => {
+                let value = self.read_operand(mode);
+                self.compare(RegType::Y, value)
+            }
 
             // CPX - immediate
-            0xE0 | 0xE4 | 0xEC => self.compare(RegType::X, addr),
+            0xE0 | 0xE4 | 0xEC => {
+                let value = self.read_operand(mode);
+                self.compare(RegType::X, value)
+            }
 
 
             // Illegal/undocumented opcodes - these do unusual things..
@@ -479,98 +629,338 @@ impl CPU {
             //
 
             // NOP - undocumented opcode
-            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {} // println!("NOP - impl - ndocumented Opcode ${:X}", instr),
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => self.dummy_read(),
 
             // DOP / NOP / SKB - undocumented Opcode
-            0x04 | 0x44 | 0x64 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 | 0x14 | 0x34 |
-            0x54 | 0x74 | 0xD4 | 0xF4 => {}
-
             // TOP / NOP / SKW - undocumented opcode
-            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {}
+            // they still read their operand, page crossing included
+            0x04 | 0x44 | 0x64 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 | 0x14 | 0x34 |
+            0x54 | 0x74 | 0xD4 | 0xF4 |
+            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+                self.read_operand(mode);
+            }
 
             // LAX - Ind,x - Undocumented Opcode
             0xA3 | 0xB3 | 0xA7 | 0xB7 | 0xAF | 0xBF => {
-                let value = self.cpu_read_u8(addr);
+                let value = self.read_operand(mode);
                 self.set_register(value, RegType::A);
                 self.set_register(value, RegType::X);
             }
 
             // AAX / SAX / AXS - Undocumented Opcode
-            0x83 | 0x87 | 0x97 | 0x8F => self.and_x_a_store(addr),
+            // does NOT effect flags
+            0x83 | 0x87 | 0x97 | 0x8F => {
+                let value = self.index_x & self.accumulator;
+                self.write_operand(mode, value)
+            }
 
             // SBC - imm - Undocumented opcode (identical to E9)
-            0xEB => self.sub_with_carry(addr),
+            0xEB => {
+                let value = self.read_operand(mode);
+                self.sub_with_carry(value)
+            }
 
             // DCP / DCM - Undocumented Opcode
             0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => {
-                self.decrement_memory(addr);
-                self.compare(RegType::A, addr);
+                let value = self.modify(mode, CPU::decrement);
+                self.compare(RegType::A, value);
             }
 
             // ISC / ISB / INS - undocumented
             0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
-                self.increment_memory(addr);
-                self.sub_with_carry(addr);
+                let value = self.modify(mode, CPU::increment);
+                self.sub_with_carry(value);
             }
 
             // SLO / ASO - undocumented
             0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
-                self.shift_left(instr.addr_mode, addr);
-                self.bitwise_op_to_a(|a, m| a | m, addr);
+                let value = self.modify(mode, CPU::shift_left);
+                self.bitwise_op_to_a(|a, m| a | m, value);
             }
 
             // RLA - undocumented
             0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
-                self.rotate_left(instr.addr_mode, addr);
-                self.bitwise_op_to_a(|a, m| a & m, addr);
+                let value = self.modify(mode, CPU::rotate_left);
+                self.bitwise_op_to_a(|a, m| a & m, value);
             }
 
             // SRE / LSE - undocumented
             0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
-                self.shift_right(instr.addr_mode, addr);
-                self.bitwise_op_to_a(|a, m| a ^ m, addr);
+                let value = self.modify(mode, CPU::shift_right);
+                self.bitwise_op_to_a(|a, m| a ^ m, value);
             }
 
             // RRA - undocumented
             0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
-                self.rotate_right(instr.addr_mode, addr);
-                self.add_with_carry(addr);
+                let value = self.modify(mode, CPU::rotate_right);
+                self.add_with_carry(value);
             },
 
-            // BRK
+            // ANC / AAC - undocumented, AND then N copied into C
+            0x0B | 0x2B => {
+                let value = self.read_operand(mode);
+                self.bitwise_op_to_a(|a, m| a & m, value);
+                self.status_reg.carry = self.status_reg.negative_sign;
+            }
+
+            // ALR / ASR - undocumented, AND then LSR A
+            0x4B => {
+                let value = self.read_operand(mode);
+                let a = self.accumulator & value;
+                self.accumulator = self.shift_right(a);
+            }
+
+            // ARR - undocumented, AND then ROR A, but C and V come from
+            // bits 6 and 5 of the result
+            0x6B => {
+                let value = self.read_operand(mode);
+                let a = self.accumulator & value;
+                let result = self.rotate_right(a);
+                self.accumulator = result;
+                self.status_reg.carry = (result & (1 << 6)) != 0;
+                self.status_reg.overflow = ((result >> 6) ^ (result >> 5)) & 1 != 0;
+            }
+
+            // XAA / ANE - undocumented and unstable
+            0x8B => {
+                let value = self.read_operand(mode);
+                let result = (self.accumulator | UNSTABLE_MAGIC) & self.index_x & value;
+                self.set_register(result, RegType::A);
+            }
+
+            // LXA / ATX - undocumented and unstable
+            0xAB => {
+                let value = self.read_operand(mode);
+                let result = (self.accumulator | UNSTABLE_MAGIC) & value;
+                self.set_register(result, RegType::A);
+                self.set_register(result, RegType::X);
+            }
+
+            // AXS / SBX - undocumented, X = (A & X) - imm without borrow
+            0xCB => {
+                let value = self.read_operand(mode);
+                let ax = self.accumulator & self.index_x;
+                self.status_reg.carry = ax >= value;
+                self.set_register(ax.wrapping_sub(value), RegType::X);
+            }
+
+            // SHA / AXA - undocumented
+            0x93 | 0x9F => {
+                let value = self.accumulator & self.index_x;
+                self.store_and_high(mode, value);
+            }
+
+            // SHY / SYA - undocumented
+            0x9C => {
+                let value = self.index_y;
+                self.store_and_high(mode, value);
+            }
+
+            // SHX / SXA - undocumented
+            0x9E => {
+                let value = self.index_x;
+                self.store_and_high(mode, value);
+            }
+
+            // TAS / XAS - undocumented, S = A & X then stored like SHA
+            0x9B => {
+                self.stack_pointer = self.accumulator & self.index_x;
+                let value = self.stack_pointer;
+                self.store_and_high(mode, value);
+            }
+
+            // LAS / LAR - undocumented, A, X and S = memory & S
+            0xBB => {
+                let value = self.read_operand(mode) & self.stack_pointer;
+                self.stack_pointer = value;
+                self.set_register(value, RegType::A);
+                self.set_register(value, RegType::X);
+            }
+
+            // KIL / JAM - undocumented, locks the CPU up
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 |
+            0xD2 | 0xF2 => {
+                self.dummy_read();
+                // leave PC on the opcode so it's clear what happened
+                self.program_counter = self.program_counter.wrapping_sub(1);
+                self.jammed = true;
+            }
+
+            // BRK - the byte after the opcode is skipped
             0x00 => {
-                self.status_reg.break_flag = true;
+                self.fetch_u8();
+                self.interrupt(true);
+            }
+        }
+    }
 
-//                self.program_counter += 1;
-                self.status_reg.interrupt_disable = true;
-                let hi = (self.program_counter >> 8) as u8;
-                self.push_stack(hi);
-                let lo = (0x00ff & self.program_counter) as u8;
-                self.push_stack(lo);
-                let sr: u8 = self.status_reg.into();
-                self.push_stack(sr);
-                println!("Break");
-                let tmp = self.cpu_read_u16(IRQ_BRK_VECTOR_LOC);
-                self.program_counter = tmp;
+    // SHA, SHX, SHY and TAS store value & (H + 1), H being the high byte of
+    // the base address. If indexing crosses a page the same value ends up
+    // as the high byte of the address written to.
+    fn store_and_high(&mut self, mode: AddressMode, value: u8) {
+        let (base, index) = match mode {
+            AddressMode::AbsoluteX => (self.fetch_u16(), self.index_x),
+            AddressMode::AbsoluteY => (self.fetch_u16(), self.index_y),
+            AddressMode::IndirectY => {
+                let ptr = self.fetch_u8();
+                (self.read_zeropage_u16(ptr), self.index_y)
             }
+            _ => panic!("{:?} isn't used by the SH* opcodes", mode),
+        };
+        let addr = self.indexed(base, index, true);
+        let value = value & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if addr & 0xFF00 != base & 0xFF00 {
+            (value as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.cpu_write_u8(addr, value);
+    }
 
-            _ => panic!("The opcode: {:#x} is unrecognized", op),
+    // Reads the byte at PC and moves past it.
+    fn fetch_u8(&mut self) -> u8 {
+        let pc = self.program_counter;
+        self.program_counter = pc.wrapping_add(1);
+        self.cpu_read_u8(pc)
+    }
+
+    fn fetch_u16(&mut self) -> u16 {
+        let lo = self.fetch_u8() as u16;
+        let hi = self.fetch_u8() as u16;
+        hi << 8 | lo
+    }
+
+    // Single byte instructions still read the byte after the opcode, they
+    // just don't use it.
+    fn dummy_read(&mut self) {
+        let pc = self.program_counter;
+        self.cpu_read_u8(pc);
+    }
+
+    // The cycle spent incrementing S before a pull, or waiting in JSR.
+    fn dummy_stack_read(&mut self) {
+        let addr = 0x100 + self.stack_pointer as u16;
+        self.cpu_read_u8(addr);
+    }
+
+    // Pointers in the zero page wrap around without leaving it.
+    fn read_zeropage_u16(&mut self, ptr: u8) -> u16 {
+        let lo = self.cpu_read_u8(ptr as u16) as u16;
+        let hi = self.cpu_read_u8(ptr.wrapping_add(1) as u16) as u16;
+        hi << 8 | lo
+    }
+
+    // Works out the address an instruction operates on, doing the same bus
+    // accesses the 6502 does along the way. write is for stores and
+    // read-modify-write instructions, which can't skip the extra cycle of
+    // the indexed modes.
+    fn operand_addr(&mut self, mode: AddressMode, write: bool) -> u16 {
+        match mode {
+            AddressMode::Immediate => {
+                let addr = self.program_counter;
+                self.program_counter = addr.wrapping_add(1);
+                addr
+            }
+            AddressMode::Zeropage => self.fetch_u8() as u16,
+            AddressMode::ZeropageX => {
+                let base = self.fetch_u8();
+                self.cpu_read_u8(base as u16);
+                base.wrapping_add(self.index_x) as u16
+            }
+            AddressMode::ZeropageY => {
+                let base = self.fetch_u8();
+                self.cpu_read_u8(base as u16);
+                base.wrapping_add(self.index_y) as u16
+            }
+            AddressMode::Absolute => self.fetch_u16(),
+            AddressMode::AbsoluteX => {
+                let base = self.fetch_u16();
+                let index = self.index_x;
+                self.indexed(base, index, write)
+            }
+            AddressMode::AbsoluteY => {
+                let base = self.fetch_u16();
+                let index = self.index_y;
+                self.indexed(base, index, write)
+            }
+            AddressMode::XIndirect => {
+                let ptr = self.fetch_u8();
+                self.cpu_read_u8(ptr as u16);
+                let ptr = ptr.wrapping_add(self.index_x);
+                self.read_zeropage_u16(ptr)
+            }
+            AddressMode::IndirectY => {
+                let ptr = self.fetch_u8();
+                let base = self.read_zeropage_u16(ptr);
+                let index = self.index_y;
+                self.indexed(base, index, write)
+            }
+            _ => panic!("{:?} has no operand address", mode),
+        }
+    }
+
+    // The index is added to the low byte first and the bus is read at that
+    // address while the high byte gets fixed up.
+    fn indexed(&mut self, base: u16, index: u8, write: bool) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        if write || addr & 0xFF00 != base & 0xFF00 {
+            self.cpu_read_u8((base & 0xFF00) | (addr & 0x00FF));
         }
+        addr
     }
 
-    // does NOT effect flags
-    fn and_x_a_store(&mut self, addr: u16) {
-        let value = self.index_x & self.accumulator;
+    fn read_operand(&mut self, mode: AddressMode) -> u8 {
+        let addr = self.operand_addr(mode, false);
+        self.cpu_read_u8(addr)
+    }
+
+    fn write_operand(&mut self, mode: AddressMode, value: u8) {
+        let addr = self.operand_addr(mode, true);
         self.cpu_write_u8(addr, value)
     }
 
-    fn rotate_right(&mut self, addr_mode: AddressMode, addr: u16) {
-        let value = if addr_mode == AddressMode::Accumulator {
-            self.accumulator
+    // Read-modify-write instructions write the unmodified value back while
+    // the ALU works, then the result. Returns the result.
+    fn modify<F>(&mut self, mode: AddressMode, f: F) -> u8
+        where F: FnOnce(&mut CPU, u8) -> u8
+    {
+        let addr = self.operand_addr(mode, true);
+        let value = self.cpu_read_u8(addr);
+        self.cpu_write_u8(addr, value);
+        let result = f(self, value);
+        self.cpu_write_u8(addr, result);
+        result
+    }
+
+    fn modify_accumulator<F>(&mut self, f: F)
+        where F: FnOnce(&mut CPU, u8) -> u8
+    {
+        self.dummy_read();
+        let value = self.accumulator;
+        self.accumulator = f(self, value);
+    }
+
+    fn branch(&mut self, condition: bool) {
+        let offset = self.fetch_u8() as i8;
+        if !condition {
+            return;
+        }
+
+        let poll = self.prev_run_irq;
+        self.dummy_read();
+        let pc = self.program_counter;
+        let addr = pc.wrapping_add(offset as u16);
+        if addr & 0xFF00 != pc & 0xFF00 {
+            self.cpu_read_u8((pc & 0xFF00) | (addr & 0x00FF));
         } else {
-            self.cpu_read_u8(addr)
-        };
+            // a taken branch that stays on its page doesn't poll interrupts
+            // on its last cycle
+            self.prev_run_irq = poll;
+        }
+        self.program_counter = addr;
+    }
 
+    fn rotate_right(&mut self, value: u8) -> u8 {
         let c = if self.status_reg.carry {
             1
         } else {
@@ -580,21 +970,10 @@ impl CPU {
         let value = (value >> 1) | c << 7;
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-
-        if addr_mode == AddressMode::Accumulator {
-            self.set_register(value, RegType::A);
-        } else {
-            self.cpu_write_u8(addr, value);
-        }
+        value
     }
 
-    fn rotate_left(&mut self, addr_mode: AddressMode, addr: u16) {
-        let value = if addr_mode == AddressMode::Accumulator {
-            self.accumulator
-        } else {
-            self.cpu_read_u8(addr)
-        };
-
+    fn rotate_left(&mut self, value: u8) -> u8 {
         let c = if self.status_reg.carry {
             1
         } else {
@@ -604,64 +983,35 @@ impl CPU {
         let value = (value << 1) | c << 0;
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-
-        if addr_mode == AddressMode::Accumulator {
-            self.set_register(value, RegType::A);
-        } else {
-            self.cpu_write_u8(addr, value);
-        }
+        value
     }
 
-    fn shift_right(&mut self, addr_mode: AddressMode, addr: u16) {
-        let value = if addr_mode == AddressMode::Accumulator {
-            self.accumulator
-        } else {
-            self.cpu_read_u8(addr)
-        };
-
+    fn shift_right(&mut self, value: u8) -> u8 {
         self.status_reg.carry = (value & (1 << 0)) != 0;
         let value = value >> 1;
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-
-        if addr_mode == AddressMode::Accumulator {
-            self.set_register(value, RegType::A);
-        } else {
-            self.cpu_write_u8(addr, value);
-        }
+        value
     }
 
-    fn shift_left(&mut self, addr_mode: AddressMode, addr: u16) {
-        let value = if addr_mode == AddressMode::Accumulator {
-            self.accumulator
-        } else {
-            self.cpu_read_u8(addr)
-        };
-
+    fn shift_left(&mut self, value: u8) -> u8 {
         self.status_reg.carry = (value & (1 << 7)) != 0;
         let value = ((value as u16) << 1) as u8;
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-
-        if addr_mode == AddressMode::Accumulator {
-            self.set_register(value, RegType::A);
-        } else {
-            self.cpu_write_u8(addr, value);
-        }
+        value
     }
 
-    fn bitwise_op_to_a<F>(&mut self, f: F, addr: u16)
+    fn bitwise_op_to_a<F>(&mut self, f: F, m: u8)
         where F: FnOnce(u8, u8) -> u8
     {
-
-        let m = self.cpu_read_u8(addr);
         let a = self.accumulator;
         let value = f(a, m);
         self.set_register(value, RegType::A);
     }
 
-    fn add_with_carry(&mut self, addr: u16) {
-        let value = self.cpu_read_u8(addr) as u16;
+    fn add_with_carry(&mut self, value: u8) {
+        let value = value as u16;
         let a = self.accumulator as u16;
         let c = if self.status_reg.carry {
             1
@@ -670,53 +1020,37 @@ impl CPU {
         };
 
         let result = a + value + c;
-        //        println!("ADC: A{:#X} + M{:#X} + C{:#X} = {:X}", a, value, c, result);
         self.status_reg.carry = result > 0xff;
         self.status_reg.overflow = ((a ^ result) & (value ^ result) & 0x80) != 0;
         self.set_register(result as u8, RegType::A);
     }
 
     // implemented as binary add with 1s(ones) compliment of the value being sub from A
-    fn sub_with_carry(&mut self, addr: u16) {
-        let value = self.cpu_read_u8(addr) as u16;
-        let a = self.accumulator as u16;
-        let c = if self.status_reg.carry {
-            1
-        } else {
-            0
-        };
-
-        let result = a + (0xff - value) + c;
-        // println!("SBC: A{:#X} + (0xff-M{:#X}) + C{:#X} = {:X}", a, value, c, result);
-        self.status_reg.carry = result > 0xff;
-        self.status_reg.overflow = ((a ^ result) & ((0xff - value) ^ result) & 0x80) != 0;
-        self.set_register(result as u8, RegType::A);
+    fn sub_with_carry(&mut self, value: u8) {
+        self.add_with_carry(!value);
     }
 
-    fn increment_memory(&mut self, addr: u16) {
-        let mut value = self.cpu_read_u8(addr);
-        value = value.wrapping_add(1);
+    fn increment(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_add(1);
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-        self.cpu_write_u8(addr, value);
+        value
     }
 
-    fn decrement_memory(&mut self, addr: u16) {
-        let mut value = self.cpu_read_u8(addr);
-        value = value.wrapping_sub(1);
+    fn decrement(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_sub(1);
         self.status_reg.zero = value == 0;
         self.status_reg.negative_sign = (value & (1 << 7)) != 0;
-        self.cpu_write_u8(addr, value);
+        value
     }
 
-    fn compare(&mut self, reg: RegType, addr: u16) {
+    fn compare(&mut self, reg: RegType, value: u8) {
         let register = match reg {
             RegType::A => self.accumulator as i16,
             RegType::Y => self.index_y as i16,
             RegType::X => self.index_x as i16,
         };
-        let value = self.cpu_read_u8(addr) as i16;
-        //        println!("CMY {:#X} - {:#X}", register, value);
+        let value = value as i16;
         let result = (register - value) as u8;
         self.status_reg.zero = register == value;
         self.status_reg.negative_sign = (result & (1 << 7)) != 0;
@@ -737,17 +1071,44 @@ impl CPU {
     fn push_stack(&mut self, value: u8) {
         let addr = 0x100 + self.stack_pointer as u16;
         self.cpu_write_u8(addr, value);
-        // println!("stack wrote at 0x01{:x}: {:x}", self.stack_pointer, value);
-        self.stack_pointer -= 1;
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
     fn pull_stack(&mut self) -> u8 {
-        self.stack_pointer += 1;
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
         let tmp = 0x100 + self.stack_pointer as u16;
         self.cpu_read_u8(tmp)
     }
 
-    pub fn cpu_read_u8(&mut self, mut addr: u16) -> u8 {
+    // A read cycle on the bus.
+    pub fn cpu_read_u8(&mut self, addr: u16) -> u8 {
+        self.tick();
+        let value = self.read_bus(addr);
+        self.open_bus = value;
+        #[cfg(test)]
+        self.accesses.push((addr, value, false));
+        value
+    }
+
+    // A write cycle on the bus.
+    fn cpu_write_u8(&mut self, addr: u16, value: u8) {
+        self.tick();
+        self.open_bus = value;
+        #[cfg(test)]
+        self.accesses.push((addr, value, true));
+        self.write_bus(addr, value);
+    }
+
+    // Reads memory without side effects or passing time, for debug output.
+    pub fn peek_u8(&self, addr: u16) -> u8 {
+        match addr {
+            RAM_START...RAM_VIRTUAL_END => self.bus.ram[(addr % RAM_LEN) as usize],
+            EXPANSION_ROM_START...PRG_ROM_END => self.bus.cart.read_cart_u8(addr),
+            _ => 0,
+        }
+    }
+
+    fn read_bus(&mut self, mut addr: u16) -> u8 {
         // println!("Read {:#X}", addr);
         if addr > 0x2007 && addr < 0x4000 {
             addr = 0x2000 + ((addr - 0x2000) % 8)
@@ -768,47 +1129,26 @@ impl CPU {
                 self.bus.ram[addr as usize]
             }
 
-            PPUCTRL => self.bus.ppu.lastwrite,
-            PPUMASK => self.bus.ppu.lastwrite,
-            OAMADDR => self.bus.ppu.lastwrite,
+            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR => self.bus.ppu.lastwrite,
 
             PPUSTATUS => self.bus.ppu.read_ppustatus(),
             PPUDATA => self.bus.ppu.read_ppudata(),
             OAMDATA => self.bus.ppu.read_oamdata(),
 
-            SND_CHN => 0,
+            // bit 5 isn't driven
+            SND_CHN => (self.bus.apu.read_status_reg() & !0x20) | (self.open_bus & 0x20),
             // TODO: implement joysticks
             JOY1 => self.bus.joy.read_joy1(),
             JOY2 => 0,
 
             EXPANSION_ROM_START...PRG_ROM_END => self.bus.cart.read_cart_u8(addr),
 
-            _ => panic!("Invalid read location {:#X}", addr),
-        }
-    }
-
-    // The actual 6502 can't read a u16, this is for convenince only
-    fn cpu_read_u16(&self, mut addr: u16) -> u16 {
-        if addr > 0x2007 && addr < 0x4000 {
-            addr = 0x2000 + ((addr - 0x2000) % 8)
-        }
-        // println!("Read {:#X}", addr);
-        match addr {
-            RAM_START...RAM_VIRTUAL_END => {
-                let addr = addr % RAM_LEN;
-                let lo = self.bus.ram[addr as usize] as u16;
-                let hi = self.bus.ram[(addr as usize) + 1] as u16;
-                hi << 8 | lo
-            }
-
-
-            EXPANSION_ROM_START...PRG_ROM_END => self.bus.cart.read_cart_u16(addr),
-
-            _ => panic!("Invalid u16 read location {:#X}", addr),
+            // write only registers, dummy reads end up here
+            _ => self.open_bus,
         }
     }
 
-    fn cpu_write_u8(&mut self, mut addr: u16, value: u8) {
+    fn write_bus(&mut self, mut addr: u16, value: u8) {
 
         if addr > 0x2007 && addr < 0x4000 {
             addr = 0x2000 + ((addr - 0x2000) % 8)
@@ -817,9 +1157,6 @@ impl CPU {
         match addr {
             RAM_START...RAM_VIRTUAL_END => {
                 let addr = addr % RAM_LEN;
-                if addr > 0x1FF && addr < 0x300 {
-                    // println!("Wrote {:#X} at {:#X} in RAM", value, addr);
-                };
                 self.bus.ram[addr as usize] = value
             }
 
@@ -839,27 +1176,28 @@ impl CPU {
                 self.bus.apu.write(addr, value);
             }
 
-            OAMDMA => {
-                // println!("OAMDMA at {:#X}", value);
-                for i in 0..0x100 {
-                    // println!("OAMDMA");
-                    let ramaddr:usize = (value as usize) << 8 | i;
-                    let data = self.bus.ram[ramaddr];
-                    // println!("ramaddr: {:#X} data: {:#X}", ramaddr, data);
-                    self.bus.ppu.write_oamdata(data);
-                }
-
-                self.cycle += 513 * PPU_MULTIPLIER;
-                self.cycle %= 341;
-                self.bus.ppu.cycles = self.cycle;
-                self.bus.ppu.scanline += 5;
-            }
+            OAMDMA => self.oam_dma(value),
 
             JOY1 => self.bus.joy.strobe_joy(value),
 
             EXPANSION_ROM_START...PRG_ROM_END => self.bus.cart.write_cart_u8(addr, value),
 
-            _ => {panic!("Invalid write location {:#X}", addr);},
+            // $4018-$401F and anything else unmapped, the write goes nowhere
+            _ => {}
+        }
+    }
+
+    // The CPU stops while a page is copied into OAM: a halt cycle, one more
+    // to line up with a read cycle if needed, then a read and a write per
+    // byte. 513 or 514 cycles in total.
+    fn oam_dma(&mut self, page: u8) {
+        self.tick();
+        if self.cycle % 2 == 1 {
+            self.tick();
+        }
+        for i in 0..0x100 {
+            let data = self.cpu_read_u8((page as u16) << 8 | i);
+            self.cpu_write_u8(OAMDATA, data);
         }
     }
 
@@ -874,7 +1212,6 @@ impl From<u8> for StatusReg {
             negative_sign: (value & (1 << 7)) != 0, // N
             overflow: (value & (1 << 6)) != 0, // V
             unused: true,
-            break_flag: (value & (1 << 4)) != 0, // B
             decimal_mode: (value & (1 << 3)) != 0, // D
             interrupt_disable: (value & (1 << 2)) != 0, // I
             zero: (value & (1 << 1)) != 0, // Z
@@ -896,9 +1233,6 @@ impl Into<u8> for StatusReg {
         if self.unused {
             value = value | 1 << 5
         }
-        if self.break_flag {
-            value = value | 1 << 4
-        }
         if self.decimal_mode {
             value = value | 1 << 3
         }
@@ -915,3 +1249,562 @@ impl Into<u8> for StatusReg {
         value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use cart::Cart;
+    use mapper::{Mapper, Mirroring, SharedMapper};
+    use rom::RomHeader;
+
+    const NMI_HANDLER: u16 = 0x9000;
+    const IRQ_HANDLER: u16 = 0xA000;
+
+    // RAM over the whole cartridge space, with an IRQ output that goes up
+    // on the CPU cycle the test asks for
+    struct TestBoard {
+        mem: Vec<u8>,
+        cycle: u64,
+        irq_at: Rc<Cell<u64>>,
+    }
+
+    impl Mapper for TestBoard {
+        fn cpu_read(&self, addr: u16) -> u8 {
+            self.mem[addr as usize]
+        }
+
+        fn cpu_write(&mut self, addr: u16, value: u8) {
+            self.mem[addr as usize] = value;
+        }
+
+        fn ppu_read(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _value: u8) {}
+
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Horizontal
+        }
+
+        fn irq(&self) -> bool {
+            self.cycle >= self.irq_at.get()
+        }
+
+        fn tick(&mut self) {
+            self.cycle += 1;
+        }
+
+        fn save_state(&self, _out: &mut StateWriter) {}
+
+        fn load_state(&mut self, _input: &mut StateReader) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    // Powers up with prg at $8000 and NOPs everywhere else, I clear. The
+    // board's IRQ line stays down until the returned cell is set.
+    fn cpu(prg: &[u8]) -> (CPU, Rc<Cell<u64>>) {
+        let irq_at = Rc::new(Cell::new(u64::max_value()));
+        let mut mem = vec![0xEA; 0x10000];
+        mem[0x8000..0x8000 + prg.len()].copy_from_slice(prg);
+        for &(vector, handler) in &[(NMI_VECTOR_LOC, NMI_HANDLER),
+                                    (IRQ_BRK_VECTOR_LOC, IRQ_HANDLER)] {
+            mem[vector as usize] = handler as u8;
+            mem[vector as usize + 1] = (handler >> 8) as u8;
+        }
+        let board = TestBoard {
+            mem: mem,
+            cycle: 0,
+            irq_at: irq_at.clone(),
+        };
+        let mapper: SharedMapper = Rc::new(RefCell::new(Box::new(board) as Box<Mapper>));
+
+        let header = RomHeader::parse(b"NES\x1a\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00")
+            .unwrap();
+        let bus = Bus {
+            ram: vec![0; RAM_LEN as usize].into_boxed_slice(),
+            cart: Cart::with_mapper(header, mapper.clone()),
+            apu: apu::APU::new(),
+            ppu: ppu::PPU::new(mapper),
+            joy: joy::Joy::new(),
+        };
+        let mut cpu = CPU::new(bus, 0x8000);
+        cpu.status_reg = 0x20.into();
+        (cpu, irq_at)
+    }
+
+    // The return address and P the last interrupt pushed.
+    fn pushed(cpu: &CPU) -> (u16, u8) {
+        let s = 0x100 + cpu.stack_pointer as usize;
+        let ram = &cpu.bus.ram;
+        ((ram[s + 3] as u16) << 8 | ram[s + 2] as u16, ram[s + 1])
+    }
+
+    fn status(cpu: &CPU) -> u8 {
+        cpu.status_reg.into()
+    }
+
+    #[test]
+    fn irq_is_serviced_after_the_current_instruction() {
+        let (mut cpu, irq_at) = cpu(&[0xEA]);
+        irq_at.set(0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        // 2 for the NOP, 7 for the interrupt sequence
+        assert_eq!(cpu.cycle, 9);
+        assert_eq!(pushed(&cpu), (0x8001, 0x20));
+        assert_eq!(status(&cpu), 0x24);
+    }
+
+    #[test]
+    fn cli_delays_the_irq_by_one_instruction() {
+        let (mut cpu, irq_at) = cpu(&[0x58, 0xEA, 0xEA]);
+        cpu.status_reg = 0x24.into();
+        irq_at.set(0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8001);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(cpu.cycle, 11);
+        assert_eq!(pushed(&cpu), (0x8002, 0x20));
+    }
+
+    #[test]
+    fn sei_still_lets_a_pending_irq_through() {
+        let (mut cpu, irq_at) = cpu(&[0x78, 0xEA]);
+        irq_at.set(0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        // the handler sees the I the SEI set
+        assert_eq!(pushed(&cpu), (0x8001, 0x24));
+    }
+
+    #[test]
+    fn plp_delays_the_irq_by_one_instruction() {
+        let (mut cpu, irq_at) = cpu(&[0x28, 0xEA, 0xEA]);
+        cpu.status_reg = 0x24.into();
+        cpu.bus.ram[0x1FE] = 0x00;
+        irq_at.set(0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8001);
+        assert_eq!(cpu.cycle, 4);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu), (0x8002, 0x20));
+    }
+
+    #[test]
+    fn rti_lets_an_irq_in_straight_away() {
+        let (mut cpu, irq_at) = cpu(&[0x40]);
+        cpu.status_reg = 0x24.into();
+        cpu.stack_pointer = 0xFA;
+        cpu.bus.ram[0x1FB] = 0x00;
+        cpu.bus.ram[0x1FC] = 0x10;
+        cpu.bus.ram[0x1FD] = 0x80;
+        irq_at.set(0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(cpu.cycle, 13);
+        assert_eq!(pushed(&cpu), (0x8010, 0x20));
+    }
+
+    #[test]
+    fn irq_raised_on_the_second_to_last_cycle_is_serviced() {
+        // JMP abs, 3 cycles
+        let (mut cpu, irq_at) = cpu(&[0x4C, 0x00, 0x81]);
+        irq_at.set(2);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu), (0x8100, 0x20));
+    }
+
+    #[test]
+    fn taken_branch_on_the_same_page_skips_a_poll() {
+        // BCC +0, taken and 3 cycles like the JMP above
+        let (mut cpu, irq_at) = cpu(&[0x90, 0x00, 0xEA]);
+        irq_at.set(2);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.cycle, 3);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu), (0x8003, 0x20));
+    }
+
+    #[test]
+    fn taken_branch_across_a_page_polls_as_usual() {
+        let (mut cpu, irq_at) = cpu(&[]);
+        // BCC +1 from $80FD lands on $8100
+        cpu.bus.cart.write_cart_u8(0x80FD, 0x90);
+        cpu.bus.cart.write_cart_u8(0x80FE, 0x01);
+        cpu.program_counter = 0x80FD;
+        irq_at.set(2);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(cpu.cycle, 11);
+        assert_eq!(pushed(&cpu), (0x8100, 0x20));
+    }
+
+    #[test]
+    fn nmi_hijacks_brk_but_keeps_b() {
+        let (mut cpu, _) = cpu(&[0x00, 0x00]);
+        // NMI goes low during the BRK's opcode fetch
+        cpu.trigger_nmi();
+        cpu.step();
+        assert_eq!(cpu.program_counter, NMI_HANDLER);
+        assert_eq!(cpu.cycle, 7);
+        assert_eq!(pushed(&cpu), (0x8002, 0x30));
+        // it's been serviced, so the handler runs
+        cpu.step();
+        assert_eq!(cpu.program_counter, NMI_HANDLER + 1);
+    }
+
+    #[test]
+    fn nmi_hijacks_irq() {
+        let (mut cpu, irq_at) = cpu(&[0xEA]);
+        irq_at.set(0);
+        cpu.trigger_nmi();
+        cpu.step();
+        assert_eq!(cpu.program_counter, NMI_HANDLER);
+        assert_eq!(pushed(&cpu), (0x8001, 0x20));
+        // the IRQ is still up but I is set now
+        cpu.step();
+        assert_eq!(cpu.program_counter, NMI_HANDLER + 1);
+    }
+
+    #[test]
+    fn php_and_brk_push_b_set() {
+        let (mut cpu, _) = cpu(&[0x08, 0x00, 0x00]);
+        cpu.step();
+        assert_eq!(cpu.bus.ram[0x1FD], 0x30);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu), (0x8003, 0x30));
+        assert_eq!(status(&cpu), 0x24);
+    }
+
+    #[test]
+    fn mapper_irq_is_masked_by_i_and_follows_the_board() {
+        let (mut cpu, irq_at) = cpu(&[0xEA, 0xEA, 0xEA, 0x58, 0xEA]);
+        cpu.status_reg = 0x24.into();
+        irq_at.set(0);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.irq_line, IRQ_MAPPER);
+
+        // once the board lets go there's nothing left to service
+        irq_at.set(u64::max_value());
+        cpu.step();
+        assert_eq!(cpu.irq_line, 0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn jammed_cpu_ignores_interrupts() {
+        let (mut cpu, irq_at) = cpu(&[0x02]);
+        irq_at.set(0);
+        cpu.trigger_nmi();
+        cpu.step();
+        cpu.step();
+        assert!(cpu.jammed());
+        assert_eq!(cpu.program_counter, 0x8000);
+    }
+
+    const R: bool = false;
+    const W: bool = true;
+
+    // Runs one instruction and returns the bus accesses it made, one a cycle.
+    fn run(cpu: &mut CPU) -> Vec<(u16, u8, bool)> {
+        cpu.accesses.clear();
+        cpu.step();
+        cpu.accesses.clone()
+    }
+
+    #[test]
+    fn immediate() {
+        let (mut cpu, _) = cpu(&[0xA9, 0x42]);
+        assert_eq!(run(&mut cpu), vec![(0x8000, 0xA9, R), (0x8001, 0x42, R)]);
+        assert_eq!(cpu.accumulator, 0x42);
+    }
+
+    #[test]
+    fn zeropage() {
+        let (mut cpu, _) = cpu(&[0xA5, 0x10]);
+        cpu.bus.ram[0x10] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xA5, R), (0x8001, 0x10, R), (0x0010, 0x55, R)]);
+    }
+
+    #[test]
+    fn zeropage_x_reads_the_base_first() {
+        let (mut cpu, _) = cpu(&[0xB5, 0x10]);
+        cpu.index_x = 5;
+        cpu.bus.ram[0x10] = 0x11;
+        cpu.bus.ram[0x15] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xB5, R), (0x8001, 0x10, R), (0x0010, 0x11, R),
+                        (0x0015, 0x55, R)]);
+    }
+
+    #[test]
+    fn zeropage_y_wraps_in_the_zero_page() {
+        // LDX $FF,Y
+        let (mut cpu, _) = cpu(&[0xB6, 0xFF]);
+        cpu.index_y = 2;
+        cpu.bus.ram[0x01] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xB6, R), (0x8001, 0xFF, R), (0x00FF, 0x00, R),
+                        (0x0001, 0x55, R)]);
+        assert_eq!(cpu.index_x, 0x55);
+    }
+
+    #[test]
+    fn absolute() {
+        let (mut cpu, _) = cpu(&[0xAD, 0x34, 0x02]);
+        cpu.bus.ram[0x234] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xAD, R), (0x8001, 0x34, R), (0x8002, 0x02, R),
+                        (0x0234, 0x55, R)]);
+    }
+
+    #[test]
+    fn absolute_x_load_on_the_same_page() {
+        let (mut cpu, _) = cpu(&[0xBD, 0x30, 0x02]);
+        cpu.index_x = 4;
+        cpu.bus.ram[0x234] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xBD, R), (0x8001, 0x30, R), (0x8002, 0x02, R),
+                        (0x0234, 0x55, R)]);
+    }
+
+    #[test]
+    fn absolute_x_load_across_a_page_reads_the_wrong_page_first() {
+        let (mut cpu, _) = cpu(&[0xBD, 0xF0, 0x02]);
+        cpu.index_x = 0x20;
+        cpu.bus.ram[0x210] = 0x11;
+        cpu.bus.ram[0x310] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xBD, R), (0x8001, 0xF0, R), (0x8002, 0x02, R),
+                        (0x0210, 0x11, R), (0x0310, 0x55, R)]);
+        assert_eq!(cpu.accumulator, 0x55);
+    }
+
+    #[test]
+    fn absolute_y_load_on_the_same_page() {
+        let (mut cpu, _) = cpu(&[0xB9, 0x30, 0x02]);
+        cpu.index_y = 4;
+        cpu.bus.ram[0x234] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xB9, R), (0x8001, 0x30, R), (0x8002, 0x02, R),
+                        (0x0234, 0x55, R)]);
+    }
+
+    #[test]
+    fn absolute_x_store_always_does_the_dummy_read() {
+        let (mut cpu, _) = cpu(&[0x9D, 0x30, 0x02]);
+        cpu.index_x = 4;
+        cpu.accumulator = 0x99;
+        cpu.bus.ram[0x234] = 0x11;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0x9D, R), (0x8001, 0x30, R), (0x8002, 0x02, R),
+                        (0x0234, 0x11, R), (0x0234, 0x99, W)]);
+    }
+
+    #[test]
+    fn indexed_indirect() {
+        let (mut cpu, _) = cpu(&[0xA1, 0x10]);
+        cpu.index_x = 4;
+        cpu.bus.ram[0x14] = 0x34;
+        cpu.bus.ram[0x15] = 0x02;
+        cpu.bus.ram[0x234] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xA1, R), (0x8001, 0x10, R), (0x0010, 0x00, R),
+                        (0x0014, 0x34, R), (0x0015, 0x02, R), (0x0234, 0x55, R)]);
+    }
+
+    #[test]
+    fn indirect_indexed_across_a_page() {
+        let (mut cpu, _) = cpu(&[0xB1, 0x10]);
+        cpu.index_y = 0x20;
+        cpu.bus.ram[0x10] = 0xF0;
+        cpu.bus.ram[0x11] = 0x02;
+        cpu.bus.ram[0x210] = 0x11;
+        cpu.bus.ram[0x310] = 0x55;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xB1, R), (0x8001, 0x10, R), (0x0010, 0xF0, R),
+                        (0x0011, 0x02, R), (0x0210, 0x11, R), (0x0310, 0x55, R)]);
+    }
+
+    #[test]
+    fn read_modify_write_writes_the_old_value_back_first() {
+        let (mut cpu, _) = cpu(&[0xEE, 0x34, 0x02]);
+        cpu.bus.ram[0x234] = 0x41;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0xEE, R), (0x8001, 0x34, R), (0x8002, 0x02, R),
+                        (0x0234, 0x41, R), (0x0234, 0x41, W), (0x0234, 0x42, W)]);
+    }
+
+    #[test]
+    fn implied_and_accumulator_read_the_next_byte() {
+        let (mut cpu, _) = cpu(&[0xEA, 0x0A, 0x33]);
+        assert_eq!(run(&mut cpu), vec![(0x8000, 0xEA, R), (0x8001, 0x0A, R)]);
+        assert_eq!(run(&mut cpu), vec![(0x8001, 0x0A, R), (0x8002, 0x33, R)]);
+    }
+
+    #[test]
+    fn indirect_jump_doesnt_carry_into_the_high_byte() {
+        let (mut cpu, _) = cpu(&[0x6C, 0xFF, 0x02]);
+        cpu.bus.ram[0x2FF] = 0x34;
+        cpu.bus.ram[0x200] = 0x91;
+        cpu.bus.ram[0x300] = 0x12;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0x6C, R), (0x8001, 0xFF, R), (0x8002, 0x02, R),
+                        (0x02FF, 0x34, R), (0x0200, 0x91, R)]);
+        assert_eq!(cpu.program_counter, 0x9134);
+    }
+
+    #[test]
+    fn branch_across_a_page_reads_the_wrong_page_first() {
+        let (mut cpu, _) = cpu(&[]);
+        cpu.bus.cart.write_cart_u8(0x80FD, 0x90);
+        cpu.bus.cart.write_cart_u8(0x80FE, 0x01);
+        cpu.program_counter = 0x80FD;
+        assert_eq!(run(&mut cpu),
+                   vec![(0x80FD, 0x90, R), (0x80FE, 0x01, R), (0x80FF, 0xEA, R),
+                        (0x8000, 0xEA, R)]);
+        assert_eq!(cpu.program_counter, 0x8100);
+    }
+
+    #[test]
+    fn jsr_and_rts() {
+        let (mut cpu, _) = cpu(&[0x20, 0x00, 0x90]);
+        cpu.bus.cart.write_cart_u8(0x9000, 0x60);
+        assert_eq!(run(&mut cpu),
+                   vec![(0x8000, 0x20, R), (0x8001, 0x00, R), (0x01FD, 0x00, R),
+                        (0x01FD, 0x80, W), (0x01FC, 0x02, W), (0x8002, 0x90, R)]);
+        assert_eq!(cpu.program_counter, 0x9000);
+
+        assert_eq!(run(&mut cpu),
+                   vec![(0x9000, 0x60, R), (0x9001, 0xEA, R), (0x01FB, 0x00, R),
+                        (0x01FC, 0x02, R), (0x01FD, 0x80, R), (0x8002, 0x90, R)]);
+        assert_eq!(cpu.program_counter, 0x8003);
+    }
+
+    // Unofficial opcodes with an immediate or zero page operand, as the
+    // opcode then A, X, P and M before and after. M is the operand, either
+    // way $10 holds it.
+    const UNOFFICIAL: &'static [(u8, [u8; 4], [u8; 4])] = &[
+        // SLO
+        (0x07, [0x10, 0x00, 0x20, 0x81], [0x12, 0x00, 0x21, 0x02]),
+        // RLA
+        (0x27, [0xFF, 0x00, 0x21, 0x80], [0x01, 0x00, 0x21, 0x01]),
+        // SRE
+        (0x47, [0x01, 0x00, 0x20, 0x03], [0x00, 0x00, 0x23, 0x01]),
+        // RRA, the carry out of the ROR goes into the ADC
+        (0x67, [0x7F, 0x00, 0x20, 0x03], [0x81, 0x00, 0xE0, 0x01]),
+        // SAX leaves the flags alone
+        (0x87, [0xF0, 0x3C, 0xA3, 0x00], [0xF0, 0x3C, 0xA3, 0x30]),
+        // LAX
+        (0xA7, [0x00, 0x00, 0x22, 0x80], [0x80, 0x80, 0xA0, 0x80]),
+        // DCP
+        (0xC7, [0x10, 0x00, 0x20, 0x11], [0x10, 0x00, 0x23, 0x10]),
+        // ISC
+        (0xE7, [0x20, 0x00, 0x21, 0x0F], [0x10, 0x00, 0x21, 0x10]),
+        (0xE7, [0x80, 0x00, 0x21, 0x00], [0x7F, 0x00, 0x61, 0x01]),
+        // ANC copies N into C
+        (0x0B, [0xFF, 0x00, 0x20, 0x80], [0x80, 0x00, 0xA1, 0x80]),
+        (0x2B, [0xFF, 0x00, 0x21, 0x7F], [0x7F, 0x00, 0x20, 0x7F]),
+        // ALR
+        (0x4B, [0xFF, 0x00, 0x20, 0x03], [0x01, 0x00, 0x21, 0x03]),
+        (0x4B, [0x01, 0x00, 0x20, 0x01], [0x00, 0x00, 0x23, 0x01]),
+        // ARR, C is bit 6 of the result and V bit 6 xor bit 5
+        (0x6B, [0xFF, 0x00, 0x21, 0xFF], [0xFF, 0x00, 0xA1, 0xFF]),
+        (0x6B, [0xFF, 0x00, 0x20, 0x80], [0x40, 0x00, 0x61, 0x80]),
+        (0x6B, [0xFF, 0x00, 0x21, 0x40], [0xA0, 0x00, 0xE0, 0x40]),
+        (0x6B, [0x01, 0x00, 0x20, 0x01], [0x00, 0x00, 0x22, 0x01]),
+        // AXS, C is set when there's no borrow and V is left alone
+        (0xCB, [0xF0, 0x3C, 0x20, 0x10], [0xF0, 0x20, 0x21, 0x10]),
+        (0xCB, [0xFF, 0x0F, 0x21, 0x10], [0xFF, 0xFF, 0xA0, 0x10]),
+        (0xCB, [0x10, 0x10, 0x60, 0x10], [0x10, 0x00, 0x63, 0x10]),
+    ];
+
+    #[test]
+    fn unofficial_opcode_results_and_flags() {
+        for &(op, before, after) in UNOFFICIAL {
+            let immediate = match INSTRUCTIONS[op as usize].addr_mode {
+                AddressMode::Immediate => true,
+                _ => false,
+            };
+            let operand = if immediate { before[3] } else { 0x10 };
+            let (mut cpu, _) = cpu(&[op, operand]);
+            cpu.accumulator = before[0];
+            cpu.index_x = before[1];
+            cpu.status_reg = before[2].into();
+            cpu.bus.ram[0x10] = before[3];
+            cpu.step();
+
+            let got = [cpu.accumulator, cpu.index_x, status(&cpu), cpu.bus.ram[0x10]];
+            assert!(got == after,
+                    "{:02X} with {:?}: got {:?}, want {:?}",
+                    op, before, got, after);
+        }
+    }
+
+    #[test]
+    fn sh_stores_and_with_the_high_byte_plus_one() {
+        // SHX $0230,Y, SHY $0230,X and SHA $0230,Y, all storing $FF & 3
+        let cases = [(0x9E, 0x00, 0xFF, 4), (0x9C, 0x00, 4, 0xFF), (0x9F, 0xFF, 0xFF, 4)];
+        for &(op, a, x, y) in &cases {
+            let (mut cpu, _) = cpu(&[op, 0x30, 0x02]);
+            cpu.accumulator = a;
+            cpu.index_x = x;
+            cpu.index_y = y;
+            cpu.step();
+            assert_eq!(cpu.bus.ram[0x234], 0x03, "{:02X}", op);
+        }
+    }
+
+    #[test]
+    fn sh_across_a_page_corrupts_the_high_byte_of_the_address() {
+        // SHX $02F0,Y with Y=$20 stores 5 & 3 at $0110 instead of $0310
+        let (mut cpu, _) = cpu(&[0x9E, 0xF0, 0x02]);
+        cpu.index_x = 0x05;
+        cpu.index_y = 0x20;
+        cpu.bus.ram[0x310] = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.bus.ram[0x110], 0x01);
+        assert_eq!(cpu.bus.ram[0x310], 0xFF);
+    }
+
+    #[test]
+    fn tas_sets_s_and_las_loads_from_it() {
+        // TAS $0230,Y
+        let (mut cpu, _) = cpu(&[0x9B, 0x30, 0x02, 0xBB, 0x30, 0x02]);
+        cpu.accumulator = 0xF3;
+        cpu.index_x = 0x3F;
+        cpu.index_y = 4;
+        cpu.bus.ram[0x234] = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.stack_pointer, 0x33);
+        assert_eq!(cpu.bus.ram[0x234], 0x03);
+
+        // LAS $0230,Y
+        cpu.step();
+        assert_eq!((cpu.accumulator, cpu.index_x, cpu.stack_pointer), (0x03, 0x03, 0x03));
+        assert_eq!(status(&cpu), 0x20);
+    }
+
+    #[test]
+    fn writes_to_unmapped_addresses_go_nowhere() {
+        // STA $4018
+        let (mut cpu, _) = cpu(&[0x8D, 0x18, 0x40]);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8003);
+    }
+}
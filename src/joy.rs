@@ -1,4 +1,14 @@
-use sdl2::keyboard::Keycode;
+use state::{StateError, StateReader, StateWriter};
+
+// Bit positions follow the order the controller shifts them out in.
+pub const BUTTON_A: u8 = 1 << 0;
+pub const BUTTON_B: u8 = 1 << 1;
+pub const BUTTON_SELECT: u8 = 1 << 2;
+pub const BUTTON_START: u8 = 1 << 3;
+pub const BUTTON_UP: u8 = 1 << 4;
+pub const BUTTON_DOWN: u8 = 1 << 5;
+pub const BUTTON_LEFT: u8 = 1 << 6;
+pub const BUTTON_RIGHT: u8 = 1 << 7;
 
 pub struct Joy {
     joy1: u8,
@@ -19,37 +29,9 @@ impl Joy {
         }
     }
 
-    pub fn set_keys(&mut self, keys: Vec<Keycode>) {
-        for key in keys {
-            match key {
-                Keycode::LCtrl => {
-                    // panic!("Works..");
-                    self.joy1 |= 1 << 0;
-                }
-                Keycode::LShift => {
-                    self.joy1 |= 1 << 1;
-                }
-                Keycode::Space => {
-                    self.joy1 |= 1 << 2;
-                }
-                Keycode::Return => {
-                    self.joy1 |= 1 << 3;
-                }
-                Keycode::Up => {
-                    self.joy1 |= 1 << 4;
-                }
-                Keycode::Down => {
-                    self.joy1 |= 1 << 5;
-                }
-                Keycode::Left => {
-                    self.joy1 |= 1 << 6;
-                }
-                Keycode::Right => {
-                    self.joy1 |= 1 << 7;
-                }
-                _ => ()// panic!("Unkown key {:?}", key),
-            }
-        }
+    // buttons is a mask of the BUTTON_* bits, as read out by the game
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.joy1 |= buttons;
     }
 
     pub fn strobe_joy(&mut self, value: u8) {
@@ -61,6 +43,21 @@ impl Joy {
         }
     }
 
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.joy1);
+        out.u8(self._joy2);
+        out.u8(self.joy1_read);
+        out.u8(self.joy2_read);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.joy1 = try!(input.u8());
+        self._joy2 = try!(input.u8());
+        self.joy1_read = try!(input.u8());
+        self.joy2_read = try!(input.u8());
+        Ok(())
+    }
+
     pub fn read_joy1(&mut self) -> u8 {
         let ret = (self.joy1 & (1 << self.joy1_read)) >> self.joy1_read;
         // println!("joy1 {:#b} ret {} at {}", self.joy1, ret, self.joy1_read);
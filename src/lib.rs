@@ -0,0 +1,518 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub mod cart;
+pub mod mem_map;
+pub mod cpu;
+pub mod apu;
+pub mod ppu;
+pub mod joy;
+pub mod opcodes;
+pub mod mapper;
+pub mod rom;
+pub mod wav;
+pub mod state;
+pub mod rewind;
+pub mod movie;
+
+use opcodes::AddressMode;
+
+use mem_map::*;
+use movie::{Movie, MovieError};
+use rom::RomError;
+use state::{StateError, StateReader, StateWriter};
+
+// How often battery backed RAM gets flushed while running, about 10s.
+const BATTERY_SAVE_INTERVAL: usize = 600;
+
+pub struct Bus {
+    pub ram: Box<[u8]>,
+    pub cart: cart::Cart,
+    pub apu: apu::APU,
+    pub ppu: ppu::PPU,
+    pub joy: joy::Joy,
+}
+
+// The whole console, without any notion of a window or an input device.
+// Frontends feed it controller state and pull finished frames out of it.
+pub struct Nes {
+    pub cpu: cpu::CPU,
+
+    frame_ready: bool,
+    frames_since_save: usize,
+    // frames run since power on, kept in save states so movies can tell
+    // where they are
+    frame: u64,
+
+    // what the frontend last set, read by the game from the next frame on
+    buttons: u8,
+    movie: Option<MovieMode>,
+    // the frame the movie's first input goes to
+    movie_start: u64,
+
+    recorder: Option<wav::Recorder>,
+    // log every instruction to stderr, see cpu_debug
+    trace: bool,
+}
+
+enum MovieMode {
+    Recording(Movie),
+    Playing(Movie),
+}
+
+impl Nes {
+    pub fn new(rompath: &String) -> Result<Nes, RomError> {
+        Nes::with_save_dir(rompath, None)
+    }
+
+    // save_dir is where battery saves go, next to the ROM if None.
+    pub fn with_save_dir(rompath: &String, save_dir: Option<&Path>) -> Result<Nes, RomError> {
+        Nes::with_options(rompath, save_dir, true)
+    }
+
+    // Without battery the save file is neither loaded nor written, so runs
+    // start from the same PRG RAM whatever is on disk and leave it as it
+    // was. Movie playback and scripted runs want that.
+    pub fn with_options(rompath: &String,
+                        save_dir: Option<&Path>,
+                        battery: bool)
+                        -> Result<Nes, RomError>
+    {
+        let mut cart = try!(cart::Cart::new(rompath));
+        if let Some(dir) = save_dir {
+            cart.set_save_dir(dir);
+        }
+        cart.set_battery_enabled(battery);
+        if let Err(e) = cart.load_battery() {
+            warn(format_args!("Couldn't load the battery save: {}", e));
+        }
+        let apu = apu::APU::new();
+
+        let ppu = ppu::PPU::new(cart.mapper());
+        let joy = joy::Joy::new();
+
+        let cpubus = Bus {
+            ram: vec![0; RAM_LEN as usize].into_boxed_slice(),
+            cart: cart,
+            apu: apu,
+            ppu: ppu,
+            joy: joy,
+        };
+
+        let pc = cpubus.cart.read_cart_u16(RESET_VECTOR_LOC);
+        let cpu = cpu::CPU::new(cpubus, pc as u16);
+
+        Ok(Nes {
+            cpu: cpu,
+            frame_ready: false,
+            frames_since_save: 0,
+            frame: 0,
+
+            buttons: 0,
+            movie: None,
+            movie_start: 0,
+
+            recorder: None,
+            trace: false,
+        })
+    }
+
+    // Flushes battery backed RAM to disk, frontends should call this on exit.
+    pub fn save_battery(&mut self) -> io::Result<()> {
+        self.frames_since_save = 0;
+        self.cpu.bus.cart.save_battery()
+    }
+
+    // Logs each instruction before it runs, in the format of nestest's log,
+    // to stderr.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    // Runs one instruction, with the rest of the console clocked along
+    // with each of its cycles. Returns false once the CPU has jammed on a
+    // KIL opcode, as nothing but a reset gets it out of that.
+    pub fn step_instruction(&mut self) -> bool {
+        let cpu = &mut self.cpu;
+
+        if cpu.jammed() {
+            return false;
+        }
+
+        if self.trace {
+            cpu_debug(cpu);
+        }
+
+        let scanline = cpu.bus.ppu.scanline;
+        cpu.step();
+        if scanline < 240 && cpu.bus.ppu.scanline >= 240 {
+            self.frame_ready = true;
+        }
+
+        !cpu.jammed()
+    }
+
+    // Runs instructions until the PPU has finished drawing the visible
+    // part of a frame. Returns false if the CPU jammed before that.
+    pub fn run_frame(&mut self) -> bool {
+        let buttons = self.next_buttons();
+        self.cpu.bus.joy.set_buttons(buttons);
+
+        self.frame_ready = false;
+        while !self.frame_ready {
+            if !self.step_instruction() {
+                return false;
+            }
+        }
+        self.frame_ready = false;
+        self.frame += 1;
+
+        if let Err(e) = self.write_recording() {
+            warn(format_args!("Couldn't write the audio recording, stopping it: {}", e));
+            self.recorder = None;
+            self.cpu.bus.apu.stop_capture();
+        }
+
+        self.frames_since_save += 1;
+        if self.frames_since_save >= BATTERY_SAVE_INTERVAL {
+            if let Err(e) = self.save_battery() {
+                warn(format_args!("Couldn't write the battery save: {}", e));
+            }
+        }
+        true
+    }
+
+    // Runs a frame whose audio has already been heard, like the ones
+    // rewinding shows. Nothing from it is recorded or left for
+    // take_samples.
+    pub fn run_frame_muted(&mut self) -> bool {
+        let recorder = self.recorder.take();
+        let ran = self.run_frame();
+        self.recorder = recorder;
+        self.cpu.bus.apu.take_capture();
+        self.cpu.bus.apu.take_samples();
+        ran
+    }
+
+    pub fn framebuffer(&self) -> &[[u32; 256]; 240] {
+        &self.cpu.bus.ppu.screen
+    }
+
+    // Audio produced since the last call, as mono samples at the rate set
+    // with set_sample_rate. Roughly -1.0 to 1.0 once filtered, the raw
+    // profile gives the mixer's 0.0 to 1.0.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(rate);
+    }
+
+    pub fn set_filter_profile(&mut self, profile: apu::FilterProfile) {
+        self.cpu.bus.apu.set_filter_profile(profile);
+    }
+
+    // Mutes the APU channels whose bit is clear, see APU::set_channel_mask.
+    pub fn set_channel_mask(&mut self, mask: u8) {
+        self.cpu.bus.apu.set_channel_mask(mask);
+    }
+
+    pub fn channel_mask(&self) -> u8 {
+        self.cpu.bus.apu.channel_mask()
+    }
+
+    // Whether to stick to eight sprites a line like the hardware, which
+    // makes games flicker when they have more.
+    pub fn set_sprite_limit(&mut self, limit: bool) {
+        self.cpu.bus.ppu.set_sprite_limit(limit);
+    }
+
+    // Per channel levels for a visualiser, see APU::take_scope.
+    pub fn set_scope(&mut self, enabled: bool) {
+        self.cpu.bus.apu.set_scope(enabled);
+    }
+
+    pub fn take_scope(&mut self) -> Vec<[u8; 5]> {
+        self.cpu.bus.apu.take_scope()
+    }
+
+    // Records the audio to a WAV file at path, see wav::Recorder for where
+    // the channels go with channels set. Samples are written at the current
+    // sample rate, and whether or not the frontend takes them.
+    pub fn start_recording(&mut self, path: &Path, channels: bool) -> io::Result<()> {
+        try!(self.stop_recording());
+        let sample_rate = self.cpu.bus.apu.sample_rate();
+        self.recorder = Some(try!(wav::Recorder::create(path, sample_rate, channels)));
+        self.cpu.bus.apu.start_capture(channels);
+        Ok(())
+    }
+
+    // Writes out what's left and closes the files.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        try!(self.write_recording());
+        self.cpu.bus.apu.stop_capture();
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn write_recording(&mut self) -> io::Result<()> {
+        if let Some(ref mut recorder) = self.recorder {
+            if let Some((mix, channels)) = self.cpu.bus.apu.take_capture() {
+                try!(recorder.write(&mix, &channels));
+            }
+        }
+        Ok(())
+    }
+
+    // Snapshots the whole console, see state.rs for the format. States
+    // are taken between instructions and tied to the ROM they came from.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter::new(self.cpu.bus.cart.hash);
+        out.u64(self.frame);
+        self.cpu.save_state(&mut out);
+        out.into_bytes()
+    }
+
+    // Puts the console back the way save_state found it. On an error the
+    // console carries on as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let hash = self.cpu.bus.cart.hash;
+        let mut input = try!(StateReader::new(data, hash));
+
+        let backup = self.save_state();
+        let result = self.load_state_from(&mut input).and_then(|_| input.finish());
+        if result.is_err() {
+            let mut input = StateReader::new(&backup, hash).unwrap();
+            self.load_state_from(&mut input).unwrap();
+            return result;
+        }
+        self.frame_ready = false;
+
+        // Going back before a recording started means starting it over
+        // from here. Otherwise the frames after this one get recorded again.
+        if self.frame < self.movie_start {
+            if let Some(MovieMode::Recording(_)) = self.movie {
+                self.start_movie_recording();
+            }
+        }
+        result
+    }
+
+    fn load_state_from(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.frame = try!(input.u64());
+        self.cpu.load_state(input)
+    }
+
+    // The file for a numbered save state slot.
+    pub fn state_path(&self, slot: u8) -> PathBuf {
+        self.cpu.bus.cart.state_path(slot)
+    }
+
+    // Controller 1 state as a mask of the joy::BUTTON_* bits, for the
+    // next frame. A movie being played overrides it until it runs out.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    // Records the input from the next frame on, starting from a save state
+    // of the console as it is now.
+    pub fn start_movie_recording(&mut self) {
+        let cart = &self.cpu.bus.cart;
+        let movie = Movie::new(cart.hash, cart.md5, Some(self.save_state()));
+        self.movie = Some(MovieMode::Recording(movie));
+        self.movie_start = self.frame;
+    }
+
+    // Loads the movie's starting state, if it has one, and plays its input
+    // from the next frame on. Movies without one, like FM2 files, are
+    // meant to be played straight after power on, so PRG RAM goes back to
+    // how it powers on rather than holding the battery save. Either way
+    // the battery save is left alone from here on, as what the movie does
+    // to PRG RAM isn't the player's progress.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if !movie.matches_rom(self.cpu.bus.cart.hash, &self.cpu.bus.cart.md5) {
+            return Err(MovieError::WrongRom);
+        }
+        match movie.start_state {
+            Some(ref state) => try!(self.load_state(state)),
+            None => self.cpu.bus.cart.reset_prg_ram(),
+        }
+        self.cpu.bus.cart.set_battery_enabled(false);
+        self.movie = Some(MovieMode::Playing(movie));
+        self.movie_start = self.frame;
+        Ok(())
+    }
+
+    // Stops recording or playing, handing back the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|mode| {
+            match mode {
+                MovieMode::Recording(movie) | MovieMode::Playing(movie) => movie,
+            }
+        })
+    }
+
+    pub fn is_recording_movie(&self) -> bool {
+        match self.movie {
+            Some(MovieMode::Recording(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Whether a movie is playing and still has input left.
+    pub fn is_playing_movie(&self) -> bool {
+        match self.movie {
+            Some(MovieMode::Playing(ref movie)) => self.movie_frame() < movie.frames.len(),
+            _ => false,
+        }
+    }
+
+    fn movie_frame(&self) -> usize {
+        self.frame.saturating_sub(self.movie_start) as usize
+    }
+
+    // The buttons for the frame about to run, which is where a movie
+    // records or plays back.
+    fn next_buttons(&mut self) -> u8 {
+        let index = self.movie_frame();
+        match self.movie {
+            Some(MovieMode::Recording(ref mut movie)) => {
+                movie.frames.truncate(index);
+                movie.frames.push(self.buttons);
+                self.buttons
+            }
+            Some(MovieMode::Playing(ref movie)) => {
+                movie.frames.get(index).cloned().unwrap_or(self.buttons)
+            }
+            None => self.buttons,
+        }
+    }
+}
+
+
+// Problems the console can carry on past go to stderr, so they don't end up
+// mixed into whatever the frontend prints.
+fn warn(args: fmt::Arguments) {
+    let _ = writeln!(io::stderr(), "{}", args);
+}
+
+// Prints the instruction at PC in the format of nestest's log. Memory is
+// peeked so tracing doesn't disturb the bus.
+fn cpu_debug(cpu: &cpu::CPU) {
+    let pc = cpu.program_counter;
+    let op = cpu.peek_u8(pc);
+    let instr = opcodes::INSTRUCTIONS[op as usize];
+    let lo = cpu.peek_u8(pc.wrapping_add(1));
+    let hi = cpu.peek_u8(pc.wrapping_add(2));
+    let peek_u16 = |addr: u16, next: u16| {
+        (cpu.peek_u8(next) as u16) << 8 | cpu.peek_u8(addr) as u16
+    };
+
+    let (operand, operand_bytes) = match instr.bytes {
+        3 => ((hi as u16) << 8 | lo as u16, format!("{:02X} {:02X}", lo, hi)),
+        2 => (lo as u16, format!("{:02X}   ", lo)),
+        _ => (0, format!("     ")),
+    };
+
+    let value = |addr: u16| {
+        if addr < 0x800 {
+            format!(" = {:02X}", cpu.peek_u8(addr))
+        } else {
+            format!("")
+        }
+    };
+    let addrs = match instr.addr_mode {
+        AddressMode::Immediate => format!("#${:02X}", lo),
+        AddressMode::Absolute => format!("${:04X}{}", operand, value(operand)),
+        AddressMode::AbsoluteX => {
+            let addr = operand.wrapping_add(cpu.index_x as u16);
+            format!("${:04X},X @ {:04X}{}", operand, addr, value(addr))
+        }
+        AddressMode::AbsoluteY => {
+            let addr = operand.wrapping_add(cpu.index_y as u16);
+            format!("${:04X},Y @ {:04X}{}", operand, addr, value(addr))
+        }
+        AddressMode::XIndirect => {
+            let ptr = lo.wrapping_add(cpu.index_x);
+            let addr = peek_u16(ptr as u16, ptr.wrapping_add(1) as u16);
+            format!("(${:02X},X) @ {:02X} = {:04X}{}", lo, ptr, addr, value(addr))
+        }
+        AddressMode::IndirectY => {
+            let base = peek_u16(lo as u16, lo.wrapping_add(1) as u16);
+            let addr = base.wrapping_add(cpu.index_y as u16);
+            format!("(${:02X}),Y = {:04X} @ {:04X}{}", lo, base, addr, value(addr))
+        }
+        AddressMode::Zeropage => format!("${:02X}{}", lo, value(lo as u16)),
+        AddressMode::ZeropageX => {
+            let addr = lo.wrapping_add(cpu.index_x) as u16;
+            format!("${:02X},X @ {:02X}{}", lo, addr, value(addr))
+        }
+        AddressMode::ZeropageY => {
+            let addr = lo.wrapping_add(cpu.index_y) as u16;
+            format!("${:02X},Y @ {:02X}{}", lo, addr, value(addr))
+        }
+        AddressMode::Indirect => {
+            let addr = peek_u16(operand, (operand & 0xFF00) | (operand.wrapping_add(1) & 0xFF));
+            format!("(${:04X}) = {:04X}", operand, addr)
+        }
+        AddressMode::Relative => {
+            format!("${:04X}", pc.wrapping_add(2).wrapping_add(lo as i8 as u16))
+        }
+        AddressMode::Accumulator => format!("A"),
+        AddressMode::Implied => format!(""),
+    };
+    let tmp: u8 = cpu.status_reg.into();
+    let _ = write!(io::stderr(),
+                   "{:04X}  {:02X} {} {:>4} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} \
+                    SP:{:02X} CYC:{:>3} SL:{:}\r\n",
+                   pc,
+                   op,
+                   operand_bytes,
+                   instr.name,
+                   addrs,
+                   cpu.accumulator,
+                   cpu.index_x,
+                   cpu.index_y,
+                   tmp,
+                   cpu.stack_pointer,
+                   cpu.bus.ppu.cycles,
+                   cpu.bus.ppu.scanline);
+
+}
+
+
+impl Bus {
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.ram);
+        self.ppu.save_state(out);
+        self.apu.save_state(out);
+        self.joy.save_state(out);
+        self.cart.save_state(out);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        try!(input.bytes(&mut self.ram));
+        try!(self.ppu.load_state(input));
+        try!(self.apu.load_state(input));
+        try!(self.joy.load_state(input));
+        self.cart.load_state(input)
+    }
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "")
+    }
+}
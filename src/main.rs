@@ -1,40 +1,114 @@
 extern crate sdl2;
 extern crate time;
+extern crate oxidenes;
+
+mod cli;
+mod sdl_audio;
+mod sdl_scope;
 
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, LALTMOD, RALTMOD};
 use sdl2::event::Event;
 
-// use time;
-
 use std::env;
-use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod cart;
-mod mem_map;
-mod cpu;
-mod apu;
-mod ppu;
-mod joy;
-mod opcodes;
+use oxidenes::Nes;
+use oxidenes::movie::Movie;
+use oxidenes::rewind::Rewind;
+use oxidenes::joy;
+use oxidenes::apu::{self, FilterProfile};
 
-use opcodes::AddressMode;
+use cli::{fail, parse_arg, parse_arg_in, parse_channels, parse_filter, warn};
+use sdl_audio::AudioOut;
 
-use mem_map::*;
-// use cpu::RunCondition;
+// An NTSC frame is 29780.5 CPU cycles, a little quicker than 1/60s.
+const FRAME_NS: u64 = 16_639_267;
 
-const PPU_MULTIPLIER:isize = 3;
+// Save state slots 0 to 9.
+const STATE_SLOTS: u8 = 10;
 
-pub struct Bus {
-    ram: Box<[u8]>,
-    cart: cart::Cart,
-    apu: apu::APU,
-    ppu: ppu::PPU,
-    joy: joy::Joy,
-}
+// Longest audio queue --latency asks for, past this it's just lag.
+const MAX_LATENCY_MS: u32 = 1000;
+
+// Held to play backwards.
+const REWIND_KEY: Keycode = Keycode::Backspace;
 
 fn main() {
-    let rompath = env::args().nth(1).unwrap_or(String::from("smb.nes"));
+    let mut rompath = String::from("smb.nes");
+    let mut save_dir: Option<PathBuf> = None;
+    let mut sample_rate = 44100;
+    let mut latency_ms = 60;
+    let mut audio_enabled = true;
+    let mut filter_profile = FilterProfile::Hardware;
+    let mut record_path: Option<PathBuf> = None;
+    let mut record_channels = false;
+    let mut max_frames: Option<u32> = None;
+    let mut channel_mask = apu::ALL_CHANNELS;
+    let mut rewind_seconds = 10;
+    let mut rewind_interval = 1;
+    let mut movie_record_path: Option<PathBuf> = None;
+    let mut movie_play_path: Option<PathBuf> = None;
+    let mut sprite_limit = true;
+    let mut trace = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save-dir" => save_dir = args.next().map(PathBuf::from),
+            "--sample-rate" => {
+                sample_rate = parse_arg_in(&arg,
+                                           args.next(),
+                                           apu::MIN_SAMPLE_RATE,
+                                           apu::MAX_SAMPLE_RATE)
+            }
+            "--latency" => latency_ms = parse_arg_in(&arg, args.next(), 1, MAX_LATENCY_MS),
+            "--no-audio" => audio_enabled = false,
+            "--audio-filter" => filter_profile = parse_filter(args.next()),
+            "--record-audio" => record_path = args.next().map(PathBuf::from),
+            "--record-channels" => record_channels = true,
+            "--frames" => max_frames = Some(parse_arg(&arg, args.next())),
+            "--rewind" => rewind_seconds = parse_arg(&arg, args.next()),
+            "--rewind-interval" => rewind_interval = parse_arg(&arg, args.next()),
+            "--record-movie" => movie_record_path = args.next().map(PathBuf::from),
+            "--play-movie" => movie_play_path = args.next().map(PathBuf::from),
+            "--no-sprite-limit" => sprite_limit = false,
+            "--trace" => trace = true,
+            "--channels" => channel_mask = parse_channels(args.next()),
+            _ => rompath = arg,
+        }
+    }
+
+    let save_dir = save_dir.as_ref().map(|d| d.as_path());
+    // playing a movie back has to start from the same PRG RAM every time,
+    // and mustn't touch the player's own save
+    let battery = movie_play_path.is_none();
+    let mut nes = match Nes::with_options(&rompath, save_dir, battery) {
+        Ok(nes) => nes,
+        Err(e) => fail(format_args!("Couldn't load {}: {}", rompath, e)),
+    };
+    println!("{:#?}", nes.cpu.bus.cart);
+    nes.set_filter_profile(filter_profile);
+    nes.set_channel_mask(channel_mask);
+    nes.set_sprite_limit(sprite_limit);
+    nes.set_trace(trace);
+
+    if movie_play_path.is_some() && movie_record_path.is_some() {
+        fail(format_args!("--play-movie and --record-movie can't be used together"));
+    }
+    if let Some(ref path) = movie_play_path {
+        let result = Movie::load(path).and_then(|movie| nes.play_movie(movie));
+        if let Err(e) = result {
+            fail(format_args!("Couldn't play {}: {}", path.display(), e));
+        }
+    }
+    if movie_record_path.is_some() {
+        nes.start_movie_recording();
+    }
+    let movie_record_path = movie_record_path.as_ref().map(|p| p.as_path());
 
     let sdl = sdl2::init().unwrap();
     let video = sdl.video().unwrap();
@@ -50,173 +124,270 @@ fn main() {
                                                         240).unwrap();
     let mut events = sdl.event_pump().unwrap();
 
-
-    let cart = cart::Cart::new(&rompath);
-    println!("{:#?}", cart);
-    let chr_rom = cart::ChrRom::new(&rompath);
-    let apu = apu::APU::new();
-
-    let ppu = ppu::PPU::new(chr_rom);
-    let joy = joy::Joy::new();
-
-    let cpubus = Bus {
-        ram: vec![0; RAM_LEN as usize].into_boxed_slice(),
-        cart: cart,
-        apu: apu,
-        ppu: ppu,
-        joy: joy,
+    let mut audio = if audio_enabled {
+        match sdl.audio().and_then(|a| AudioOut::open(&a, sample_rate, latency_ms)) {
+            Ok(audio) => Some(audio),
+            Err(e) => {
+                warn(format_args!("Couldn't open the audio device, continuing without sound: {}",
+                                  e));
+                None
+            }
+        }
+    } else {
+        None
     };
-
-    let pc = cpubus.cart.read_cart_u16(RESET_VECTOR_LOC);
-    // println!("PC is {:#X}", pc);
-    let mut cpu = cpu::CPU::new(cpubus, pc as u16);
-    // println!("{:#?}", cpu);
+    if let Some(ref audio) = audio {
+        nes.set_sample_rate(audio.sample_rate());
+    } else {
+        nes.set_sample_rate(sample_rate);
+    }
+    if let Some(ref path) = record_path {
+        start_recording(&mut nes, path, record_channels);
+    }
 
     // TODO: re-add specific run conditions for debugging
-    let mut nmi:bool;
-    let mut framestart = time::precise_time_ns();
+    let mut next_frame = time::precise_time_ns();
+    let mut frame = 0;
+    let mut show_scope = false;
+    let mut state_slot = 0;
+    // --rewind 0 turns it off
+    let mut rewind = if rewind_seconds > 0 {
+        Some(Rewind::new(rewind_seconds, rewind_interval))
+    } else {
+        None
+    };
+    let mut rewinding = false;
+    let mut playing_movie = nes.is_playing_movie();
     'main: loop {
-
-        let (op, instr) = cpu.read_instruction();
-        if op == 0 {
-            break;
+        if rewinding {
+            // Going backwards loads the previous snapshot and runs a frame
+            // from it to have something to show, so each frame on screen is
+            // one interval further back. With no history left it stays put.
+            // That frame has been heard before, so it's kept out of the
+            // speakers and the recording.
+            let stepped_back = match rewind.as_mut().map(|r| r.step_back(&mut nes)) {
+                Some(Ok(stepped_back)) => stepped_back,
+                Some(Err(e)) => {
+                    warn(format_args!("Couldn't rewind: {}", e));
+                    false
+                }
+                None => false,
+            };
+            if stepped_back && !nes.run_frame_muted() {
+                break;
+            }
+        } else {
+            if !nes.run_frame() {
+                break;
+            }
+            if let Some(ref mut rewind) = rewind {
+                rewind.push(&nes);
+            }
+            frame += 1;
+            if max_frames.map_or(false, |max| frame >= max) {
+                break;
+            }
+            if playing_movie && !nes.is_playing_movie() {
+                println!("Movie finished at frame {}", nes.frame_count());
+                playing_movie = false;
+            }
         }
 
-        // TODO: Move this to a specific debug output
-        if false {
-            cpu_debug(&op, &instr, &cpu);
+        render_frame(nes.framebuffer(), &mut renderer, &mut texture);
+        if show_scope {
+            sdl_scope::draw(&mut renderer, &nes.take_scope(), nes.channel_mask());
         }
-
-        cpu.cycle += instr.ticks as isize * PPU_MULTIPLIER;
-        nmi = cpu.bus.ppu.tick(instr.ticks as isize * PPU_MULTIPLIER);
-        if cpu.bus.ppu.extra_cycle {
-            cpu.cycle += 1;
-            cpu.bus.ppu.extra_cycle = false;
+        renderer.present();
+
+        // nothing is played while rewinding, the queue running dry just
+        // holds the last sample
+        let samples = nes.take_samples();
+        if let Some(ref mut audio) = audio {
+            if !rewinding {
+                let rate = audio.queue(&samples);
+                nes.set_sample_rate(rate);
+            }
         }
 
-        if cpu.cycle >= 341 {
-            cpu.cycle %= 341;
+        // Frame limiter. Audio follows it through the rate control above.
+        next_frame += FRAME_NS;
+        let now = time::precise_time_ns();
+        if next_frame > now {
+            std::thread::sleep(Duration::new(0, (next_frame - now) as u32));
+        } else if now - next_frame > FRAME_NS {
+            // too far behind to catch up, carry on from here
+            next_frame = now;
+        }
 
-            if cpu.bus.ppu.scanline == 240 {
-                render_frame(&cpu.bus.ppu.screen, &mut renderer, &mut texture);
 
-                // Frame limiter.
-                let mut frametime = time::precise_time_ns() - framestart;
-                // println!("Frame took {}", frametime);
-                if frametime < 16_666_667 {
-                    frametime = 16_666_667 - frametime;
-                    std::thread::sleep(std::time::Duration::new(0, frametime as u32));
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    break 'main
                 }
-                framestart = time::precise_time_ns();
-
-
-                for event in events.poll_iter() {
-                    match event {
-                        Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                            break 'main
-                        }
-                        _ => ()
+                // F9 starts and stops recording the audio
+                Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                    if nes.is_recording() {
+                        stop_recording(&mut nes);
+                    } else {
+                        let path = match record_path {
+                            Some(ref path) => path.clone(),
+                            None => next_recording_path(&rompath),
+                        };
+                        start_recording(&mut nes, &path, record_channels);
+                    }
+                }
+                // F5 saves to the current slot, F8 loads it and F6/F7 pick
+                // the slot
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => {
+                    save_state(&nes, state_slot);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F8), repeat: false, .. } => {
+                    load_state(&mut nes, state_slot);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                    state_slot = (state_slot + STATE_SLOTS - 1) % STATE_SLOTS;
+                    println!("State slot {}", state_slot);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                    state_slot = (state_slot + 1) % STATE_SLOTS;
+                    println!("State slot {}", state_slot);
+                }
+                // F10 shows the channels' waveforms
+                Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => {
+                    show_scope = !show_scope;
+                    nes.set_scope(show_scope);
+                }
+                // 1 to 6 mute a channel, with alt solo it, 0 plays them all
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => {
+                    if let Some(channel) = key_to_channel(key) {
+                        let bit = 1 << channel;
+                        let mask = nes.channel_mask();
+                        let mask = if keymod.intersects(LALTMOD | RALTMOD) {
+                            if mask == bit { apu::ALL_CHANNELS } else { bit }
+                        } else {
+                            mask ^ bit
+                        };
+                        nes.set_channel_mask(mask);
+                    } else if key == Keycode::Num0 {
+                        nes.set_channel_mask(apu::ALL_CHANNELS);
                     }
                 }
+                _ => ()
+            }
+        }
+
+        let keys: Vec<Keycode> = events.
+                        keyboard_state().
+                        pressed_scancodes().
+                        filter_map(Keycode::from_scancode).
+                        collect();
+        let buttons = keys.iter().fold(0, |buttons, &key| buttons | key_to_button(key));
+
+        nes.set_buttons(buttons);
+        rewinding = rewind.is_some() && keys.contains(&REWIND_KEY);
+    }
+
+    if nes.cpu.jammed() {
+        warn(format_args!("CPU jammed at {:#06X}", nes.cpu.program_counter));
+    }
 
-                let keys: Vec<Keycode> = events.
-                                keyboard_state().
-                                pressed_scancodes().
-                                filter_map(Keycode::from_scancode).
-                                collect();
+    shut_down(&mut nes, movie_record_path);
+}
 
-                cpu.bus.joy.set_keys(keys);
+// movie_path is where the movie being recorded goes, if one is.
+fn shut_down(nes: &mut Nes, movie_path: Option<&Path>) {
+    if nes.is_recording() {
+        stop_recording(nes);
+    }
+    if let Some(path) = movie_path {
+        if let Some(movie) = nes.stop_movie() {
+            match movie.save(path) {
+                Ok(()) => println!("Saved {} frames of input to {}", movie.frames.len(), path.display()),
+                Err(e) => warn(format_args!("Couldn't write {}: {}", path.display(), e)),
             }
         }
+    }
+    if let Err(e) = nes.save_battery() {
+        warn(format_args!("Couldn't write the battery save: {}", e));
+    }
+}
 
-        cpu.execute_op(&op, &instr);
+fn save_state(nes: &Nes, slot: u8) {
+    let path = nes.state_path(slot);
+    let state = nes.save_state();
+    match File::create(&path).and_then(|mut file| file.write_all(&state)) {
+        Ok(()) => println!("Saved state {} to {}", slot, path.display()),
+        Err(e) => warn(format_args!("Couldn't write {}: {}", path.display(), e)),
+    }
+}
 
-        if nmi {
-            //    println!("NMI");
-            cpu.nmi();
-            cpu.bus.ppu.tick(7 * PPU_MULTIPLIER);
-        }
+fn load_state(nes: &mut Nes, slot: u8) {
+    let path = nes.state_path(slot);
+    let mut state = Vec::new();
+    if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_end(&mut state)) {
+        warn(format_args!("Couldn't read {}: {}", path.display(), e));
+        return;
+    }
+    match nes.load_state(&state) {
+        Ok(()) => println!("Loaded state {}", slot),
+        Err(e) => warn(format_args!("Couldn't load {}: {}", path.display(), e)),
     }
 }
 
+fn start_recording(nes: &mut Nes, path: &Path, channels: bool) {
+    match nes.start_recording(path, channels) {
+        Ok(()) => println!("Recording audio to {}", path.display()),
+        Err(e) => warn(format_args!("Couldn't start recording to {}: {}", path.display(), e)),
+    }
+}
 
-fn cpu_debug (op: &u8, instr: &opcodes::Instruction, cpu: &cpu::CPU) {
-    let pc = cpu.program_counter - instr.bytes as u16;
-    let operand = if instr.bytes != 1 {
-        // opr = instr.operand.unwrap();
-        if instr.operand > 0xFF {
-            let opr1 = instr.operand & 0xFF;
-            let opr2 = instr.operand >> 8;
-            format!("{:02X} {:02X}", opr1 as u8, opr2 as u8)
-        } else {
-            format!("{:02X}   ", instr.operand as u8)
-        }
-    } else {
-        format!("     ")
-    };
+fn stop_recording(nes: &mut Nes) {
+    match nes.stop_recording() {
+        Ok(()) => println!("Stopped recording audio"),
+        Err(e) => warn(format_args!("Couldn't finish the audio recording: {}", e)),
+    }
+}
 
-    let addrs = if instr.dest_addr != None {
-        let addr = instr.dest_addr.unwrap();
-        let value = if addr < 0x800 {
-            format!(" = {:02X}", cpu.bus.ram[addr as usize])
-        } else {
-            format!("")
-        };
-        match instr.addr_mode {
-            AddressMode::Immediate => format!("#${:02X}", instr.operand as u8),
-            AddressMode::Absolute => format!("${:04X}{}", addr, value),
-            AddressMode::AbsoluteX => format!("${:04X},X @ {:04X}{}", instr.operand,
-                                                                      addr,
-                                                                      value),
-            AddressMode::AbsoluteY => format!("${:04X},Y @ {:04X}{}", instr.operand,
-                                                                      addr,
-                                                                      value),
-            AddressMode::XIndirect => {
-                format!("(${:02X},X) @ {:02X} = {:04X}{}", instr.operand,
-                                                            instr.operand.wrapping_add(cpu.index_x as u16),
-                                                            addr,
-                                                            value)
-            }
-            AddressMode::IndirectY => {
-                format!("(${:02X}),Y = {:04X} @ {:04X}{}", instr.operand,
-                                                            addr.wrapping_sub(cpu.index_y as u16),
-                                                            addr,
-                                                            value)
-            }
-            AddressMode::Zeropage => format!("${:02X}{}", addr as u8, value),
-            AddressMode::ZeropageX => format!("${:02X},X @ {:02X}{}", instr.operand,
-                                                                        addr,
-                                                                        value),
-            AddressMode::ZeropageY => format!("${:02X},Y @ {:02X}{}", instr.operand,
-                                                                        addr,
-                                                                        value),
-            AddressMode::Indirect => format!("(${:04X}) = {:04X}", instr.operand, addr),
-            AddressMode::Relative => format!("${:04X}", (cpu.program_counter as i16 +
-                                                        instr.operand as i8 as i16) as u16),
-            _ => format!(""),
+// The first of game-1.wav, game-2.wav and so on next to the ROM that
+// doesn't exist yet.
+fn next_recording_path(rompath: &str) -> PathBuf {
+    let rompath = Path::new(rompath);
+    let stem = rompath.file_stem().and_then(|s| s.to_str()).unwrap_or("oxidenes");
+    let mut n = 1;
+    loop {
+        let path = rompath.with_file_name(format!("{}-{}.wav", stem, n));
+        if !path.exists() {
+            return path;
         }
-    } else if instr.addr_mode == AddressMode::Accumulator {
-        format!("A")
-    } else {
-        format!("")
-    };
-    let tmp: u8 = cpu.status_reg.into();
-    print!("{:04X}  {:02X} {} {:>4} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} \
-              SP:{:02X} CYC:{:>3} SL:{:}\r\n",
-             pc,
-             op,
-             operand,
-             instr.name,
-             addrs,
-             cpu.accumulator,
-             cpu.index_x,
-             cpu.index_y,
-             tmp,
-             cpu.stack_pointer,
-             cpu.cycle % 341,
-             cpu.bus.ppu.scanline,
-             ); //, self.status_reg);
+        n += 1;
+    }
+}
+
+fn key_to_channel(key: Keycode) -> Option<usize> {
+    match key {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        _ => None,
+    }
+}
 
+fn key_to_button(key: Keycode) -> u8 {
+    match key {
+        Keycode::LCtrl => joy::BUTTON_A,
+        Keycode::LShift => joy::BUTTON_B,
+        Keycode::Space => joy::BUTTON_SELECT,
+        Keycode::Return => joy::BUTTON_START,
+        Keycode::Up => joy::BUTTON_UP,
+        Keycode::Down => joy::BUTTON_DOWN,
+        Keycode::Left => joy::BUTTON_LEFT,
+        Keycode::Right => joy::BUTTON_RIGHT,
+        _ => 0,
+    }
 }
 
 
@@ -248,19 +419,5 @@ fn render_frame(screen: &[[u32; 256]; 240],
 
     renderer.clear();
     renderer.copy(&texture, None, None);
-    renderer.present();
-
-}
-
-
-
 
-
-
-
-
-impl fmt::Debug for Bus {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "")
-    }
 }
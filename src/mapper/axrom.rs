@@ -0,0 +1,76 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_LEN: usize = 0x8000;
+
+// Mapper 7: the whole of $8000-$FFFF switches as one 32KB bank, and the same
+// register picks which nametable page the single-screen mirroring shows.
+// Writes are ---M-PPP.
+pub struct Axrom {
+    prg_rom: Box<[u8]>,
+    chr: Chr,
+
+    prg_bank: u8,
+    upper_nametable: bool,
+}
+
+impl Axrom {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr) -> Axrom {
+        Axrom {
+            prg_rom: prg_rom,
+            chr: chr,
+
+            prg_bank: 0,
+            upper_nametable: false,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if addr < PRG_ROM_START {
+            return 0;
+        }
+        let banks = self.prg_rom.len() / PRG_BANK_LEN;
+        let bank = if banks == 0 { 0 } else { self.prg_bank as usize % banks };
+        let offset = (addr - PRG_ROM_START) as usize;
+        self.prg_rom[(bank * PRG_BANK_LEN + offset) % self.prg_rom.len()]
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.prg_bank = value & 0b111;
+            self.upper_nametable = value & 0x10 != 0;
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read_u8(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.chr.write_u8(addr, value)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.upper_nametable {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.prg_bank);
+        out.bool(self.upper_nametable);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = try!(input.u8());
+        self.upper_nametable = try!(input.bool());
+        self.chr.load_state(input)
+    }
+}
@@ -0,0 +1,77 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const CHR_BANK_LEN: usize = 0x1000;
+
+// Mapper 13: 32KB of PRG ROM and 16KB of CHR RAM. $0000-$0FFF always shows
+// the first 4KB of CHR RAM, writes to $8000-$FFFF pick the 4KB at $1000.
+pub struct Cprom {
+    prg_rom: Box<[u8]>,
+    chr: Chr,
+    mirroring: Mirroring,
+
+    chr_bank: u8,
+}
+
+impl Cprom {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, mirroring: Mirroring) -> Cprom {
+        Cprom {
+            prg_rom: prg_rom,
+            chr: chr,
+            mirroring: mirroring,
+
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        if addr < 0x1000 {
+            0
+        } else {
+            self.chr_bank as usize
+        }
+    }
+}
+
+impl Mapper for Cprom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if addr >= PRG_ROM_START {
+            let offset = (addr - PRG_ROM_START) as usize % self.prg_rom.len();
+            self.prg_rom[offset]
+        } else {
+            0
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.chr_bank = value & 0b11;
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let bank = self.chr_bank_at(addr);
+        self.chr.read_banked_u8(bank, CHR_BANK_LEN, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_banked_u8(bank, CHR_BANK_LEN, addr, value)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.chr_bank);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.chr_bank = try!(input.u8());
+        self.chr.load_state(input)
+    }
+}
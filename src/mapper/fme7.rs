@@ -0,0 +1,455 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const PRG_RAM_LEN: usize = 0x2000;
+const PRG_BANK_LEN: usize = 0x2000;
+const CHR_BANK_LEN: usize = 0x400;
+
+// Mapper 69: Sunsoft's FME-7, 8KB PRG banks, 1KB CHR banks and a 16 bit
+// CPU cycle IRQ counter. The Sunsoft 5B is the same chip with a YM2149
+// style sound generator added, so both are handled here.
+// http://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+pub struct Fme7 {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Chr,
+
+    // $8000 picks which of these $A000 writes go to
+    command: u8,
+    chr_banks: [u8; 8],
+    // ERBBBBBB, RAM enable, RAM or ROM and the ROM bank at $6000
+    prg_bank_6000: u8,
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+
+    irq_enable: bool,
+    irq_counter_enable: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Sunsoft5b,
+}
+
+impl Fme7 {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr) -> Fme7 {
+        Fme7 {
+            prg_rom: prg_rom,
+            prg_ram: vec![0; PRG_RAM_LEN].into_boxed_slice(),
+            chr: chr,
+
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: Mirroring::Vertical,
+
+            irq_enable: false,
+            irq_counter_enable: false,
+            irq_counter: 0,
+            irq_pending: false,
+
+            audio: Sunsoft5b::new(),
+        }
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0...7 => self.chr_banks[self.command as usize] = value,
+            8 => self.prg_bank_6000 = value,
+            9...0xB => self.prg_banks[self.command as usize - 9] = value & 0x3F,
+            0xC => {
+                self.mirroring = match value & 0b11 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xD => {
+                self.irq_enable = value & 1 != 0;
+                self.irq_counter_enable = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = self.irq_counter & 0xFF00 | value as u16,
+            _ => self.irq_counter = self.irq_counter & 0x00FF | (value as u16) << 8,
+        }
+    }
+
+    fn prg_rom_u8(&self, bank: u8, addr: u16) -> u8 {
+        let count = self.prg_rom.len() / PRG_BANK_LEN;
+        let offset = addr as usize & (PRG_BANK_LEN - 1);
+        self.prg_rom[(bank as usize % count) * PRG_BANK_LEN + offset]
+    }
+
+    fn prg_ram_mapped(&self) -> bool {
+        self.prg_bank_6000 & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank_6000 & 0xC0 == 0xC0
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if !self.prg_ram_mapped() {
+                    self.prg_rom_u8(self.prg_bank_6000 & 0x3F, addr)
+                } else if self.prg_ram_enabled() {
+                    self.prg_ram[(addr - SRAM_START) as usize]
+                } else {
+                    0
+                }
+            }
+            0x8000...0xDFFF => {
+                let bank = self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_LEN];
+                self.prg_rom_u8(bank, addr)
+            }
+            0xE000...PRG_ROM_END => {
+                let last = (self.prg_rom.len() / PRG_BANK_LEN - 1) as u8;
+                self.prg_rom_u8(last, addr)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enabled() {
+                    self.prg_ram[(addr - SRAM_START) as usize] = value;
+                }
+            }
+            0x8000...0x9FFF => self.command = value & 0x0F,
+            0xA000...0xBFFF => self.write_parameter(value),
+            0xC000...0xDFFF => self.audio.select(value),
+            0xE000...PRG_ROM_END => self.audio.write(value),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr.read_banked_u8(bank, CHR_BANK_LEN, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr.write_banked_u8(bank, CHR_BANK_LEN, addr, value)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // The counter goes down every CPU cycle and fires as it wraps to $FFFF.
+    fn tick(&mut self) {
+        if self.irq_counter_enable {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enable {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.command);
+        for &bank in self.chr_banks.iter() {
+            out.u8(bank);
+        }
+        out.u8(self.prg_bank_6000);
+        for &bank in self.prg_banks.iter() {
+            out.u8(bank);
+        }
+        self.mirroring.save_state(out);
+        out.bool(self.irq_enable);
+        out.bool(self.irq_counter_enable);
+        out.u16(self.irq_counter);
+        out.bool(self.irq_pending);
+        self.audio.save_state(out);
+        out.bytes(&self.prg_ram);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.command = try!(input.u8());
+        for bank in self.chr_banks.iter_mut() {
+            *bank = try!(input.u8());
+        }
+        self.prg_bank_6000 = try!(input.u8());
+        for bank in self.prg_banks.iter_mut() {
+            *bank = try!(input.u8());
+        }
+        self.mirroring = try!(Mirroring::load_state(input));
+        self.irq_enable = try!(input.bool());
+        self.irq_counter_enable = try!(input.bool());
+        self.irq_counter = try!(input.u16());
+        self.irq_pending = try!(input.bool());
+        try!(self.audio.load_state(input));
+        try!(input.bytes(&mut self.prg_ram));
+        self.chr.load_state(input)
+    }
+}
+
+// 1.5dB steps from silence up to full volume.
+const VOLUME_STEPS: usize = 32;
+
+// The 5B's three square channels with their shared noise generator and
+// envelope, written through an address register at $C000 and a data
+// register at $E000. Tone and noise count in units of 16 CPU cycles, the
+// envelope in units of 8 so its 32 steps take 256 of them.
+// http://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+struct Sunsoft5b {
+    register: u8,
+    divider: u8,
+
+    tone_periods: [u16; 3],
+    tone_timers: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    noise_period: u8,
+    noise_timer: u8,
+    // 17 bit LFSR
+    noise_shift: u32,
+
+    // register 7 - ..CBAcba, noise off for CBA and tone off for cba
+    mixer: u8,
+    // registers 8-A - ...EVVVV, envelope or fixed volume
+    volumes: [u8; 3],
+
+    envelope_period: u16,
+    envelope_timer: u16,
+    // register D - CAtH, continue, attack, alternate and hold
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    levels: [f32; VOLUME_STEPS],
+}
+
+impl Sunsoft5b {
+    fn new() -> Sunsoft5b {
+        let mut levels = [0.0; VOLUME_STEPS];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            let db = (VOLUME_STEPS - 1 - i) as f32 * -1.5;
+            *level = 10.0f32.powf(db / 20.0);
+        }
+
+        Sunsoft5b {
+            register: 0,
+            divider: 0,
+
+            tone_periods: [0; 3],
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+
+            noise_period: 0,
+            noise_timer: 0,
+            noise_shift: 1,
+
+            mixer: 0,
+            volumes: [0; 3],
+
+            envelope_period: 0,
+            envelope_timer: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: true,
+
+            levels: levels,
+        }
+    }
+
+    // $C000, values over $0F disable register writes
+    fn select(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    // $E000
+    fn write(&mut self, value: u8) {
+        match self.register {
+            0 | 2 | 4 => {
+                let ch = self.register as usize / 2;
+                self.tone_periods[ch] = self.tone_periods[ch] & 0x0F00 | value as u16;
+            }
+            1 | 3 | 5 => {
+                let ch = self.register as usize / 2;
+                self.tone_periods[ch] = self.tone_periods[ch] & 0x00FF | (value as u16 & 0x0F) << 8;
+            }
+            6 => self.noise_period = value & 0x1F,
+            7 => self.mixer = value,
+            8...0xA => self.volumes[self.register as usize - 8] = value & 0x1F,
+            0xB => self.envelope_period = self.envelope_period & 0xFF00 | value as u16,
+            0xC => self.envelope_period = self.envelope_period & 0x00FF | (value as u16) << 8,
+            0xD => {
+                self.envelope_shape = value & 0x0F;
+                self.envelope_attack = value & 0b0100 != 0;
+                self.envelope_step = 0;
+                self.envelope_holding = false;
+                self.envelope_timer = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.divider = (self.divider + 1) % 16;
+        if self.divider % 8 == 0 {
+            self.clock_envelope();
+        }
+        if self.divider != 0 {
+            return;
+        }
+
+        for ch in 0..3 {
+            self.tone_timers[ch] += 1;
+            if self.tone_timers[ch] >= self.tone_periods[ch] {
+                self.tone_timers[ch] = 0;
+                self.tone_outputs[ch] = !self.tone_outputs[ch];
+            }
+        }
+
+        // the noise runs at half the rate of a tone with the same period
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period * 2 {
+            self.noise_timer = 0;
+            let feedback = (self.noise_shift ^ self.noise_shift >> 3) & 1;
+            self.noise_shift = self.noise_shift >> 1 | feedback << 16;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope_timer += 1;
+        if self.envelope_timer < self.envelope_period {
+            return;
+        }
+        self.envelope_timer = 0;
+
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < VOLUME_STEPS as u8 {
+            return;
+        }
+
+        let shape = self.envelope_shape;
+        if shape & 0b1000 == 0 {
+            // no continue, drop to silence and stay there
+            self.envelope_attack = false;
+            self.envelope_step = VOLUME_STEPS as u8 - 1;
+            self.envelope_holding = true;
+        } else {
+            if shape & 0b0010 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            if shape & 0b0001 != 0 {
+                self.envelope_step = VOLUME_STEPS as u8 - 1;
+                self.envelope_holding = true;
+            } else {
+                self.envelope_step = 0;
+            }
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.register);
+        out.u8(self.divider);
+        for ch in 0..3 {
+            out.u16(self.tone_periods[ch]);
+            out.u16(self.tone_timers[ch]);
+            out.bool(self.tone_outputs[ch]);
+            out.u8(self.volumes[ch]);
+        }
+        out.u8(self.noise_period);
+        out.u8(self.noise_timer);
+        out.u32(self.noise_shift);
+        out.u8(self.mixer);
+        out.u16(self.envelope_period);
+        out.u16(self.envelope_timer);
+        out.u8(self.envelope_shape);
+        out.u8(self.envelope_step);
+        out.bool(self.envelope_attack);
+        out.bool(self.envelope_holding);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.register = try!(input.u8());
+        self.divider = try!(input.u8());
+        for ch in 0..3 {
+            self.tone_periods[ch] = try!(input.u16());
+            self.tone_timers[ch] = try!(input.u16());
+            self.tone_outputs[ch] = try!(input.bool());
+            self.volumes[ch] = try!(input.u8());
+        }
+        self.noise_period = try!(input.u8());
+        self.noise_timer = try!(input.u8());
+        self.noise_shift = try!(input.u32());
+        self.mixer = try!(input.u8());
+        self.envelope_period = try!(input.u16());
+        self.envelope_timer = try!(input.u16());
+        self.envelope_shape = try!(input.u8());
+        self.envelope_step = try!(input.u8());
+        if self.envelope_step as usize >= VOLUME_STEPS {
+            return Err(StateError::Corrupt);
+        }
+        self.envelope_attack = try!(input.bool());
+        self.envelope_holding = try!(input.bool());
+        Ok(())
+    }
+
+    fn envelope_level(&self) -> usize {
+        if self.envelope_attack {
+            self.envelope_step as usize
+        } else {
+            VOLUME_STEPS - 1 - self.envelope_step as usize
+        }
+    }
+
+    // At full volume a 5B square comes out about twice as loud as an APU
+    // pulse.
+    fn output(&self) -> f32 {
+        let noise = self.noise_shift & 1 != 0;
+        let mut sum = 0.0;
+        for ch in 0..3 {
+            let tone_off = self.mixer & (1 << ch) != 0;
+            let noise_off = self.mixer & (8 << ch) != 0;
+            if !((tone_off || self.tone_outputs[ch]) && (noise_off || noise)) {
+                continue;
+            }
+
+            let volume = self.volumes[ch];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume as usize & 0x0F) * 2 + 1
+            };
+            sum += self.levels[level];
+        }
+        sum * 2.0
+    }
+}
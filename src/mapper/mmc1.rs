@@ -0,0 +1,388 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const PRG_RAM_BANK_LEN: usize = 0x2000;
+// last_write before there's been one, far enough from any cycle that the
+// first write is never taken for the second of a pair
+const NEVER_WRITTEN: usize = ::std::usize::MAX - 1;
+
+// Mapper 1: registers are loaded one bit at a time through a serial port
+// at $8000-$FFFF. The fifth write commits the value to the register picked
+// by bits 13 and 14 of that write's address.
+// http://wiki.nesdev.com/w/index.php/MMC1
+pub struct Mmc1 {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Chr,
+
+    shift_reg: u8,
+    shift_count: u8,
+
+    // $8000 - CPPMM
+    control: u8,
+    // $A000 / $C000
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    // $E000 - RPPPP
+    prg_bank: u8,
+
+    // CPU cycle count, to spot writes on back to back cycles
+    cycle: usize,
+    last_write: usize,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, prg_ram: Box<[u8]>) -> Mmc1 {
+        Mmc1 {
+            prg_rom: prg_rom,
+            prg_ram: prg_ram,
+            chr: chr,
+
+            shift_reg: 0,
+            shift_count: 0,
+
+            // powers up with the last bank fixed at $C000
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+
+            cycle: 0,
+            last_write: NEVER_WRITTEN,
+        }
+    }
+
+    fn write_serial(&mut self, addr: u16, value: u8) {
+        // Only the first of two writes on consecutive cycles is seen, which
+        // is what happens to the double write of INC and friends.
+        let consecutive = self.cycle == self.last_write.wrapping_add(1);
+        self.last_write = self.cycle;
+        if consecutive {
+            return;
+        }
+
+        if value & 0x80 != 0 {
+            self.shift_reg = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift_reg |= (value & 1) << self.shift_count;
+        self.shift_count += 1;
+
+        if self.shift_count == 5 {
+            let data = self.shift_reg;
+            match addr {
+                0x8000...0x9FFF => self.control = data,
+                0xA000...0xBFFF => self.chr_bank_0 = data,
+                0xC000...0xDFFF => self.chr_bank_1 = data,
+                _ => self.prg_bank = data,
+            }
+            self.shift_reg = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    // Where addr lands in PRG RAM. SOROM's 16KB and SXROM's 32KB are
+    // banked 8KB at a time by bit 3 or bits 2-3 of the CHR register,
+    // smaller chips are mirrored.
+    fn prg_ram_pos(&self, addr: u16) -> usize {
+        let bank = match self.prg_ram.len() {
+            0x4000 => (self.chr_bank_0 as usize >> 3) & 1,
+            0x8000 => (self.chr_bank_0 as usize >> 2) & 3,
+            _ => 0,
+        };
+        (bank * PRG_RAM_BANK_LEN + (addr - SRAM_START) as usize) % self.prg_ram.len()
+    }
+
+    // 16KB bank mapped at addr, in units of PRG_ROM_LOWER_LEN
+    fn prg_bank_at(&self, addr: u16) -> usize {
+        // SUROM and friends use bit 4 of the CHR register to pick which
+        // 256KB half of the PRG ROM the banks below refer to.
+        let outer = if self.prg_rom.len() > 0x40000 {
+            self.chr_bank_0 as usize & 0x10
+        } else {
+            0
+        };
+        let bank = self.prg_bank as usize & 0x0F;
+        let last = (self.prg_rom.len() / PRG_ROM_LOWER_LEN as usize - 1) & 0x0F;
+        let upper = addr >= PRG_ROM_UPPER_START;
+
+        let selected = match (self.control >> 2) & 0b11 {
+            // 32KB at $8000, low bit of the bank number ignored
+            0 | 1 => (bank & !1) | if upper { 1 } else { 0 },
+            // first bank fixed at $8000, switchable at $C000
+            2 => if upper { bank } else { 0 },
+            // switchable at $8000, last bank fixed at $C000
+            _ => if upper { last } else { bank },
+        };
+        outer | selected
+    }
+
+    // 4KB bank mapped at addr
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        if self.control & 0x10 == 0 {
+            // 8KB mode, low bit ignored
+            (self.chr_bank_0 as usize & !1) | (addr as usize >> 12)
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enabled() {
+                    self.prg_ram[self.prg_ram_pos(addr)]
+                } else {
+                    0
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => {
+                let bank = self.prg_bank_at(addr);
+                let offset = (addr as usize) & (PRG_ROM_LOWER_LEN as usize - 1);
+                let pos = bank * PRG_ROM_LOWER_LEN as usize + offset;
+                self.prg_rom[pos % self.prg_rom.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enabled() {
+                    let pos = self.prg_ram_pos(addr);
+                    self.prg_ram[pos] = value;
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => self.write_serial(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let bank = self.chr_bank_at(addr);
+        self.chr.read_banked_u8(bank, 0x1000, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_banked_u8(bank, 0x1000, addr, value)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn tick(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.shift_reg);
+        out.u8(self.shift_count);
+        out.u8(self.control);
+        out.u8(self.chr_bank_0);
+        out.u8(self.chr_bank_1);
+        out.u8(self.prg_bank);
+        out.u64(self.cycle as u64);
+        out.u64(self.last_write as u64);
+        out.bytes(&self.prg_ram);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.shift_reg = try!(input.u8());
+        self.shift_count = try!(input.u8());
+        self.control = try!(input.u8());
+        self.chr_bank_0 = try!(input.u8());
+        self.chr_bank_1 = try!(input.u8());
+        self.prg_bank = try!(input.u8());
+        self.cycle = try!(input.u64()) as usize;
+        self.last_write = try!(input.u64()) as usize;
+        try!(input.bytes(&mut self.prg_ram));
+        self.chr.load_state(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BANKS: usize = 8;
+
+    // every 16KB PRG bank and 4KB CHR bank filled with its own number
+    fn mmc1() -> Mmc1 {
+        let prg: Vec<u8> = (0..BANKS * 0x4000).map(|i| (i / 0x4000) as u8).collect();
+        let chr: Vec<u8> = (0..16 * 0x1000).map(|i| (i / 0x1000) as u8).collect();
+        Mmc1::new(prg.into_boxed_slice(),
+                  Chr::rom(chr.into_boxed_slice()),
+                  vec![0; 0x2000].into_boxed_slice())
+    }
+
+    fn write(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        mmc1.cpu_write(addr, value);
+        mmc1.tick();
+        mmc1.tick();
+    }
+
+    // the five serial writes for one register, low bit first
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            write(mmc1, addr, value >> i & 1);
+        }
+    }
+
+    fn prg_banks(mmc1: &Mmc1) -> (u8, u8) {
+        (mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000))
+    }
+
+    #[test]
+    fn powers_up_with_the_last_bank_fixed() {
+        let mmc1 = mmc1();
+        assert_eq!(prg_banks(&mmc1), (0, BANKS as u8 - 1));
+    }
+
+    #[test]
+    fn register_loads_on_the_fifth_write() {
+        let mut mmc1 = mmc1();
+        for i in 0..4 {
+            write(&mut mmc1, 0xE000, 0b00101 >> i & 1);
+            assert_eq!(prg_banks(&mmc1), (0, 7));
+        }
+        write(&mut mmc1, 0xE000, 0);
+        assert_eq!(prg_banks(&mmc1), (5, 7));
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0xE000, 3);
+        assert_eq!(prg_banks(&mmc1), (3, 7));
+
+        // first bank fixed at $8000
+        write_register(&mut mmc1, 0x8000, 0x08);
+        assert_eq!(prg_banks(&mmc1), (0, 3));
+
+        // 32KB, the low bit of the bank ignored
+        write_register(&mut mmc1, 0x8000, 0x00);
+        assert_eq!(prg_banks(&mmc1), (2, 3));
+    }
+
+    #[test]
+    fn reset_bit_clears_the_shift_register() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x00);
+        write(&mut mmc1, 0xE000, 1);
+        write(&mut mmc1, 0xE000, 1);
+        write(&mut mmc1, 0xE000, 0x80);
+        assert_eq!(mmc1.control & 0x0C, 0x0C);
+
+        write_register(&mut mmc1, 0xE000, 4);
+        assert_eq!(prg_banks(&mmc1), (4, 7));
+    }
+
+    #[test]
+    fn back_to_back_writes_only_count_once() {
+        let mut mmc1 = mmc1();
+        for i in 0..5 {
+            // like the dummy write of a read-modify-write instruction, the
+            // second write has the opposite bit and shouldn't be seen
+            let bit = 0b00101 >> i & 1;
+            mmc1.cpu_write(0xE000, bit);
+            mmc1.tick();
+            mmc1.cpu_write(0xE000, bit ^ 1);
+            mmc1.tick();
+            mmc1.tick();
+        }
+        assert_eq!(prg_banks(&mmc1), (5, 7));
+        assert_eq!(mmc1.shift_count, 0);
+    }
+
+    #[test]
+    fn chr_modes() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0xA000, 5);
+        write_register(&mut mmc1, 0xC000, 2);
+        // 8KB, the low bit of the first register ignored
+        assert_eq!((mmc1.ppu_read(0x0000), mmc1.ppu_read(0x1000)), (4, 5));
+
+        write_register(&mut mmc1, 0x8000, 0x1C);
+        assert_eq!((mmc1.ppu_read(0x0000), mmc1.ppu_read(0x1000)), (5, 2));
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut mmc1 = mmc1();
+        let modes = [Mirroring::SingleScreenLower,
+                     Mirroring::SingleScreenUpper,
+                     Mirroring::Vertical,
+                     Mirroring::Horizontal];
+        for (value, &mode) in modes.iter().enumerate() {
+            write_register(&mut mmc1, 0x8000, 0x0C | value as u8);
+            assert_eq!(mmc1.mirroring(), mode);
+        }
+    }
+
+    #[test]
+    fn prg_ram_disable() {
+        let mut mmc1 = mmc1();
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), 0x42);
+
+        write_register(&mut mmc1, 0xE000, 0x10);
+        mmc1.cpu_write(0x6000, 0x24);
+        assert_eq!(mmc1.cpu_read(0x6000), 0);
+
+        write_register(&mut mmc1, 0xE000, 0);
+        assert_eq!(mmc1.cpu_read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn sxrom_banks_32kb_of_prg_ram_with_the_chr_register() {
+        let mut mmc1 = mmc1();
+        mmc1.prg_ram = vec![0; 0x8000].into_boxed_slice();
+        for bank in 0..4 {
+            write_register(&mut mmc1, 0xA000, bank << 2);
+            mmc1.cpu_write(0x6000, bank + 1);
+        }
+        for bank in 0..4 {
+            write_register(&mut mmc1, 0xA000, bank << 2);
+            assert_eq!(mmc1.cpu_read(0x6000), bank + 1);
+            assert_eq!(mmc1.prg_ram[bank as usize * 0x2000], bank + 1);
+        }
+    }
+
+    #[test]
+    fn first_write_after_power_on_is_seen() {
+        let mut mmc1 = mmc1();
+        mmc1.tick();
+        write_register(&mut mmc1, 0xE000, 3);
+        assert_eq!(prg_banks(&mmc1), (3, 7));
+    }
+}
@@ -0,0 +1,250 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_LEN: usize = 0x2000;
+const CHR_BANK_LEN: usize = 0x400;
+
+// Mapper 4: 8KB PRG banks, 1KB/2KB CHR banks and a scanline counter that is
+// clocked by rising edges on PPU A12.
+// http://wiki.nesdev.com/w/index.php/MMC3
+pub struct Mmc3 {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Chr,
+
+    four_screen: bool,
+    mirroring: Mirroring,
+
+    // $8000 - CP...RRR
+    bank_select: u8,
+    // R0-R7, loaded through $8001
+    banks: [u8; 8],
+
+    // $A001
+    prg_ram_enable: bool,
+    prg_ram_write_protect: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enable: bool,
+    irq_pending: bool,
+
+    last_a12: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, prg_ram: Box<[u8]>, mirroring: Mirroring) -> Mmc3 {
+        Mmc3 {
+            prg_rom: prg_rom,
+            prg_ram: prg_ram,
+            chr: chr,
+
+            four_screen: mirroring == Mirroring::FourScreen,
+            mirroring: mirroring,
+
+            bank_select: 0,
+            banks: [0; 8],
+
+            prg_ram_enable: true,
+            prg_ram_write_protect: false,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enable: false,
+            irq_pending: false,
+
+            last_a12: false,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000...0x9FFF, true) => self.bank_select = value,
+            (0x8000...0x9FFF, false) => {
+                let reg = self.bank_select & 0b111;
+                self.banks[reg as usize] = value;
+            }
+            (0xA000...0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if value & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xA000...0xBFFF, false) => {
+                self.prg_ram_enable = value & 0x80 != 0;
+                self.prg_ram_write_protect = value & 0x40 != 0;
+            }
+            (0xC000...0xDFFF, true) => self.irq_latch = value,
+            (0xC000...0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enable = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enable = true,
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enable {
+            self.irq_pending = true;
+        }
+    }
+
+    fn prg_bank_at(&self, addr: u16) -> usize {
+        let count = self.prg_rom.len() / PRG_BANK_LEN;
+        let second_last = count - 2;
+        let swapped = self.bank_select & 0x40 != 0;
+
+        let bank = match addr {
+            0x8000...0x9FFF => {
+                if swapped { second_last } else { self.banks[6] as usize }
+            }
+            0xA000...0xBFFF => self.banks[7] as usize,
+            0xC000...0xDFFF => {
+                if swapped { self.banks[6] as usize } else { second_last }
+            }
+            _ => count - 1,
+        };
+        bank % count
+    }
+
+    // 1KB bank mapped at addr
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        // with bit 7 set the 2KB banks move to $1000 and the 1KB ones to $0000
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+
+        match addr {
+            0x0000...0x07FF => (self.banks[0] as usize & !1) | (addr as usize >> 10 & 1),
+            0x0800...0x0FFF => (self.banks[1] as usize & !1) | (addr as usize >> 10 & 1),
+            0x1000...0x13FF => self.banks[2] as usize,
+            0x1400...0x17FF => self.banks[3] as usize,
+            0x1800...0x1BFF => self.banks[4] as usize,
+            _ => self.banks[5] as usize,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enable {
+                    self.prg_ram[(addr - SRAM_START) as usize % self.prg_ram.len()]
+                } else {
+                    0
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => {
+                let bank = self.prg_bank_at(addr);
+                let offset = addr as usize & (PRG_BANK_LEN - 1);
+                self.prg_rom[bank * PRG_BANK_LEN + offset]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enable && !self.prg_ram_write_protect {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr - SRAM_START) as usize % len] = value;
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let bank = self.chr_bank_at(addr);
+        self.chr.read_banked_u8(bank, CHR_BANK_LEN, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_banked_u8(bank, CHR_BANK_LEN, addr, value)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn notify_ppu_addr(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.last_a12 {
+            self.clock_irq_counter();
+        }
+        self.last_a12 = a12;
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        self.mirroring.save_state(out);
+        out.u8(self.bank_select);
+        for &bank in self.banks.iter() {
+            out.u8(bank);
+        }
+        out.bool(self.prg_ram_enable);
+        out.bool(self.prg_ram_write_protect);
+        out.u8(self.irq_latch);
+        out.u8(self.irq_counter);
+        out.bool(self.irq_reload);
+        out.bool(self.irq_enable);
+        out.bool(self.irq_pending);
+        out.bool(self.last_a12);
+        out.bytes(&self.prg_ram);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.mirroring = try!(Mirroring::load_state(input));
+        self.bank_select = try!(input.u8());
+        for bank in self.banks.iter_mut() {
+            *bank = try!(input.u8());
+        }
+        self.prg_ram_enable = try!(input.bool());
+        self.prg_ram_write_protect = try!(input.bool());
+        self.irq_latch = try!(input.u8());
+        self.irq_counter = try!(input.u8());
+        self.irq_reload = try!(input.bool());
+        self.irq_enable = try!(input.bool());
+        self.irq_pending = try!(input.bool());
+        self.last_a12 = try!(input.bool());
+        try!(input.bytes(&mut self.prg_ram));
+        self.chr.load_state(input)
+    }
+}
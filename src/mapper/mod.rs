@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use cart::Chr;
+use rom::{RomError, RomHeader};
+use state::{StateError, StateReader, StateWriter};
+
+mod nrom;
+mod mmc1;
+mod mmc3;
+mod uxrom;
+mod cprom;
+mod unrom512;
+mod vrc6;
+mod fme7;
+mod axrom;
+
+// The PPU and the CPU both talk to the cartridge, so they share the mapper.
+pub type SharedMapper = Rc<RefCell<Box<Mapper>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+impl Mirroring {
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(*self as u8);
+    }
+
+    pub fn load_state(input: &mut StateReader) -> Result<Mirroring, StateError> {
+        match try!(input.u8()) {
+            0 => Ok(Mirroring::Horizontal),
+            1 => Ok(Mirroring::Vertical),
+            2 => Ok(Mirroring::FourScreen),
+            3 => Ok(Mirroring::SingleScreenLower),
+            4 => Ok(Mirroring::SingleScreenUpper),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    // Which 1KB page of nametable RAM each of the four nametables at
+    // $2000-$2FFF uses. Pages 0 and 1 are the console's own, 2 and 3 the
+    // extra RAM a four-screen board carries.
+    pub fn nametable_page(&self, table: usize) -> usize {
+        match *self {
+            Mirroring::Horizontal => (table >> 1) & 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::FourScreen => table & 3,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+        }
+    }
+}
+
+// Everything on the cartridge side of the CPU and PPU buses goes through here.
+// CPU addresses are $4020-$FFFF, PPU addresses are the pattern tables at
+// $0000-$1FFF.
+pub trait Mapper {
+    fn cpu_read(&self, addr: u16) -> u8;
+    fn cpu_write(&mut self, addr: u16, value: u8);
+
+    fn ppu_read(&self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, value: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Where nametable accesses go, see Mirroring::nametable_page. Boards
+    // that wire the nametable RAM's address lines up some other way than
+    // one of the mirroring modes override this.
+    fn nametable_page(&self, table: usize) -> usize {
+        self.mirroring().nametable_page(table)
+    }
+
+    // Level of the cartridge's IRQ output.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called once for every CPU cycle, for mappers with their own timers.
+    fn tick(&mut self) {}
+
+    // Battery backed carts save and restore their PRG RAM through these.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Called with the addresses the PPU puts on its bus for pattern fetches
+    // and PPUADDR writes, for mappers that count scanlines off A12.
+    fn notify_ppu_addr(&mut self, _addr: u16) {}
+
+    // Output of the cartridge's own sound chip, for boards that have one.
+    // Measured in APU pulse channels at full volume so the mixer can blend
+    // it in at the right level.
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Everything a save state needs to put the board back as it was:
+    // registers, counters and any RAM. ROM never changes so it's left out.
+    fn save_state(&self, out: &mut StateWriter);
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError>;
+}
+
+// Picks the mapper implementation for the cartridge's iNES mapper number.
+pub fn new_mapper(header: &RomHeader,
+                  prg_rom: Box<[u8]>,
+                  chr_rom: Box<[u8]>)
+                  -> Result<SharedMapper, RomError>
+{
+    // the banking maths assumes there's at least one whole bank, or two
+    // for the boards with a fixed bank or pair of banks at the top
+    let min_prg_rom = match header.mapper {
+        1 | 2 | 4 | 30 => 0x4000,
+        24 | 26 | 69 => 0x2000,
+        _ => 1,
+    };
+    if prg_rom.len() < min_prg_rom {
+        return Err(RomError::PrgRomTooSmall { size: prg_rom.len(), min: min_prg_rom });
+    }
+
+    let mirroring = header.mirroring();
+    let mapper: Box<Mapper> = match header.mapper {
+        0 => Box::new(nrom::Nrom::new(prg_rom, new_chr(header, chr_rom, 0x2000), mirroring)),
+        1 => {
+            Box::new(mmc1::Mmc1::new(prg_rom,
+                                     new_chr(header, chr_rom, 0x2000),
+                                     new_prg_ram(header)))
+        }
+        2 => Box::new(uxrom::Uxrom::new(prg_rom, new_chr(header, chr_rom, 0x2000), mirroring)),
+        4 => {
+            Box::new(mmc3::Mmc3::new(prg_rom,
+                                     new_chr(header, chr_rom, 0x2000),
+                                     new_prg_ram(header),
+                                     mirroring))
+        }
+        7 => Box::new(axrom::Axrom::new(prg_rom, new_chr(header, chr_rom, 0x2000))),
+        13 => Box::new(cprom::Cprom::new(prg_rom, new_chr(header, chr_rom, 0x4000), mirroring)),
+        24 => Box::new(vrc6::Vrc6::new(prg_rom, new_chr(header, chr_rom, 0x2000), false)),
+        26 => Box::new(vrc6::Vrc6::new(prg_rom, new_chr(header, chr_rom, 0x2000), true)),
+        30 => {
+            // the four-screen bit without vertical mirroring means the
+            // board's one-screen mirroring is under software control
+            let one_screen = header.four_screen_vram && !header.vertical_mirroring;
+            let mirroring = if one_screen {
+                Mirroring::SingleScreenLower
+            } else {
+                mirroring
+            };
+            Box::new(unrom512::Unrom512::new(prg_rom,
+                                             new_chr(header, chr_rom, 0x8000),
+                                             mirroring,
+                                             one_screen))
+        }
+        69 => Box::new(fme7::Fme7::new(prg_rom, new_chr(header, chr_rom, 0x2000))),
+        n => return Err(RomError::UnsupportedMapper(n)),
+    };
+    Ok(Rc::new(RefCell::new(mapper)))
+}
+
+// PRG RAM at $6000-$7FFF of the size the header asks for, battery backed
+// or not. 8KB if the header doesn't say.
+fn new_prg_ram(header: &RomHeader) -> Box<[u8]> {
+    let len = header.prg_ram_size + header.prg_nvram_size;
+    vec![0; if len == 0 { 0x2000 } else { len }].into_boxed_slice()
+}
+
+// CHR ROM if the cart has any, otherwise CHR RAM of the size the header asks
+// for. iNES 1.0 headers can't describe more than 8KB of CHR RAM, so boards
+// that always carry more give their size as min_ram.
+fn new_chr(header: &RomHeader, chr_rom: Box<[u8]>, min_ram: usize) -> Chr {
+    if chr_rom.len() > 0 {
+        return Chr::rom(chr_rom);
+    }
+
+    let len = header.chr_ram_size + header.chr_nvram_size;
+    Chr::ram(if len < min_ram { min_ram } else { len })
+}
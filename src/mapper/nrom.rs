@@ -0,0 +1,57 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+// Mapper 0: 16KB or 32KB of PRG ROM, 8KB of CHR and no registers at all.
+// A 16KB PRG ROM shows up at both $8000 and $C000.
+pub struct Nrom {
+    prg_rom: Box<[u8]>,
+    chr: Chr,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, mirroring: Mirroring) -> Nrom {
+        Nrom {
+            prg_rom: prg_rom,
+            chr: chr,
+            mirroring: mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if addr >= PRG_ROM_START {
+            let offset = (addr - PRG_ROM_START) as usize % self.prg_rom.len();
+            self.prg_rom[offset]
+        } else {
+            0
+        }
+    }
+
+    fn cpu_write(&mut self, _addr: u16, _value: u8) {
+        // mapper 0 doesn't do anything afaik.
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read_u8(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.chr.write_u8(addr, value)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.chr.load_state(input)
+    }
+}
@@ -0,0 +1,93 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const CHR_BANK_LEN: usize = 0x2000;
+
+// Mapper 30: UxROM style PRG banking plus four 8KB banks of CHR RAM, all
+// selected by one register at $8000-$FFFF - MCCPPPPP. The M bit picks the
+// nametable on boards wired for one-screen mirroring.
+// http://wiki.nesdev.com/w/index.php/UNROM_512
+pub struct Unrom512 {
+    prg_rom: Box<[u8]>,
+    chr: Chr,
+    mirroring: Mirroring,
+    one_screen: bool,
+
+    bank_select: u8,
+}
+
+impl Unrom512 {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, mirroring: Mirroring, one_screen: bool) -> Unrom512 {
+        Unrom512 {
+            prg_rom: prg_rom,
+            chr: chr,
+            mirroring: mirroring,
+            one_screen: one_screen,
+
+            bank_select: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_ROM_LOWER_LEN as usize
+    }
+
+    fn chr_bank(&self) -> usize {
+        (self.bank_select as usize >> 5) & 0b11
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        let bank = if addr >= PRG_ROM_UPPER_START {
+            self.prg_banks() - 1
+        } else if addr >= PRG_ROM_LOWER_START {
+            (self.bank_select as usize & 0x1F) % self.prg_banks()
+        } else {
+            return 0;
+        };
+
+        let offset = (addr as usize) & (PRG_ROM_LOWER_LEN as usize - 1);
+        self.prg_rom[bank * PRG_ROM_LOWER_LEN as usize + offset]
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.bank_select = value;
+            if self.one_screen {
+                self.mirroring = if value & 0x80 == 0 {
+                    Mirroring::SingleScreenLower
+                } else {
+                    Mirroring::SingleScreenUpper
+                };
+            }
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read_banked_u8(self.chr_bank(), CHR_BANK_LEN, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_bank();
+        self.chr.write_banked_u8(bank, CHR_BANK_LEN, addr, value)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.bank_select);
+        self.mirroring.save_state(out);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.bank_select = try!(input.u8());
+        self.mirroring = try!(Mirroring::load_state(input));
+        self.chr.load_state(input)
+    }
+}
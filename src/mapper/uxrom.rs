@@ -0,0 +1,73 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+// Mapper 2: a switchable 16KB bank at $8000 and the last bank fixed at $C000.
+// Any write to $8000-$FFFF selects the lower bank.
+pub struct Uxrom {
+    prg_rom: Box<[u8]>,
+    chr: Chr,
+    mirroring: Mirroring,
+
+    low_prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, mirroring: Mirroring) -> Uxrom {
+        Uxrom {
+            prg_rom: prg_rom,
+            chr: chr,
+            mirroring: mirroring,
+
+            low_prg_bank: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_ROM_LOWER_LEN as usize
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        let bank = if addr >= PRG_ROM_UPPER_START {
+            self.prg_banks() - 1
+        } else if addr >= PRG_ROM_LOWER_START {
+            self.low_prg_bank as usize % self.prg_banks()
+        } else {
+            return 0;
+        };
+
+        let offset = (addr as usize) & (PRG_ROM_LOWER_LEN as usize - 1);
+        self.prg_rom[bank * PRG_ROM_LOWER_LEN as usize + offset]
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.low_prg_bank = value & 0xF;
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read_u8(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.chr.write_u8(addr, value)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.low_prg_bank);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.low_prg_bank = try!(input.u8());
+        self.chr.load_state(input)
+    }
+}
@@ -0,0 +1,500 @@
+use cart::Chr;
+use mem_map::*;
+use super::{Mapper, Mirroring};
+use state::{StateError, StateReader, StateWriter};
+
+const PRG_RAM_LEN: usize = 0x2000;
+const PRG_BANK_LEN: usize = 0x2000;
+const CHR_BANK_LEN: usize = 0x400;
+
+// CPU cycles per scanline times 3, the IRQ prescaler counts down by 3
+const IRQ_PRESCALER_PERIOD: i16 = 341;
+
+// Mappers 24 and 26: Konami's VRC6, 16KB + 8KB PRG banks, 1KB CHR banks, a
+// CPU cycle based IRQ counter and two pulse channels plus a sawtooth.
+// Mapper 26 boards have CPU A0 and A1 swapped.
+// http://wiki.nesdev.com/w/index.php/VRC6
+pub struct Vrc6 {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Chr,
+
+    swap_lines: bool,
+
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003 - R.MMNNPP, PRG RAM enable, mirroring and the CHR layout
+    banking: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_prescaler: i16,
+    irq_enable: bool,
+    irq_enable_after_ack: bool,
+    irq_cycle_mode: bool,
+    irq_pending: bool,
+
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    // $9003 - .....ABH, halt and the frequency shifts
+    audio_halt: bool,
+    freq_shift: u8,
+}
+
+impl Vrc6 {
+    pub fn new(prg_rom: Box<[u8]>, chr: Chr, swap_lines: bool) -> Vrc6 {
+        Vrc6 {
+            prg_rom: prg_rom,
+            prg_ram: vec![0; PRG_RAM_LEN].into_boxed_slice(),
+            chr: chr,
+
+            swap_lines: swap_lines,
+
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking: 0,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: IRQ_PRESCALER_PERIOD,
+            irq_enable: false,
+            irq_enable_after_ack: false,
+            irq_cycle_mode: false,
+            irq_pending: false,
+
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            audio_halt: false,
+            freq_shift: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        let addr = if self.swap_lines {
+            addr & !0b11 | (addr & 1) << 1 | (addr & 2) >> 1
+        } else {
+            addr
+        };
+
+        match addr & 0xF003 {
+            0x8000...0x8003 => self.prg_bank_16k = value & 0x0F,
+            0x9000...0x9002 => self.pulse1.write(addr & 3, value),
+            0x9003 => {
+                self.audio_halt = value & 1 != 0;
+                // the x256 shift wins over the x16 one
+                self.freq_shift = if value & 4 != 0 {
+                    8
+                } else if value & 2 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000...0xA002 => self.pulse2.write(addr & 3, value),
+            0xB000...0xB002 => self.saw.write(addr & 3, value),
+            0xB003 => self.banking = value,
+            0xC000...0xC003 => self.prg_bank_8k = value & 0x1F,
+            0xD000...0xD003 => self.chr_banks[(addr & 3) as usize] = value,
+            0xE000...0xE003 => self.chr_banks[4 + (addr & 3) as usize] = value,
+            0xF000 => self.irq_latch = value,
+            0xF001 => {
+                self.irq_enable_after_ack = value & 1 != 0;
+                self.irq_enable = value & 2 != 0;
+                self.irq_cycle_mode = value & 4 != 0;
+                if self.irq_enable {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = IRQ_PRESCALER_PERIOD;
+                }
+                self.irq_pending = false;
+            }
+            0xF002 => {
+                self.irq_pending = false;
+                self.irq_enable = self.irq_enable_after_ack;
+            }
+            _ => {}
+        }
+    }
+
+    // In scanline mode the counter is clocked every 113 2/3 CPU cycles.
+    fn clock_irq(&mut self) {
+        if !self.irq_enable {
+            return;
+        }
+
+        if !self.irq_cycle_mode {
+            self.irq_prescaler -= 3;
+            if self.irq_prescaler > 0 {
+                return;
+            }
+            self.irq_prescaler += IRQ_PRESCALER_PERIOD;
+        }
+
+        if self.irq_counter == 0xFF {
+            self.irq_counter = self.irq_latch;
+            self.irq_pending = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking & 0x80 != 0
+    }
+
+    // 1KB bank mapped at addr. Modes 1-3 use 2KB banks for some or all of
+    // the pattern tables, those take A10 from the PPU like the MMC3's.
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        let slot = (addr >> 10) as usize & 7;
+        let a10 = slot & 1;
+        match (self.banking & 0b11, slot) {
+            (0, _) => self.chr_banks[slot] as usize,
+            (1, _) => (self.chr_banks[slot / 2] as usize & !1) | a10,
+            (_, 0...3) => self.chr_banks[slot] as usize,
+            (_, _) => (self.chr_banks[4 + (slot - 4) / 2] as usize & !1) | a10,
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enabled() {
+                    self.prg_ram[(addr - SRAM_START) as usize]
+                } else {
+                    0
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => {
+                let count = self.prg_rom.len() / PRG_BANK_LEN;
+                let bank = match addr {
+                    0x8000...0xBFFF => self.prg_bank_16k as usize * 2 + (addr as usize >> 13 & 1),
+                    0xC000...0xDFFF => self.prg_bank_8k as usize,
+                    _ => count - 1,
+                };
+                let offset = addr as usize & (PRG_BANK_LEN - 1);
+                self.prg_rom[(bank % count) * PRG_BANK_LEN + offset]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SRAM_START...SRAM_END => {
+                if self.prg_ram_enabled() {
+                    self.prg_ram[(addr - SRAM_START) as usize] = value;
+                }
+            }
+            PRG_ROM_START...PRG_ROM_END => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let bank = self.chr_bank_at(addr);
+        self.chr.read_banked_u8(bank, CHR_BANK_LEN, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_banked_u8(bank, CHR_BANK_LEN, addr, value)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // Games leave the nametables in CIRAM, where the mode 0 layout of the
+    // mirroring bits is the one everything uses.
+    fn mirroring(&self) -> Mirroring {
+        match self.banking >> 2 & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self) {
+        self.clock_irq();
+        if !self.audio_halt {
+            self.pulse1.clock(self.freq_shift);
+            self.pulse2.clock(self.freq_shift);
+            self.saw.clock(self.freq_shift);
+        }
+    }
+
+    // The pulses are about as loud as the APU's at the same volume.
+    fn audio_output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        level as f32 / 15.0
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.prg_bank_16k);
+        out.u8(self.prg_bank_8k);
+        for &bank in self.chr_banks.iter() {
+            out.u8(bank);
+        }
+        out.u8(self.banking);
+        out.u8(self.irq_latch);
+        out.u8(self.irq_counter);
+        out.u16(self.irq_prescaler as u16);
+        out.bool(self.irq_enable);
+        out.bool(self.irq_enable_after_ack);
+        out.bool(self.irq_cycle_mode);
+        out.bool(self.irq_pending);
+        self.pulse1.save_state(out);
+        self.pulse2.save_state(out);
+        self.saw.save_state(out);
+        out.bool(self.audio_halt);
+        out.u8(self.freq_shift);
+        out.bytes(&self.prg_ram);
+        self.chr.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank_16k = try!(input.u8());
+        self.prg_bank_8k = try!(input.u8());
+        for bank in self.chr_banks.iter_mut() {
+            *bank = try!(input.u8());
+        }
+        self.banking = try!(input.u8());
+        self.irq_latch = try!(input.u8());
+        self.irq_counter = try!(input.u8());
+        self.irq_prescaler = try!(input.u16()) as i16;
+        self.irq_enable = try!(input.bool());
+        self.irq_enable_after_ack = try!(input.bool());
+        self.irq_cycle_mode = try!(input.bool());
+        self.irq_pending = try!(input.bool());
+        try!(self.pulse1.load_state(input));
+        try!(self.pulse2.load_state(input));
+        try!(self.saw.load_state(input));
+        self.audio_halt = try!(input.bool());
+        self.freq_shift = try!(input.u8());
+        try!(input.bytes(&mut self.prg_ram));
+        self.chr.load_state(input)
+    }
+}
+
+// $9000-$9002 and $A000-$A002: a pulse with 16 steps and 8 duty cycles.
+struct Vrc6Pulse {
+    // MDDDVVVV
+    ignore_duty: bool,
+    duty: u8,
+    volume: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            ignore_duty: false,
+            duty: 0,
+            volume: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.ignore_duty = value & 0x80 != 0;
+                self.duty = value >> 4 & 0b111;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = self.period & 0x0F00 | value as u16,
+            _ => {
+                self.period = self.period & 0x00FF | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.ignore_duty);
+        out.u8(self.duty);
+        out.u8(self.volume);
+        out.bool(self.enabled);
+        out.u16(self.period);
+        out.u16(self.timer);
+        out.u8(self.step);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.ignore_duty = try!(input.bool());
+        self.duty = try!(input.u8());
+        self.volume = try!(input.u8());
+        self.enabled = try!(input.bool());
+        self.period = try!(input.u16());
+        self.timer = try!(input.u16());
+        self.step = try!(input.u8());
+        if self.step > 0x0F {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+// $B000-$B002: a sawtooth made by adding the rate to an accumulator every
+// other step and clearing it after the 7th add.
+struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            rate: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = self.period & 0x0F00 | value as u16,
+            _ => {
+                self.period = self.period & 0x00FF | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step % 2 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.rate);
+        out.bool(self.enabled);
+        out.u16(self.period);
+        out.u16(self.timer);
+        out.u8(self.step);
+        out.u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.rate = try!(input.u8());
+        self.enabled = try!(input.bool());
+        self.period = try!(input.u16());
+        self.timer = try!(input.u16());
+        self.step = try!(input.u8());
+        self.accumulator = try!(input.u8());
+        if self.step >= 14 {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+
+    // the top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulse_step_out_of_range_is_corrupt() {
+        let mut out = StateWriter::new(0);
+        Vrc6Pulse::new().save_state(&mut out);
+        let mut data = out.into_bytes();
+        // the step is saved last
+        let pos = data.len() - 1;
+        data[pos] = 0x10;
+        let mut input = StateReader::new(&data, 0).unwrap();
+        match Vrc6Pulse::new().load_state(&mut input) {
+            Err(StateError::Corrupt) => (),
+            _ => panic!("loaded a pulse on step 16"),
+        }
+    }
+
+    #[test]
+    fn saw_step_out_of_range_is_corrupt() {
+        let mut out = StateWriter::new(0);
+        Vrc6Saw::new().save_state(&mut out);
+        let mut data = out.into_bytes();
+        // the step comes just before the accumulator
+        let pos = data.len() - 2;
+        data[pos] = 14;
+        let mut input = StateReader::new(&data, 0).unwrap();
+        match Vrc6Saw::new().load_state(&mut input) {
+            Err(StateError::Corrupt) => (),
+            _ => panic!("loaded a saw on step 14"),
+        }
+    }
+}
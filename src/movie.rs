@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use state::StateError;
+
+// Input movies, controller 1's buttons for every frame from a known
+// starting point. Our own files start from a save state so the battery RAM
+// and everything else is pinned down; FM2 files, FCEUX's text format,
+// start from power on.
+const MAGIC: &'static [u8] = b"OXMV";
+const VERSION: u32 = 1;
+
+// FM2 writes each pad as RLDUTSBA, the reverse of our bit order.
+const FM2_BUTTONS: &'static [u8] = b"RLDUTSBA";
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    // the line of an FM2 file that couldn't be used, and why
+    Fm2 { line: usize, reason: &'static str },
+    // recorded on a different ROM than the one loaded
+    WrongRom,
+    // the starting save state wouldn't load
+    State(StateError),
+}
+
+pub struct Movie {
+    // CRC32 and MD5 of the ROM it was recorded on, see Rom::hash. FM2
+    // files only carry the MD5.
+    pub rom_hash: Option<u32>,
+    pub rom_md5: Option<[u8; 16]>,
+    // where playback starts from, None for power on
+    pub start_state: Option<Vec<u8>>,
+    // a joy::BUTTON_* mask per frame
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(rom_hash: u32, rom_md5: [u8; 16], start_state: Option<Vec<u8>>) -> Movie {
+        Movie {
+            rom_hash: Some(rom_hash),
+            rom_md5: Some(rom_md5),
+            start_state: start_state,
+            frames: Vec::new(),
+        }
+    }
+
+    // Reads either format, going by the contents rather than the name.
+    pub fn load(path: &Path) -> Result<Movie, MovieError> {
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data)));
+        if data.starts_with(MAGIC) {
+            Movie::parse(&data)
+        } else {
+            Movie::parse_fm2(&String::from_utf8_lossy(&data))
+        }
+    }
+
+    // Writes an FM2 file if the name ends in .fm2, our own format otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = if path.extension().map_or(false, |e| e == "fm2") {
+            let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            self.to_fm2(name).into_bytes()
+        } else {
+            self.to_bytes()
+        };
+        File::create(path).and_then(|mut file| file.write_all(&data))
+    }
+
+    // Whether the movie was recorded on the ROM with these hashes, as far
+    // as the movie knows.
+    pub fn matches_rom(&self, hash: u32, md5: &[u8; 16]) -> bool {
+        self.rom_hash.map_or(true, |h| h == hash) && self.rom_md5.map_or(true, |m| &m == md5)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        write_u32(&mut out, VERSION);
+        write_u32(&mut out, self.rom_hash.unwrap_or(0));
+        out.extend_from_slice(&self.rom_md5.unwrap_or([0; 16]));
+        let state: &[u8] = self.start_state.as_ref().map_or(&[], |s| s);
+        write_u32(&mut out, state.len() as u32);
+        out.extend_from_slice(state);
+        write_u32(&mut out, self.frames.len() as u32);
+        out.extend_from_slice(&self.frames);
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        if !data.starts_with(MAGIC) {
+            return Err(MovieError::BadMagic);
+        }
+        let mut pos = MAGIC.len();
+        let version = try!(read_u32(data, &mut pos));
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_hash = try!(read_u32(data, &mut pos));
+        let mut rom_md5 = [0; 16];
+        rom_md5.copy_from_slice(try!(read_bytes(data, &mut pos, 16)));
+
+        let state_len = try!(read_u32(data, &mut pos)) as usize;
+        let start_state = if state_len > 0 {
+            Some(try!(read_bytes(data, &mut pos, state_len)).to_vec())
+        } else {
+            None
+        };
+        let frame_count = try!(read_u32(data, &mut pos)) as usize;
+        let frames = try!(read_bytes(data, &mut pos, frame_count)).to_vec();
+
+        Ok(Movie {
+            rom_hash: Some(rom_hash),
+            rom_md5: Some(rom_md5),
+            start_state: start_state,
+            frames: frames,
+        })
+    }
+
+    // Only what we can play back is accepted: text input, a single
+    // controller in port 0 and no resets past the first frame.
+    pub fn parse_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            rom_hash: None,
+            rom_md5: None,
+            start_state: None,
+            frames: Vec::new(),
+        };
+        let mut seen_version = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let fail = |reason| MovieError::Fm2 { line: line_number, reason: reason };
+            let line = line.trim_right();
+
+            if line.starts_with('|') {
+                let fields: Vec<&str> = line.split('|').collect();
+                if fields.len() < 3 {
+                    return Err(fail("input line has too few fields"));
+                }
+                let commands: u32 = try!(fields[1].parse().map_err(|_| fail("bad command number")));
+                // a power on before the first frame is where we start anyway
+                let power_on_first = commands == 2 && movie.frames.is_empty();
+                if commands != 0 && !power_on_first {
+                    return Err(fail("resets and other commands aren't supported"));
+                }
+                let pad = fields[2].as_bytes();
+                if pad.len() != FM2_BUTTONS.len() {
+                    return Err(fail("controller 1 should have 8 buttons"));
+                }
+                let mut buttons = 0;
+                for (i, &c) in pad.iter().enumerate() {
+                    if c != b'.' && c != b' ' {
+                        buttons |= 1 << (7 - i);
+                    }
+                }
+                movie.frames.push(buttons);
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "" => (),
+                "version" => {
+                    if value != "3" {
+                        return Err(fail("only version 3 movies are supported"));
+                    }
+                    seen_version = true;
+                }
+                "binary" if value != "0" => return Err(fail("binary input isn't supported")),
+                "savestate" => return Err(fail("movies starting from a save state aren't supported")),
+                "palFlag" if value != "0" => return Err(fail("PAL movies aren't supported")),
+                "fourscore" if value != "0" => return Err(fail("the Four Score isn't supported")),
+                "port0" if value != "1" => return Err(fail("port 0 has to be a gamepad")),
+                "romChecksum" => {
+                    let md5 = if value.starts_with("base64:") {
+                        decode_base64(&value[7..])
+                    } else {
+                        None
+                    };
+                    match md5 {
+                        Some(ref md5) if md5.len() == 16 => {
+                            let mut digest = [0; 16];
+                            digest.copy_from_slice(md5);
+                            movie.rom_md5 = Some(digest);
+                        }
+                        _ => return Err(fail("couldn't read the ROM checksum")),
+                    }
+                }
+                // anything else is information for FCEUX or people
+                _ => (),
+            }
+        }
+
+        if !seen_version {
+            return Err(MovieError::Fm2 {
+                line: 1,
+                reason: "not an FM2 file, there's no version",
+            });
+        }
+        Ok(movie)
+    }
+
+    // FCEUX plays these from power on, so they're only faithful for movies
+    // that start there too.
+    pub fn to_fm2(&self, rom_name: &str) -> String {
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str("emuVersion 22020\n");
+        out.push_str("rerecordCount 0\n");
+        out.push_str("palFlag 0\n");
+        out.push_str(&format!("romFilename {}\n", rom_name));
+        if let Some(ref md5) = self.rom_md5 {
+            out.push_str(&format!("romChecksum base64:{}\n", encode_base64(md5)));
+        }
+        out.push_str("guid 00000000-0000-0000-0000-000000000000\n");
+        out.push_str("fourscore 0\n");
+        out.push_str("microphone 0\n");
+        out.push_str("port0 1\n");
+        out.push_str("port1 0\n");
+        out.push_str("port2 0\n");
+        out.push_str("FDS 0\n");
+        out.push_str("NewPPU 0\n");
+        out.push_str("comment author oxidenes\n");
+
+        for &buttons in self.frames.iter() {
+            let pad: String = FM2_BUTTONS.iter()
+                .enumerate()
+                .map(|(i, &c)| if buttons & (1 << (7 - i)) != 0 { c as char } else { '.' })
+                .collect();
+            out.push_str(&format!("|0|{}|||\n", pad));
+        }
+        out
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32, MovieError> {
+    let bytes = try!(read_bytes(data, pos, 4));
+    Ok(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u32))
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], MovieError> {
+    if data.len() - *pos < len {
+        return Err(MovieError::Truncated);
+    }
+    *pos += len;
+    Ok(&data[*pos - len..*pos])
+}
+
+const BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let value = match BASE64.iter().position(|&b| b == c) {
+            Some(value) => value as u32,
+            None => return None,
+        };
+        bits = bits << 6 | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            out.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(out)
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> MovieError {
+        MovieError::Io(e)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> MovieError {
+        MovieError::State(e)
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref e) => write!(f, "couldn't read the movie: {}", e),
+            MovieError::BadMagic => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(v) => {
+                write!(f, "movie version {} isn't supported, expected {}", v, VERSION)
+            }
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Fm2 { line, reason } => write!(f, "FM2 line {}: {}", line, reason),
+            MovieError::WrongRom => write!(f, "movie was recorded on a different ROM"),
+            MovieError::State(ref e) => write!(f, "movie's starting state: {}", e),
+        }
+    }
+}
+
+impl Error for MovieError {
+    fn description(&self) -> &str {
+        match *self {
+            MovieError::Io(ref e) => e.description(),
+            MovieError::BadMagic => "not a movie file",
+            MovieError::UnsupportedVersion(_) => "unsupported movie version",
+            MovieError::Truncated => "movie is truncated",
+            MovieError::Fm2 { reason, .. } => reason,
+            MovieError::WrongRom => "movie was recorded on a different ROM",
+            MovieError::State(ref e) => e.description(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use joy;
+
+    // MD5 of nothing at all
+    const EMPTY_MD5: [u8; 16] = [0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09,
+                                 0x98, 0xec, 0xf8, 0x42, 0x7e];
+    const EMPTY_MD5_BASE64: &'static str = "1B2M2Y8AsgTpgAmY7PhCfg==";
+
+    fn movie(start_state: Option<Vec<u8>>) -> Movie {
+        let mut movie = Movie::new(0xDEADBEEF, EMPTY_MD5, start_state);
+        movie.frames = vec![0, joy::BUTTON_A, joy::BUTTON_START | joy::BUTTON_RIGHT, 0xFF];
+        movie
+    }
+
+    fn fm2_error(text: &str) -> (usize, &'static str) {
+        match Movie::parse_fm2(text) {
+            Err(MovieError::Fm2 { line, reason }) => (line, reason),
+            Err(e) => panic!("expected an FM2 error, got {}", e),
+            Ok(_) => panic!("expected an FM2 error"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for start_state in vec![None, Some(vec![1, 2, 3])] {
+            let movie = movie(start_state.clone());
+            let parsed = Movie::parse(&movie.to_bytes()).unwrap();
+            assert_eq!(parsed.rom_hash, Some(0xDEADBEEF));
+            assert_eq!(parsed.rom_md5, Some(EMPTY_MD5));
+            assert_eq!(parsed.start_state, start_state);
+            assert_eq!(parsed.frames, movie.frames);
+        }
+    }
+
+    #[test]
+    fn wrong_magic_and_version() {
+        match Movie::parse(b"OXMW\x01\x00\x00\x00") {
+            Err(MovieError::BadMagic) => (),
+            _ => panic!("expected bad magic"),
+        }
+
+        let mut data = movie(None).to_bytes();
+        data[4] = 2;
+        match Movie::parse(&data) {
+            Err(MovieError::UnsupportedVersion(2)) => (),
+            _ => panic!("expected an unsupported version"),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = movie(Some(vec![1, 2, 3])).to_bytes();
+        for len in MAGIC.len()..data.len() {
+            match Movie::parse(&data[..len]) {
+                Err(MovieError::Truncated) => (),
+                _ => panic!("expected {} bytes to be truncated", len),
+            }
+        }
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        let movie = movie(None);
+        let text = movie.to_fm2("game");
+        assert!(text.contains(&format!("romChecksum base64:{}\n", EMPTY_MD5_BASE64)));
+
+        let parsed = Movie::parse_fm2(&text).unwrap();
+        assert_eq!(parsed.rom_hash, None);
+        assert_eq!(parsed.rom_md5, Some(EMPTY_MD5));
+        assert_eq!(parsed.start_state, None);
+        assert_eq!(parsed.frames, movie.frames);
+    }
+
+    #[test]
+    fn fm2_button_order() {
+        let text = movie(None).to_fm2("game");
+        let pads: Vec<&str> = text.lines().filter(|l| l.starts_with('|')).collect();
+        assert_eq!(pads,
+                   vec!["|0|........|||", "|0|.......A|||", "|0|R...T...|||", "|0|RLDUTSBA|||"]);
+
+        let buttons = [joy::BUTTON_RIGHT,
+                       joy::BUTTON_LEFT,
+                       joy::BUTTON_DOWN,
+                       joy::BUTTON_UP,
+                       joy::BUTTON_START,
+                       joy::BUTTON_SELECT,
+                       joy::BUTTON_B,
+                       joy::BUTTON_A];
+        for (i, &button) in buttons.iter().enumerate() {
+            let mut pad = [b'.'; 8];
+            pad[i] = FM2_BUTTONS[i];
+            let text = format!("version 3\n|0|{}|||\n", String::from_utf8_lossy(&pad));
+            assert_eq!(Movie::parse_fm2(&text).unwrap().frames, vec![button]);
+        }
+    }
+
+    #[test]
+    fn fm2_power_on_only_on_the_first_frame() {
+        let movie = Movie::parse_fm2("version 3\n|2|........|||\n|0|.......A|||\n").unwrap();
+        assert_eq!(movie.frames, vec![0, joy::BUTTON_A]);
+
+        let text = "version 3\n|0|........|||\n|2|........|||\n";
+        assert_eq!(fm2_error(text), (3, "resets and other commands aren't supported"));
+        let text = "version 3\n|1|........|||\n";
+        assert_eq!(fm2_error(text), (2, "resets and other commands aren't supported"));
+    }
+
+    #[test]
+    fn fm2_errors() {
+        assert_eq!(fm2_error("version 2\n"), (1, "only version 3 movies are supported"));
+        assert_eq!(fm2_error("version 3\nsavestate base64:AAAA\n"),
+                   (2, "movies starting from a save state aren't supported"));
+        assert_eq!(fm2_error("version 3\n|0|.......|||\n"),
+                   (2, "controller 1 should have 8 buttons"));
+        assert_eq!(fm2_error("version 3\n|0\n"), (2, "input line has too few fields"));
+        assert_eq!(fm2_error("|0|........|||\n"), (1, "not an FM2 file, there's no version"));
+        assert_eq!(fm2_error("version 3\nromChecksum base64:AAAA\n"),
+                   (2, "couldn't read the ROM checksum"));
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(encode_base64(&EMPTY_MD5), EMPTY_MD5_BASE64);
+        assert_eq!(decode_base64(EMPTY_MD5_BASE64), Some(EMPTY_MD5.to_vec()));
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(decode_base64("YWI="), Some(b"ab".to_vec()));
+        assert_eq!(decode_base64("YW*="), None);
+    }
+}
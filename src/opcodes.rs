@@ -32,7 +32,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   page_boundary_cycle: false,
                                               },
 
-                                              // 02 - not implemented
+                                              // 02
                                               Instruction {
                                                   name: "*KIL",
                                                   bytes: 1,
@@ -131,7 +131,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   page_boundary_cycle: false,
                                               },
 
-                                              // 0B - not implemented
+                                              // 0B
                                               Instruction {
                                                   name: "*AAC",
                                                   bytes: 2,
@@ -914,7 +914,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 52
                                               Instruction {
-                                                  name: "KIL",
+                                                  name: "*KIL",
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 0,
@@ -936,7 +936,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 54
                                               Instruction {
-                                                  name: "DOP",
+                                                  name: "*DOP",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 4,
@@ -1090,7 +1090,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 62
                                               Instruction {
-                                                  name: "KIL",
+                                                  name: "*KIL",
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 0,
@@ -1101,7 +1101,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 63
                                               Instruction {
-                                                  name: "RRA",
+                                                  name: "*RRA",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 8,
@@ -1112,7 +1112,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 64
                                               Instruction {
-                                                  name: "DOP",
+                                                  name: "*DOP",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 3,
@@ -1145,7 +1145,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 67
                                               Instruction {
-                                                  name: "RRA",
+                                                  name: "*RRA",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 5,
@@ -1618,7 +1618,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // 92
                                               Instruction {
-                                                  name: "KIL",
+                                                  name: "*KIL",
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 0,
@@ -1864,7 +1864,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 2,
-                                                  addr_mode: AddressMode::Accumulator,
+                                                  addr_mode: AddressMode::Implied,
                                                   dest_addr: None,
                                                   page_boundary_cycle: false,
                                               },
@@ -1886,7 +1886,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 2,
-                                                  addr_mode: AddressMode::Accumulator,
+                                                  addr_mode: AddressMode::Implied,
                                                   dest_addr: None,
                                                   page_boundary_cycle: false,
                                               },
@@ -1968,7 +1968,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   page_boundary_cycle: true,
                                               },
 
-                                              // B2 -- not implemented
+                                              // B2
                                               Instruction {
                                                   name: "*KIL",
                                                   bytes: 1,
@@ -2025,7 +2025,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // B7
                                               Instruction {
-                                                  name: "LAX",
+                                                  name: "*LAX",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 4,
@@ -2067,7 +2067,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   page_boundary_cycle: false,
                                               },
 
-                                              // BB -- not implemented
+                                              // BB
                                               Instruction {
                                                   name: "*LAR",
                                                   bytes: 3,
@@ -2243,13 +2243,13 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
                                                   page_boundary_cycle: false,
                                               },
 
-                                              // CB -- Not implemented
+                                              // CB
                                               Instruction {
                                                   name: "*AXS",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 2,
-                                                  addr_mode: AddressMode::Accumulator,
+                                                  addr_mode: AddressMode::Immediate,
                                                   dest_addr: None,
                                                   page_boundary_cycle: false,
                                               },
@@ -2322,7 +2322,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // D2
                                               Instruction {
-                                                  name: "KIL",
+                                                  name: "*KIL",
                                                   bytes: 1,
                                                   operand: 0,
                                                   ticks: 0,
@@ -2531,7 +2531,7 @@ pub const INSTRUCTIONS: [Instruction; 256] = [// 00
 
                                               // E5
                                               Instruction {
-                                                  name: "CPX",
+                                                  name: "SBC",
                                                   bytes: 2,
                                                   operand: 0,
                                                   ticks: 3,
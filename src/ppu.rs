@@ -1,4 +1,5 @@
-use cart;
+use mapper::SharedMapper;
+use state::{StateError, StateReader, StateWriter};
 // use time;
 
 const PALETTE: [u32; 64] = [
@@ -16,6 +17,16 @@ const PALETTE: [u32; 64] = [
 ];
 
 
+// A sprite fetched for the line being drawn, its pattern already flipped
+// so bit 7 is the leftmost pixel.
+#[derive(Clone, Copy)]
+struct SpriteSlot {
+    x: u8,
+    attr: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+}
+
 // #[derive(Debug)]
 pub struct PPU {
     // PPUCTRL $2000
@@ -47,6 +58,27 @@ pub struct PPU {
 
     oam: Box<[u8]>,
 
+    // Sprite evaluation, which over dots 65-256 copies the next line's
+    // sprites from OAM into secondary OAM, one byte every other dot.
+    secondary_oam: [u8; 32],
+    // n is the sprite being looked at and m the byte within it
+    eval_n: u8,
+    eval_m: u8,
+    eval_count: u8,
+    eval_latch: u8,
+    eval_done: bool,
+    // whether sprite 0 is the first entry of secondary OAM
+    eval_sprite0: bool,
+    // past the eighth, with no_sprite_limit set
+    extra_sprites: Vec<[u8; 4]>,
+    // what the line being drawn shows, fetched over dots 257-320 of the
+    // line before
+    sprite_slots: Vec<SpriteSlot>,
+    sprite0_in_slots: bool,
+    // draws every sprite on a line rather than the first eight, which
+    // gets rid of flicker at the cost of showing what games hide with it
+    no_sprite_limit: bool,
+
     // scroll_x: u8,
     // scroll_y: u8,
     vram_addr: u16,
@@ -58,25 +90,34 @@ pub struct PPU {
 
     palette: Box<[u8]>,
     vram: Box<[u8]>,
-    chr: cart::ChrRom,
+    mapper: SharedMapper,
 
     pub lastwrite: u8,
     ppudata_buffer: u8,
     initial_reset: bool,
     nmi_generated: bool,
 
-    sprite0_bg_prerender: [u8; 256],
+
+    // Background tiles on their way out, two tiles of pattern bits with
+    // the attribute bits spread to match, shifted a pixel per dot. The
+    // next tile is fetched over eight dots and loaded into the low byte.
+    bg_pattern_lo: u16,
+    bg_pattern_hi: u16,
+    bg_attr_lo: u16,
+    bg_attr_hi: u16,
+    next_tile: u8,
+    next_attr: u8,
+    next_pattern_lo: u8,
+    next_pattern_hi: u8,
 
     pub screen: [[u32; 256]; 240],
 
     pub framecount: usize,
-    pub extra_cycle: bool,
     pub cycles: isize,
-    bg_column: isize,
 }
 
 impl PPU {
-    pub fn new(chr: cart::ChrRom) -> PPU {
+    pub fn new(mapper: SharedMapper) -> PPU {
         PPU {
             // PPUCTRL $2000
             // base_nametable: 0x2000,
@@ -107,6 +148,18 @@ impl PPU {
 
             oam: vec![0; 256].into_boxed_slice(),
 
+            secondary_oam: [0xFF; 32],
+            eval_n: 0,
+            eval_m: 0,
+            eval_count: 0,
+            eval_latch: 0,
+            eval_done: false,
+            eval_sprite0: false,
+            extra_sprites: Vec::new(),
+            sprite_slots: Vec::new(),
+            sprite0_in_slots: false,
+            no_sprite_limit: false,
+
             // scroll_x: 0,
             // scroll_y: 0,
             vram_addr: 0,
@@ -117,11 +170,10 @@ impl PPU {
             scanline: 241,
 
             palette: vec![0; 32].into_boxed_slice(),
-            // TODO: mappers!
-            // research if we can just give it 4kb all the time
-            // then logic out the extra RAM
+            // 4kb covers four-screen carts too, the mirroring picks
+            // which of the four nametables are actually in use
             vram: vec![0; 1024 * 4].into_boxed_slice(),
-            chr: chr,
+            mapper: mapper,
 
             lastwrite: 0,
             ppudata_buffer: 0,
@@ -129,15 +181,186 @@ impl PPU {
             nmi_generated: false,
 
             screen: [[0; 256]; 240],
-            sprite0_bg_prerender: [0; 256],
+
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attr_lo: 0,
+            bg_attr_hi: 0,
+            next_tile: 0,
+            next_attr: 0,
+            next_pattern_lo: 0,
+            next_pattern_hi: 0,
 
             framecount: 0,
-            extra_cycle: false,
             cycles: 0,
-            bg_column: 0,
         }
     }
 
+    // The picture isn't saved, the next frame paints over it anyway.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.vram_increment);
+        out.bool(self.sprite_table_high);
+        out.bool(self.bg_table_high);
+        out.bool(self.sprite_8x16);
+        out.bool(self.ppu_master);
+        out.bool(self.nmi_enable);
+
+        out.bool(self.grayscale);
+        out.bool(self.bg_left_8px);
+        out.bool(self.sprite_left_8px);
+        out.bool(self.show_bg);
+        out.bool(self.show_sprites);
+        out.bool(self.emphasize_red);
+        out.bool(self.emphasize_green);
+        out.bool(self.emphasize_blue);
+
+        out.bool(self.sprite_overflow);
+        out.bool(self.sprite0_hit);
+        out.bool(self.vblank);
+
+        out.u8(self.oam_addr);
+        out.bytes(&self.oam);
+
+        out.bytes(&self.secondary_oam);
+        out.u8(self.eval_n);
+        out.u8(self.eval_m);
+        out.u8(self.eval_count);
+        out.u8(self.eval_latch);
+        out.bool(self.eval_done);
+        out.bool(self.eval_sprite0);
+        out.u8(self.extra_sprites.len() as u8);
+        for sprite in self.extra_sprites.iter() {
+            out.bytes(sprite);
+        }
+        out.u8(self.sprite_slots.len() as u8);
+        for slot in self.sprite_slots.iter() {
+            out.u8(slot.x);
+            out.u8(slot.attr);
+            out.u8(slot.pattern_lo);
+            out.u8(slot.pattern_hi);
+        }
+        out.bool(self.sprite0_in_slots);
+
+        out.u16(self.vram_addr);
+        out.u16(self.t_vram_addr);
+        out.u8(self.fine_x);
+        out.bool(self.w_toggle);
+
+        out.u16(self.scanline as u16);
+
+        out.bytes(&self.palette);
+        out.bytes(&self.vram);
+
+        out.u8(self.lastwrite);
+        out.u8(self.ppudata_buffer);
+        out.bool(self.initial_reset);
+        out.bool(self.nmi_generated);
+
+
+        out.u16(self.bg_pattern_lo);
+        out.u16(self.bg_pattern_hi);
+        out.u16(self.bg_attr_lo);
+        out.u16(self.bg_attr_hi);
+        out.u8(self.next_tile);
+        out.u8(self.next_attr);
+        out.u8(self.next_pattern_lo);
+        out.u8(self.next_pattern_hi);
+
+        out.u64(self.framecount as u64);
+        out.i64(self.cycles as i64);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.vram_increment = try!(input.bool());
+        self.sprite_table_high = try!(input.bool());
+        self.bg_table_high = try!(input.bool());
+        self.sprite_8x16 = try!(input.bool());
+        self.ppu_master = try!(input.bool());
+        self.nmi_enable = try!(input.bool());
+
+        self.grayscale = try!(input.bool());
+        self.bg_left_8px = try!(input.bool());
+        self.sprite_left_8px = try!(input.bool());
+        self.show_bg = try!(input.bool());
+        self.show_sprites = try!(input.bool());
+        self.emphasize_red = try!(input.bool());
+        self.emphasize_green = try!(input.bool());
+        self.emphasize_blue = try!(input.bool());
+
+        self.sprite_overflow = try!(input.bool());
+        self.sprite0_hit = try!(input.bool());
+        self.vblank = try!(input.bool());
+
+        self.oam_addr = try!(input.u8());
+        try!(input.bytes(&mut self.oam));
+
+        try!(input.bytes(&mut self.secondary_oam));
+        self.eval_n = try!(input.u8());
+        self.eval_m = try!(input.u8());
+        self.eval_count = try!(input.u8());
+        self.eval_latch = try!(input.u8());
+        self.eval_done = try!(input.bool());
+        self.eval_sprite0 = try!(input.bool());
+        if self.eval_n > 64 || self.eval_m > 3 || self.eval_count > 8 {
+            return Err(StateError::Corrupt);
+        }
+        self.extra_sprites.clear();
+        for _ in 0..try!(input.u8()) {
+            let mut sprite = [0; 4];
+            try!(input.bytes(&mut sprite));
+            self.extra_sprites.push(sprite);
+        }
+        self.sprite_slots.clear();
+        for _ in 0..try!(input.u8()) {
+            let slot = SpriteSlot {
+                x: try!(input.u8()),
+                attr: try!(input.u8()),
+                pattern_lo: try!(input.u8()),
+                pattern_hi: try!(input.u8()),
+            };
+            self.sprite_slots.push(slot);
+        }
+        self.sprite0_in_slots = try!(input.bool());
+        if self.sprite0_in_slots && self.sprite_slots.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        self.vram_addr = try!(input.u16()) & 0x7FFF;
+        self.t_vram_addr = try!(input.u16()) & 0x7FFF;
+        self.fine_x = try!(input.u8()) & 0b111;
+        self.w_toggle = try!(input.bool());
+
+        self.scanline = try!(input.u16()) as i16;
+
+        try!(input.bytes(&mut self.palette));
+        try!(input.bytes(&mut self.vram));
+
+        self.lastwrite = try!(input.u8());
+        self.ppudata_buffer = try!(input.u8());
+        self.initial_reset = try!(input.bool());
+        self.nmi_generated = try!(input.bool());
+
+
+        self.bg_pattern_lo = try!(input.u16());
+        self.bg_pattern_hi = try!(input.u16());
+        self.bg_attr_lo = try!(input.u16());
+        self.bg_attr_hi = try!(input.u16());
+        self.next_tile = try!(input.u8());
+        self.next_attr = try!(input.u8()) & 0b11;
+        self.next_pattern_lo = try!(input.u8());
+        self.next_pattern_hi = try!(input.u8());
+
+        self.framecount = try!(input.u64()) as usize;
+        self.cycles = try!(input.i64()) as isize;
+        Ok(())
+    }
+
+    // With limit false every sprite on a line is drawn, see no_sprite_limit.
+    // The overflow flag still behaves as if there were a limit.
+    pub fn set_sprite_limit(&mut self, limit: bool) {
+        self.no_sprite_limit = !limit;
+    }
+
     // $2000
     pub fn write_ppuctrl(&mut self, data: u8){
         self.lastwrite = data;
@@ -241,105 +464,66 @@ impl PPU {
             self.t_vram_addr |= data as u16;
 
             self.vram_addr = self.t_vram_addr;
+            self.mapper.borrow_mut().notify_ppu_addr(self.vram_addr);
             // self.ppu_addr |= data as u16;
         //    println!("PPUADDR set: {:#X} after sl {}", self.vram_addr, self.scanline);
         }
         self.w_toggle = !self.w_toggle;
     }
 
-    // TODO - mappers
     pub fn write_ppudata(&mut self, data:u8) {
         self.lastwrite = data;
-        let v_addr = self.vram_addr;
+        // the PPU's address bus is 14 bits, the top bit of v goes nowhere
+        let v_addr = self.vram_addr & 0x3FFF;
         // println!("write PPUDATA {:#x} at virtual addr {:#X}", data, self.vram_addr);
         match v_addr {
-            0x0000...0x1FFF => self.chr.write_u8(v_addr, data),
-            0x2000...0x2FFF => {
-                // if v_addr > 0x3000 {
-                //    v_addr -= 0x1000;
-                // }
-                let mut offset:u16 = 0;
-                if self.chr.vertical_mirroring && v_addr >= 0x2800 {
-                    offset = 0x800;
-                } else if self.chr.horizontal_mirroring &&
-                    (v_addr >= 0x2400 && v_addr < 0x2800) ||
-                    (v_addr >= 0x2C00 && v_addr < 0x3000)
-                {
-                    offset = 0x400;
-                }
-
-                let realaddr = (v_addr - 0x2000) - offset;
-                self.vram[realaddr as usize] = data;
-                // if realaddr =
+            0x0000...0x1FFF => self.mapper.borrow_mut().ppu_write(v_addr, data),
+            0x2000...0x3EFF => {
+                let realaddr = self.nametable_addr(v_addr);
+                self.vram[realaddr] = data;
          //       println!("Writing PPURAM {:#X} at {:#X} = {:#X}", data, v_addr, realaddr);
             }
-            0x3000...0x3EFF => panic!("Need mirrors of 0x2000-0x2EFF"),
-            0x3F00...0x3FFF => {
-                let mut realaddr = (self.vram_addr - 0x3F00) % 0x20;
-                if realaddr == 0x10 || realaddr == 0x14 || realaddr == 0x18 || realaddr == 0x1C {
-                    realaddr -= 0x10;
-                }
+            _ => {
                 // println!("Writing palette data {:#x} at {:#x} ({:#X})", data, realaddr, self.ppu_addr);
-                self.palette[realaddr as usize] = data;
+                self.palette[palette_addr(v_addr)] = data;
             }
-            _ => panic!("need mirrors of all vram")
-        }
-        if !self.vram_increment {
-            self.vram_addr += 1;
-        } else {
-            // not sure of this...
-            self.vram_addr += 32;
         }
+        self.increment_vram_addr();
     }
 
     fn read_data(&self, addr: u16) -> u8 {
 //        println!("read from {:#X}", addr);
+        let addr = addr & 0x3FFF;
         match addr {
-            0x0000...0x1FFF => self.chr.read_u8(addr),
-            0x2000...0x2FFF => {
-                //if addr > 0x3000 {
-                //    addr -= 0x1000;
-                //  }
-                let mut offset:u16 = 0;
-                if self.chr.vertical_mirroring && addr >= 0x2800 {
-                    offset = 0x800;
-                } else if self.chr.horizontal_mirroring &&
-                    (addr >= 0x2400 && addr < 0x2800) ||
-                    (addr >= 0x2C00 && addr < 0x3000)
-                {
-                    offset = 0x400;
-                }
+            0x0000...0x1FFF => self.mapper.borrow().ppu_read(addr),
+            0x2000...0x3EFF => self.vram[self.nametable_addr(addr)],
+            _ => self.palette[palette_addr(addr)],
+        }
+    }
 
-                let realaddr = (addr - 0x2000) - offset;
-                self.vram[realaddr as usize]
-            }
-            0x3000...0x3EFF => panic!("Need mirrors of 0x2000-0x2EFF"),
-            0x3F00...0x3FFF => {
-                let mut realaddr = (addr - 0x3F00) % 0x20;
-                if realaddr == 0x10 || realaddr == 0x14 || realaddr == 0x18 || realaddr == 0x1C {
-                    realaddr -= 0x10;
-                }
-                self.palette[realaddr as usize]
-            }
-            _ => panic!("need mirrors of all vram")
+    // Maps one of the four logical nametables onto the vram we have, as
+    // the cartridge wires it up. $3000-$3EFF mirrors $2000-$2EFF.
+    fn nametable_addr(&self, addr: u16) -> usize {
+        let table = ((addr & 0x0FFF) / 0x400) as usize;
+        let offset = (addr & 0x3FF) as usize;
+        let page = self.mapper.borrow().nametable_page(table) & 3;
+        page * 0x400 + offset
+    }
 
-        }
+    fn increment_vram_addr(&mut self) {
+        let step = if self.vram_increment { 32 } else { 1 };
+        self.vram_addr = (self.vram_addr + step) & 0x7FFF;
     }
 
     pub fn read_ppudata(&mut self) -> u8 {
-        let tmp = self.vram_addr;
+        let tmp = self.vram_addr & 0x3FFF;
         let data = self.read_data(tmp);
 
         // println!("Read PPUDATA {:#X} from {:#X}", data, self.vram_addr);
 
-        if !self.vram_increment {
-            self.vram_addr += 1;
-        } else {
-            // not sure of this...
-            self.vram_addr += 32;
-        }
+        self.increment_vram_addr();
 
-        if tmp >= 0x3F00 && tmp <= 0x3FFF {
+        if tmp >= 0x3F00 {
             // println!("palette read {:#X} from {:#X}", data, tmp);
             self.ppudata_buffer = self.read_data(tmp - 0x1000);
             data
@@ -367,6 +551,7 @@ impl PPU {
                 if self.scanline == -1 {
                     self.nmi_generated = false;
                     self.sprite0_hit = false;
+                    self.sprite_overflow = false;
                     self.vblank  = false;
                     if self.initial_reset {self.initial_reset = false};
                 }
@@ -375,46 +560,22 @@ impl PPU {
                 }
             }
 
-            if self.scanline >= 0 && self.scanline < 240 {
-                if self.cycles == 0 && self.show_bg {
-                    let bgcolor = PALETTE[self.palette[0] as usize % 64];
-                    self.screen[self.scanline as usize] = [bgcolor; 256];
-                    if !self.sprite0_hit && self.show_bg && self.show_sprites {
-                        self.sprite0_bg_prerender = [0; 256];
-                    }
+            if self.scanline < 240 {
+                if self.show_bg || self.show_sprites {
+                    self.fetch_bg();
+                    self.fetch_sprites();
                 }
-
-
-                if self.cycles <= 256 && self.cycles % 8 == 0 && self.show_bg {
-                    self.render_8pxbg();
-                    self.render_sprites();
-                    self.bg_column += 1;
-                }
-
-                if self.cycles == 257 && (self.show_bg || self.show_sprites)
-                {
-                    self.increment_y();
-                    // println!("reset X");
-                    // copy horizontal bits from t to v
-                    self.vram_addr &= 0x7BE0;
-                    self.vram_addr |= self.t_vram_addr & !0x7BE0;
-                    self.bg_column = 0;
+                if self.scanline >= 0 && self.cycles >= 1 && self.cycles <= 256 {
+                    self.output_pixel();
                 }
             }
 
-
-            if self.scanline == -1 && (self.show_bg || self.show_sprites)
-                && (self.cycles >= 280 && self.cycles <= 304)
-            {
-                self.vram_addr &= 0x041F;
-                self.vram_addr |= self.t_vram_addr & !0x041F;
-
-            }
-
-            if self.scanline == -1 && (self.show_bg || self.show_sprites) && self.cycles == 340 {
-                    self.extra_cycle = self.framecount % 2 == 1;
-                    if self.extra_cycle {
-                        self.cycles = 0;
+            if self.scanline == -1 && self.cycles == 339 {
+                    // odd frames skip the last dot of the pre-render line
+                    // while rendering
+                    let odd = self.framecount % 2 == 1;
+                    if odd && (self.show_bg || self.show_sprites) {
+                        self.cycles = 340;
                     }
                     self.framecount += 1;
                     // println!("Frame# {}", self.framecount);
@@ -431,142 +592,303 @@ impl PPU {
 
     }
 
-    fn render_8pxbg(&mut self) {
-        // TODO: use left 8px setting
-        /*
-        read the nametable at the base nametable address to lookup the tile
-        read the attribute table after that to lookup the palette
-        read the looked-up tile data which is stored as a bit-pair of bytes
-        calculate the actual pixel colours from the bit-pair
-        then use the palette data to assign each pixel a colour based on the calculation
-        */
-        // using the PPU's actual functions for this should be correct.
-
-        let coarsex = self.vram_addr & 0x1F;
-        let coarsey = (self.vram_addr >> 5) & 0x1F;
-        // let col = coarsex;
-        // let sl = ((coarsey << 3) | self.vram_addr >> 12) as i16;
-        // println!("sl {} and sl-y {} coarsex {}", self.scanline, sl, coarsex);
-        let sl = self.scanline;
-        let att_tbl_addr = 0x23C0 |
-                            (self.vram_addr & 0x0C00) |
-                            ((self.vram_addr >> 4) & 0x38) |
-                            ((self.vram_addr >> 2) & 0x07);
-
-        let attr_table = &self.read_data(att_tbl_addr);
-        // println!("Attr table is {:#X} read from {:#X}", attr_table, att_tbl_addr);
-        let attr:usize;
-        if (coarsey % 4) < 2 {
-            if (coarsex % 4) < 2 {
-                attr = ((attr_table & 0b0000_0011) >> 0) as usize;
-            } else {
-                attr = ((attr_table & 0b0000_1100) >> 2) as usize;
-            }
-        } else {
-            if (coarsex % 4) < 2 {
-                attr = ((attr_table & 0b0011_0000) >> 4) as usize;
-            } else {
-                attr = ((attr_table & 0b1100_0000) >> 6) as usize;
+    // The background fetches for the current dot of a rendering line,
+    // including the pre-render line which fetches the first two tiles of
+    // line 0. Each tile takes eight dots: nametable, attribute, then the
+    // two pattern bytes, then coarse X moves on to the next tile.
+    //
+    // Mappers watching A12 hear about every pattern fetch, here and for
+    // sprites. The nametable fetches in between drop A12 for a moment too,
+    // which the MMC3 filters out and we don't, so they're left out.
+    fn fetch_bg(&mut self) {
+        let dot = self.cycles;
+
+        if (dot >= 2 && dot <= 257) || (dot >= 322 && dot <= 337) {
+            self.shift_bg();
+            if dot % 8 == 1 {
+                self.load_bg();
             }
         }
 
-        let nametable_addr = 0x2000 | (self.vram_addr & 0x0FFF);
-        let mut tile_addr = self.read_data(nametable_addr) as u16 * 16;
-        if self.bg_table_high {
-            tile_addr += 0x1000
-        }
-        let offset = self.vram_addr >> 12;
-        let tile_data1 = self.read_data(tile_addr + offset);
-        let tile_data2 = self.read_data(tile_addr + 8 + offset);
-
-        // let start = time::precise_time_ns();
-        for mut px in 0..8 {
-            let pixel_x = (self.bg_column * 8) - self.fine_x as isize + px as isize;
-        //    println!("PixelX {}", pixel_x);
-            if pixel_x <= 255 && pixel_x >= 0 {
-                px = 7 - px;
-                let pv = ((tile_data2 & (1 << px)) >> px) << 1 | (tile_data1 & (1 << px)) >> px;
-                if pv > 0 {
-                    let pixel = PALETTE[self.palette[pv as usize + (attr as usize * 4)] as usize % 64];
-                    self.screen[sl as usize][pixel_x as usize] = pixel;
-
-                    if sl >= self.oam[0] as i16 + 1 && sl <= self.oam[0] as i16 + 8 {
-                        self.sprite0_bg_prerender[pixel_x as usize] = pv;
-                    }
+        if (dot >= 1 && dot <= 256) || (dot >= 321 && dot <= 336) {
+            let v = self.vram_addr;
+            let pattern_addr = self.next_tile as u16 * 16 + (v >> 12) +
+                               if self.bg_table_high { 0x1000 } else { 0 };
+            match dot % 8 {
+                1 => self.next_tile = self.read_data(0x2000 | (v & 0x0FFF)),
+                3 => {
+                    let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    // which quadrant of the attribute byte's 4x4 tiles
+                    let shift = ((v >> 4) & 4) | (v & 2);
+                    self.next_attr = (self.read_data(addr) >> shift) & 0b11;
                 }
+                5 => {
+                    self.mapper.borrow_mut().notify_ppu_addr(pattern_addr);
+                    self.next_pattern_lo = self.read_data(pattern_addr);
+                }
+                7 => self.next_pattern_hi = self.read_data(pattern_addr + 8),
+                0 => self.increment_x(),
+                _ => (),
             }
         }
-        // let end = time::precise_time_ns();
-        self.increment_x();
+
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            // copy horizontal bits from t to v
+            self.vram_addr &= 0x7BE0;
+            self.vram_addr |= self.t_vram_addr & !0x7BE0;
+        }
+        if self.scanline == -1 && dot >= 280 && dot <= 304 {
+            // and the vertical ones, ready for the next frame
+            self.vram_addr &= 0x041F;
+            self.vram_addr |= self.t_vram_addr & !0x041F;
+        }
     }
 
-    fn render_sprites(&mut self) {
-        // TODO: deal with more than 8 sprites on a scanline
-        // TODO: 8x16 sprites
-        let sl = self.scanline;
+    fn shift_bg(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attr_lo <<= 1;
+        self.bg_attr_hi <<= 1;
+    }
 
-        for mut sprite in 0..64 {
-            sprite = 63 - sprite;
+    fn load_bg(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.next_pattern_lo as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.next_pattern_hi as u16;
+        let attr = self.next_attr;
+        let spread = |bit: u8| if attr & bit != 0 { 0xFF } else { 0 };
+        self.bg_attr_lo = (self.bg_attr_lo & 0xFF00) | spread(1);
+        self.bg_attr_hi = (self.bg_attr_hi & 0xFF00) | spread(2);
+    }
 
-            let y = self.oam[sprite * 4] as i16 + 1;
-            // println!("sprite {:} is at {:}", sprite, y);
-            if sl >= y && sl <= y + 7 {
-                let mut index = self.oam[(sprite * 4) + 1] as u16 * 16;
-                if self.sprite_table_high {
-                    index += 0x1000;
+    // Puts out the pixel for the current dot, fine X picking which bit of
+    // the shift registers is on screen.
+    fn output_pixel(&mut self) {
+        let x = (self.cycles - 1) as usize;
+        let sl = self.scanline as usize;
+
+        // PPUMASK can hide either layer in the leftmost 8 pixels, which
+        // games use to cover scrolling seams
+        let show_bg = self.show_bg && (x >= 8 || self.bg_left_8px);
+        let show_sprites = self.show_sprites && (x >= 8 || self.sprite_left_8px);
+
+        let mut pv = 0;
+        let mut attr = 0;
+        if show_bg {
+            let bit = 15 - self.fine_x as u16;
+            pv = ((self.bg_pattern_hi >> bit) & 1) << 1 | ((self.bg_pattern_lo >> bit) & 1);
+            attr = ((self.bg_attr_hi >> bit) & 1) << 1 | ((self.bg_attr_lo >> bit) & 1);
+        }
+        // Priority and sprite 0 hits go by whether the background pixel is
+        // opaque, not by its colour, as colour 0 of every palette shows
+        // the backdrop but any of them might be set to the same colour.
+        let bg_opaque = pv != 0;
+        let mut entry = if bg_opaque { attr * 4 + pv } else { 0 };
+
+        if show_sprites {
+            // Sprite 0 hits wherever both are opaque, even under another
+            // sprite, except at x=255.
+            if self.sprite0_in_slots && !self.sprite0_hit && x < 255 && bg_opaque &&
+               sprite_pixel(&self.sprite_slots[0], x) != 0 {
+                self.sprite0_hit = true;
+            }
+
+            // The first sprite with something here wins, and then either
+            // shows or lets the background through. A sprite behind the
+            // background still hides the sprites after it.
+            let found = self.sprite_slots
+                .iter()
+                .map(|slot| (slot, sprite_pixel(slot, x)))
+                .find(|&(_, spv)| spv != 0);
+            if let Some((slot, spv)) = found {
+                let behind = slot.attr & 0x20 != 0;
+                if !(behind && bg_opaque) {
+                    entry = 0x10 + (slot.attr as u16 & 0b11) * 4 + spv as u16;
                 }
-                let pal = 0x11 + (self.oam[(sprite * 4) + 2] & 0b11) * 4;
+            }
+        }
+        self.screen[sl][x] = PALETTE[self.palette[entry as usize] as usize % 64];
+    }
 
-                let background = (self.oam[(sprite * 4) + 2] & 0x20) != 0;
+    // Sprite evaluation and fetches for the current dot of a rendering
+    // line. Evaluation looks for sprites on the next line, which is why
+    // they show up a line below their Y.
+    fn fetch_sprites(&mut self) {
+        let dot = self.cycles;
+        let visible = self.scanline >= 0;
 
-                let flip_h = (self.oam[(sprite * 4) + 2] & (1 << 6)) != 0;
-                let flip_v = (self.oam[(sprite * 4) + 2] & (1 << 7)) != 0;
-                let x = self.oam[(sprite * 4) + 3];
-                // println!("Sprite {:} is {:?} on {:},{:}", sprite, bg, y, x);
+        if visible && dot >= 1 && dot <= 64 && dot % 2 == 0 {
+            self.secondary_oam[(dot / 2 - 1) as usize] = 0xFF;
+        }
+        if visible && dot == 65 {
+            self.eval_n = 0;
+            self.eval_m = 0;
+            self.eval_count = 0;
+            self.eval_done = false;
+            self.eval_sprite0 = false;
+        }
+        if visible && dot >= 65 && dot <= 256 {
+            if dot % 2 == 1 {
+                self.eval_latch = self.oam[self.eval_n as usize % 64 * 4 + self.eval_m as usize];
+            } else {
+                self.evaluate_sprite();
+            }
+        }
+        if visible && dot == 256 {
+            self.find_extra_sprites();
+        }
 
-                let offset = if flip_v {
-                    7 - (sl - y)
-                } else {
-                    sl - y
-                };
-                //println!("offset {}", offset);
-
-                let sprite_data1 = self.read_data(index + offset as u16);
-                let sprite_data2 = self.read_data(index + 8 + offset as u16);
-
-                let bgcolor = self.palette[0] as usize % 64;
-                for px in 0..8 {
-                    let pv:u8;
-                    if flip_h {
-                        pv = ((sprite_data2 & (1 << px)) >> px) << 1 | (sprite_data1 & (1 << px)) >> px;
-                    } else {
-                        pv = ((sprite_data2 & (1 << 7 - px)) >> 7 - px) << 1 | (sprite_data1 & (1 << 7 - px)) >> 7 - px;
+        if dot >= 257 && dot <= 320 {
+            self.oam_addr = 0;
+            if dot == 257 {
+                self.sprite_slots.clear();
+                self.sprite0_in_slots = visible && self.eval_sprite0;
+            }
+            let slot = (dot - 257) / 8;
+            if (dot - 257) % 8 == 0 {
+                // all eight slots are fetched, the empty ones and everything
+                // on the pre-render line as tile $FF
+                let mut sprite = [0xFF; 4];
+                let used = visible && slot < self.eval_count as isize;
+                if used {
+                    let base = slot as usize * 4;
+                    sprite.copy_from_slice(&self.secondary_oam[base..base + 4]);
+                }
+                let addr = self.sprite_pattern_addr(sprite);
+                self.mapper.borrow_mut().notify_ppu_addr(addr);
+                if used {
+                    self.fetch_sprite(sprite);
+                }
+                if visible && slot == 7 {
+                    let extra = self.extra_sprites.clone();
+                    for &sprite in extra.iter() {
+                        self.fetch_sprite(sprite);
                     }
+                }
+            }
+        }
+    }
 
-                    if (x as usize + px as usize <= 255) && pv > 0 {
-                        // TODO: This is actually wrong and shouldn't render on a 0 background value
-                        // regardless of colour (palette colour could be the same as bgcolor)
-                        // I would need the pre-render bg line to determine that
-                        let bgpixel = self.screen[sl as usize][x as usize + px as usize];
-                        let pixel = if background && bgpixel != PALETTE[bgcolor] {
-                            bgpixel
-                        } else {
-                            let plt = self.palette[pal as usize + (pv - 1) as usize] as usize;
-                            PALETTE[plt % 64]
-                        };
-                        self.screen[sl as usize][x as usize + px as usize] = pixel;
-                        if sprite == 0 && !self.sprite0_hit && (x as usize + px as usize) < 255 &&
-                             self.sprite0_bg_prerender[x as usize + px as usize] != 0
-                        {
-                                self.sprite0_hit = true;
-
-                        }
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let row = self.scanline - y as i16;
+        row >= 0 && row < self.sprite_height()
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.sprite_8x16 { 16 } else { 8 }
+    }
+
+    // One step of evaluation, on the even dots after the odd ones read
+    // OAM. Up to eight sprites are copied, after which the PPU carries on
+    // looking for a ninth to set the overflow flag, but a hardware bug
+    // moves on to the next byte of each sprite along with the next sprite,
+    // so it checks tile numbers and attributes as if they were Y.
+    fn evaluate_sprite(&mut self) {
+        if self.eval_done {
+            return;
+        }
+        let value = self.eval_latch;
+
+        if self.eval_count < 8 {
+            self.secondary_oam[self.eval_count as usize * 4 + self.eval_m as usize] = value;
+            if self.eval_m == 0 {
+                if self.sprite_in_range(value) {
+                    self.eval_m = 1;
+                    if self.eval_n == 0 {
+                        self.eval_sprite0 = true;
                     }
+                } else {
+                    self.next_eval_sprite();
+                }
+            } else {
+                self.eval_m += 1;
+                if self.eval_m == 4 {
+                    self.eval_m = 0;
+                    self.eval_count += 1;
+                    self.next_eval_sprite();
+                }
+            }
+        } else if self.sprite_in_range(value) {
+            self.sprite_overflow = true;
+            self.eval_done = true;
+        } else {
+            self.eval_m = (self.eval_m + 1) & 3;
+            self.next_eval_sprite();
+        }
+    }
+
+    fn next_eval_sprite(&mut self) {
+        self.eval_n += 1;
+        if self.eval_n == 64 {
+            self.eval_done = true;
+        }
+    }
+
+    // Everything on the next line past the eight the hardware found.
+    fn find_extra_sprites(&mut self) {
+        self.extra_sprites.clear();
+        if !self.no_sprite_limit || self.eval_count < 8 {
+            return;
+        }
+        let mut found = 0;
+        for sprite in 0..64 {
+            let base = sprite * 4;
+            if self.sprite_in_range(self.oam[base]) {
+                found += 1;
+                if found > 8 {
+                    let mut entry = [0; 4];
+                    entry.copy_from_slice(&self.oam[base..base + 4]);
+                    self.extra_sprites.push(entry);
                 }
             }
         }
     }
 
+    // Where the low pattern byte of a sprite's row on the next line is,
+    // for a Y, tile, attributes, X entry from secondary OAM.
+    fn sprite_pattern_addr(&self, sprite: [u8; 4]) -> u16 {
+        let height = self.sprite_height() as u16;
+        let row = (self.scanline - sprite[0] as i16) as u16 % height;
+        let flip_v = sprite[2] & (1 << 7) != 0;
+        // flipping an 8x16 sprite swaps its halves as well
+        let row = if flip_v { height - 1 - row } else { row };
+
+        let tile = sprite[1] as u16;
+        let index = if self.sprite_8x16 {
+            // the tile's low bit picks the pattern table, the top half is
+            // the even tile and the bottom half the one after
+            let table = if tile & 1 != 0 { 0x1000 } else { 0 };
+            table + (tile & 0xFE) * 16 + if row >= 8 { 16 } else { 0 }
+        } else if self.sprite_table_high {
+            0x1000 + tile * 16
+        } else {
+            tile * 16
+        };
+        index + (row & 7)
+    }
+
+    // Fetches the row of a sprite that's on the next line.
+    fn fetch_sprite(&mut self, sprite: [u8; 4]) {
+        let attr = sprite[2];
+        let flip_h = attr & (1 << 6) != 0;
+        let addr = self.sprite_pattern_addr(sprite);
+        let mut pattern_lo = self.read_data(addr);
+        let mut pattern_hi = self.read_data(addr + 8);
+        if flip_h {
+            pattern_lo = reverse_bits(pattern_lo);
+            pattern_hi = reverse_bits(pattern_hi);
+        }
+
+        self.sprite_slots.push(SpriteSlot {
+            x: sprite[3],
+            attr: attr,
+            pattern_lo: pattern_lo,
+            pattern_hi: pattern_hi,
+        });
+    }
+
 
     fn increment_y(&mut self) {
         // y increment V....
@@ -599,3 +921,104 @@ impl PPU {
     }
 
 }
+
+// The sprite's colour at screen column x, 0 if it's transparent or not there.
+fn sprite_pixel(slot: &SpriteSlot, x: usize) -> u8 {
+    let column = x.wrapping_sub(slot.x as usize);
+    if column >= 8 {
+        return 0;
+    }
+    let bit = 7 - column;
+    ((slot.pattern_hi >> bit) & 1) << 1 | ((slot.pattern_lo >> bit) & 1)
+}
+
+fn reverse_bits(mut value: u8) -> u8 {
+    let mut reversed = 0;
+    for _ in 0..8 {
+        reversed = reversed << 1 | (value & 1);
+        value >>= 1;
+    }
+    reversed
+}
+
+// Palette RAM is 32 bytes mirrored up to $3FFF, with the sprite palettes'
+// colour 0 entries shared with the background's.
+fn palette_addr(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    if index & 0x13 == 0x10 { index - 0x10 } else { index }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use mapper::{Mapper, Mirroring};
+    use state::{StateError, StateReader, StateWriter};
+
+    // Keeps every address the PPU tells the cartridge about.
+    struct A12Board {
+        notified: Rc<RefCell<Vec<u16>>>,
+    }
+
+    impl Mapper for A12Board {
+        fn cpu_read(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn cpu_write(&mut self, _addr: u16, _value: u8) {}
+
+        fn ppu_read(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _value: u8) {}
+
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+
+        fn notify_ppu_addr(&mut self, addr: u16) {
+            self.notified.borrow_mut().push(addr);
+        }
+
+        fn save_state(&self, _out: &mut StateWriter) {}
+
+        fn load_state(&mut self, _input: &mut StateReader) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_sprite_fetch_reaches_the_mapper() {
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        let board = A12Board { notified: notified.clone() };
+        let mut ppu = PPU::new(Rc::new(RefCell::new(Box::new(board) as Box<Mapper>)));
+        // 8x16 sprites from both tables, background from $0000
+        ppu.write_ppuctrl(0x20);
+        ppu.write_ppumask(0x18);
+        for i in 0..256 {
+            ppu.oam[i] = 0xFF;
+        }
+        for (i, &tile) in [0x01, 0x02, 0x03].iter().enumerate() {
+            ppu.oam[i * 4] = 0;
+            ppu.oam[i * 4 + 1] = tile;
+            ppu.oam[i * 4 + 2] = 0;
+        }
+
+        while !(ppu.scanline == 0 && ppu.cycles == 256) {
+            ppu.tick(1);
+        }
+        notified.borrow_mut().clear();
+        ppu.tick(64);
+
+        let notified = notified.borrow();
+        assert_eq!(notified.len(), 8);
+        assert_eq!(&notified[..3], &[0x1000, 0x0020, 0x1020]);
+        // the empty slots fetch tile $FF, from $1000 with 8x16 sprites
+        for &addr in &notified[3..] {
+            assert!(addr & 0x1000 != 0, "{:#06X}", addr);
+        }
+    }
+}
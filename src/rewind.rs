@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use Nes;
+use state::StateError;
+
+// Every this many snapshots the next one becomes a keyframe. The further
+// a state gets from its keyframe the less the two have in common, so this
+// trades keyframe size against delta size.
+const KEYFRAME_INTERVAL: usize = 60;
+
+// A save state stored as its difference from an earlier full one. Deltas
+// share their keyframe, which goes away with the last snapshot using it.
+struct Snapshot {
+    keyframe: Rc<Vec<u8>>,
+    delta: Vec<u8>,
+}
+
+// The last few seconds of play as save states, for stepping backwards.
+// Snapshots are taken every interval frames and the oldest dropped once
+// there's more than the history asked for.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    frames: u32,
+
+    snapshots: VecDeque<Snapshot>,
+    keyframe: Option<Rc<Vec<u8>>>,
+    since_keyframe: usize,
+}
+
+impl Rewind {
+    // seconds of history, with a snapshot every interval frames
+    pub fn new(seconds: u32, interval: u32) -> Rewind {
+        let interval = if interval == 0 { 1 } else { interval };
+        let capacity = (seconds * 60 / interval) as usize;
+        Rewind {
+            interval: interval,
+            capacity: if capacity == 0 { 1 } else { capacity },
+            frames: 0,
+
+            snapshots: VecDeque::new(),
+            keyframe: None,
+            since_keyframe: 0,
+        }
+    }
+
+    // Call once a frame while playing forwards.
+    pub fn push(&mut self, nes: &Nes) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = nes.save_state();
+        let keyframe = match self.keyframe {
+            Some(ref keyframe) if self.since_keyframe < KEYFRAME_INTERVAL &&
+                                  keyframe.len() == state.len() => keyframe.clone(),
+            _ => {
+                self.since_keyframe = 0;
+                Rc::new(state.clone())
+            }
+        };
+        self.since_keyframe += 1;
+        self.keyframe = Some(keyframe.clone());
+
+        let delta = compress(&keyframe, &state);
+        self.snapshots.push_back(Snapshot {
+            keyframe: keyframe,
+            delta: delta,
+        });
+        if self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Puts the console back to the latest snapshot and forgets it. Returns
+    // false once there's no history left. If the snapshot won't load the
+    // console carries on as it was and the history is dropped.
+    pub fn step_back(&mut self, nes: &mut Nes) -> Result<bool, StateError> {
+        let snapshot = match self.snapshots.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return Ok(false),
+        };
+        self.frames = 0;
+        // the next snapshot starts a fresh keyframe rather than leaning on
+        // one from further back
+        self.keyframe = None;
+
+        let state = decompress(&snapshot.keyframe, &snapshot.delta);
+        if let Err(e) = nes.load_state(&state) {
+            self.snapshots.clear();
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    // Bytes held, keyframes counted once however many snapshots share them.
+    pub fn memory_used(&self) -> usize {
+        let mut total = 0;
+        let mut last: Option<&Rc<Vec<u8>>> = None;
+        for snapshot in self.snapshots.iter() {
+            if last.map_or(true, |k| !Rc::ptr_eq(k, &snapshot.keyframe)) {
+                total += snapshot.keyframe.len();
+            }
+            last = Some(&snapshot.keyframe);
+            total += snapshot.delta.len();
+        }
+        total
+    }
+}
+
+// The state XORed with the keyframe, which leaves mostly zeros, run length
+// encoded as pairs of a zero run and a literal run followed by the literal
+// bytes. Both runs are varints.
+fn compress(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < state.len() {
+        let zeros_start = pos;
+        while pos < state.len() && state[pos] == keyframe[pos] {
+            pos += 1;
+        }
+        let literal_start = pos;
+        // a lone matching byte is cheaper kept in the literal than ending it
+        while pos < state.len() &&
+              (state[pos] != keyframe[pos] ||
+               pos + 1 < state.len() && state[pos + 1] != keyframe[pos + 1]) {
+            pos += 1;
+        }
+
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, pos - literal_start);
+        for i in literal_start..pos {
+            out.push(state[i] ^ keyframe[i]);
+        }
+    }
+    out
+}
+
+fn decompress(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = keyframe.to_vec();
+    let mut pos = 0;
+    let mut input = delta.iter().cloned();
+    while let Some(zeros) = read_varint(&mut input) {
+        pos += zeros;
+        let literal = read_varint(&mut input).unwrap_or(0);
+        for byte in state[pos..pos + literal].iter_mut() {
+            *byte ^= input.next().unwrap_or(0);
+        }
+        pos += literal;
+    }
+    state
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint<I: Iterator<Item = u8>>(input: &mut I) -> Option<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match input.next() {
+            Some(byte) => byte,
+            None => return None,
+        };
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, read_varint, write_varint};
+
+    fn round_trip(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+        let delta = compress(keyframe, state);
+        assert_eq!(decompress(keyframe, &delta), state);
+        delta
+    }
+
+    #[test]
+    fn identical_state_is_tiny() {
+        let keyframe: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let delta = round_trip(&keyframe, &keyframe);
+        assert!(delta.len() <= 4);
+    }
+
+    #[test]
+    fn scattered_changes() {
+        let keyframe: Vec<u8> = (0..5000).map(|i| (i * 7) as u8).collect();
+        let mut state = keyframe.clone();
+        for &i in [0, 1, 3, 200, 201, 4998, 4999].iter() {
+            state[i] ^= 0x5A;
+        }
+        let delta = round_trip(&keyframe, &state);
+        assert!(delta.len() < 32);
+    }
+
+    #[test]
+    fn everything_changed() {
+        let keyframe = vec![0; 300];
+        let state: Vec<u8> = (0..300).map(|i| i as u8 | 1).collect();
+        round_trip(&keyframe, &state);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(round_trip(&[], &[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn varints() {
+        let values = [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 30];
+        let mut out = Vec::new();
+        for &value in values.iter() {
+            write_varint(&mut out, value);
+        }
+        let mut input = out.into_iter();
+        for &value in values.iter() {
+            assert_eq!(read_varint(&mut input), Some(value));
+        }
+        assert_eq!(read_varint(&mut input), None);
+    }
+}
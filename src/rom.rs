@@ -0,0 +1,537 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+use mapper::Mirroring;
+
+pub const HEADER_LEN: usize = 0x10;
+pub const TRAINER_LEN: usize = 0x200;
+const MAGIC: &'static [u8] = b"NES\x1A";
+
+const PRG_ROM_UNIT: usize = 0x4000;
+const CHR_ROM_UNIT: usize = 0x2000;
+const PRG_RAM_UNIT: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomFormat {
+    INes,
+    Nes20,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended,
+}
+
+// Everything the 16 byte header says about the cartridge. Sizes are in bytes.
+// http://wiki.nesdev.com/w/index.php/NES_2.0
+#[derive(Debug, Clone)]
+pub struct RomHeader {
+    pub format: RomFormat,
+
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+
+    pub mapper: u16,
+    pub submapper: u8,
+
+    pub vertical_mirroring: bool,
+    pub four_screen_vram: bool,
+    pub battery: bool,
+    pub trainer: bool,
+
+    pub timing: Timing,
+    pub console: ConsoleType,
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    BadMagic,
+    // the header promises more PRG/CHR data than the file holds
+    Truncated { expected: usize, actual: usize },
+    NoPrgRom,
+    // less PRG ROM than one of the mapper's banks, which it can't map
+    PrgRomTooSmall { size: usize, min: usize },
+    UnsupportedMapper(u16),
+}
+
+// A ROM file split into its parts.
+pub struct Rom {
+    pub header: RomHeader,
+    pub trainer: Option<Box<[u8]>>,
+    pub prg_rom: Box<[u8]>,
+    pub chr_rom: Box<[u8]>,
+}
+
+impl RomHeader {
+    pub fn parse(data: &[u8]) -> Result<RomHeader, RomError> {
+        if data.len() < HEADER_LEN {
+            return Err(RomError::Truncated { expected: HEADER_LEN, actual: data.len() });
+        }
+        if &data[0..4] != MAGIC {
+            return Err(RomError::BadMagic);
+        }
+
+        let flags6 = data[6];
+        let flags7 = data[7];
+
+        let mut header = RomHeader {
+            format: RomFormat::INes,
+
+            prg_rom_size: data[4] as usize * PRG_ROM_UNIT,
+            chr_rom_size: data[5] as usize * CHR_ROM_UNIT,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+
+            mapper: (flags6 >> 4) as u16,
+            submapper: 0,
+
+            vertical_mirroring: flags6 & (1 << 0) != 0,
+            four_screen_vram: flags6 & (1 << 3) != 0,
+            battery: flags6 & (1 << 1) != 0,
+            trainer: flags6 & (1 << 2) != 0,
+
+            timing: Timing::Ntsc,
+            console: ConsoleType::Nes,
+        };
+
+        if flags7 & 0x0C == 0x08 {
+            header.parse_nes20(data);
+        } else {
+            header.parse_ines(data);
+        }
+
+        if header.prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+        Ok(header)
+    }
+
+    fn parse_ines(&mut self, data: &[u8]) {
+        // Old dumping tools left junk like "DiskDude!" in bytes 7-15, in
+        // which case the upper mapper nibble can't be trusted either.
+        let junk = data[12..16].iter().any(|&b| b != 0);
+        if !junk {
+            self.mapper |= (data[7] & 0xF0) as u16;
+            if data[9] & 1 != 0 {
+                self.timing = Timing::Pal;
+            }
+        }
+
+        // iNES 1.0 can't tell RAM from NVRAM, and 0 chunks means one
+        let chunks = if data[8] == 0 || junk { 1 } else { data[8] as usize };
+        if self.battery {
+            self.prg_nvram_size = chunks * PRG_RAM_UNIT;
+        } else {
+            self.prg_ram_size = chunks * PRG_RAM_UNIT;
+        }
+
+        if self.chr_rom_size == 0 {
+            self.chr_ram_size = CHR_ROM_UNIT;
+        }
+    }
+
+    fn parse_nes20(&mut self, data: &[u8]) {
+        self.format = RomFormat::Nes20;
+
+        self.mapper |= (data[7] & 0xF0) as u16 | ((data[8] & 0x0F) as u16) << 8;
+        self.submapper = data[8] >> 4;
+
+        self.prg_rom_size = nes20_rom_size(data[4], data[9] & 0x0F, PRG_ROM_UNIT);
+        self.chr_rom_size = nes20_rom_size(data[5], data[9] >> 4, CHR_ROM_UNIT);
+
+        self.prg_ram_size = shift_size(data[10] & 0x0F);
+        self.prg_nvram_size = shift_size(data[10] >> 4);
+        self.chr_ram_size = shift_size(data[11] & 0x0F);
+        self.chr_nvram_size = shift_size(data[11] >> 4);
+
+        self.timing = match data[12] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        };
+
+        self.console = match data[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended,
+        };
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.four_screen_vram {
+            Mirroring::FourScreen
+        } else if self.vertical_mirroring {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    // Bytes of ROM data the header says follow it, trainer included.
+    pub fn data_len(&self) -> usize {
+        let trainer = if self.trainer { TRAINER_LEN } else { 0 };
+        trainer.saturating_add(self.prg_rom_size).saturating_add(self.chr_rom_size)
+    }
+}
+
+// NES 2.0 sizes: the LSB from bytes 4/5 and an MSB nibble from byte 9. An MSB
+// of $F switches to 2^E * (MM*2+1) bytes, with E and MM packed in the LSB.
+fn nes20_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        // absurd sizes saturate and get caught by the length check
+        (1usize << exponent).checked_mul(multiplier).unwrap_or(usize::max_value())
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+// RAM sizes are stored as a shift count, 64 << n bytes, with 0 meaning none.
+fn shift_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+impl Rom {
+    pub fn load(rompath: &String) -> Result<Rom, RomError> {
+        let mut rom_file = try!(File::open(rompath));
+        let mut data = Vec::new();
+        try!(rom_file.read_to_end(&mut data));
+        Rom::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Rom, RomError> {
+        let header = try!(RomHeader::parse(data));
+
+        let expected = header.data_len().saturating_add(HEADER_LEN);
+        if data.len() < expected {
+            return Err(RomError::Truncated { expected: expected, actual: data.len() });
+        }
+
+        let mut pos = HEADER_LEN;
+        let trainer = if header.trainer {
+            pos += TRAINER_LEN;
+            Some(copy_slice(&data[pos - TRAINER_LEN..pos]))
+        } else {
+            None
+        };
+
+        let prg_rom = copy_slice(&data[pos..pos + header.prg_rom_size]);
+        pos += header.prg_rom_size;
+        let chr_rom = copy_slice(&data[pos..pos + header.chr_rom_size]);
+
+        Ok(Rom {
+            header: header,
+            trainer: trainer,
+            prg_rom: prg_rom,
+            chr_rom: chr_rom,
+        })
+    }
+
+    // CRC32 of PRG and CHR ROM, which unlike the file's doesn't change when
+    // a header gets fixed up. Save states and movies are tied to it.
+    pub fn hash(&self) -> u32 {
+        let mut crc = !0u32;
+        for &byte in self.prg_rom.iter().chain(self.chr_rom.iter()) {
+            crc = crc32_byte(crc, byte);
+        }
+        !crc
+    }
+
+    // MD5 of PRG and CHR ROM, the same as FCEUX puts in its movies.
+    pub fn md5(&self) -> [u8; 16] {
+        let mut data: Vec<u8> = self.prg_rom.iter().chain(self.chr_rom.iter()).cloned().collect();
+        let bit_len = (data.len() as u64).wrapping_mul(8);
+        data.push(0x80);
+        while data.len() % 64 != 56 {
+            data.push(0);
+        }
+        for i in 0..8 {
+            data.push((bit_len >> (i * 8)) as u8);
+        }
+
+        let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+        for block in data.chunks(64) {
+            let mut words = [0u32; 16];
+            for (i, word) in words.iter_mut().enumerate() {
+                *word = block[i * 4] as u32 | (block[i * 4 + 1] as u32) << 8 |
+                        (block[i * 4 + 2] as u32) << 16 | (block[i * 4 + 3] as u32) << 24;
+            }
+
+            let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+            for i in 0..64 {
+                let (f, g) = match i / 16 {
+                    0 => ((b & c) | (!b & d), i),
+                    1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                    2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                    _ => (c ^ (b | !d), (7 * i) % 16),
+                };
+                let rotated = a.wrapping_add(f)
+                    .wrapping_add(MD5_K[i])
+                    .wrapping_add(words[g])
+                    .rotate_left(MD5_SHIFTS[i / 16][i % 4]);
+                a = d;
+                d = c;
+                c = b;
+                b = b.wrapping_add(rotated);
+            }
+            state[0] = state[0].wrapping_add(a);
+            state[1] = state[1].wrapping_add(b);
+            state[2] = state[2].wrapping_add(c);
+            state[3] = state[3].wrapping_add(d);
+        }
+
+        let mut digest = [0; 16];
+        for i in 0..16 {
+            digest[i] = (state[i / 4] >> ((i % 4) * 8)) as u8;
+        }
+        digest
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| crc32_byte(crc, byte))
+}
+
+fn crc32_byte(mut crc: u32, byte: u8) -> u32 {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+        let mask = (crc & 1).wrapping_neg();
+        crc = (crc >> 1) ^ (0xEDB88320 & mask);
+    }
+    crc
+}
+
+const MD5_SHIFTS: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+fn copy_slice(data: &[u8]) -> Box<[u8]> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(data);
+    buffer.into_boxed_slice()
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref e) => write!(f, "couldn't read the ROM file: {}", e),
+            RomError::BadMagic => write!(f, "not an iNES file, the header doesn't start with NES<EOF>"),
+            RomError::Truncated { expected, actual } => {
+                write!(f, "ROM file is truncated, expected {} bytes but found {}", expected, actual)
+            }
+            RomError::NoPrgRom => write!(f, "the header says there's no PRG ROM"),
+            RomError::PrgRomTooSmall { size, min } => {
+                write!(f, "{} bytes of PRG ROM is too small for the mapper, it needs {}", size, min)
+            }
+            RomError::UnsupportedMapper(n) => write!(f, "mapper {} is unimplemented", n),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn description(&self) -> &str {
+        match *self {
+            RomError::Io(_) => "couldn't read the ROM file",
+            RomError::BadMagic => "not an iNES file",
+            RomError::Truncated { .. } => "ROM file is truncated",
+            RomError::NoPrgRom => "no PRG ROM",
+            RomError::PrgRomTooSmall { .. } => "PRG ROM too small for the mapper",
+            RomError::UnsupportedMapper(_) => "unsupported mapper",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            RomError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+
+    fn header(bytes: [u8; 12]) -> [u8; HEADER_LEN] {
+        let mut data = [0; HEADER_LEN];
+        data[0..4].copy_from_slice(MAGIC);
+        data[4..].copy_from_slice(&bytes);
+        data
+    }
+
+    fn rom(header: &[u8], prg: usize, chr: usize) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(HEADER_LEN + prg + chr, 0);
+        data
+    }
+
+    #[test]
+    fn ines_sizes_and_flags() {
+        let data = header([2, 1, 0x13, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.format, RomFormat::INes);
+        assert_eq!(h.prg_rom_size, 0x8000);
+        assert_eq!(h.chr_rom_size, 0x2000);
+        assert_eq!(h.mapper, 0x41);
+        assert!(h.vertical_mirroring && h.battery && !h.trainer);
+        assert_eq!(h.prg_nvram_size, 0x2000);
+        assert_eq!(h.prg_ram_size, 0);
+        assert_eq!(h.chr_ram_size, 0);
+    }
+
+    #[test]
+    fn ines_without_chr_rom_gets_chr_ram() {
+        let data = header([1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.chr_ram_size, 0x2000);
+        assert_eq!(h.prg_ram_size, 0x4000);
+    }
+
+    #[test]
+    fn junk_in_header_ignores_upper_mapper_nibble() {
+        let mut data = header([1, 1, 0x10, 0x40, 0, 1, 0, 0, 0, 0, 0, 0]);
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.format, RomFormat::INes);
+        assert_eq!(h.mapper, 1);
+        assert_eq!(h.timing, Timing::Ntsc);
+        assert_eq!(h.prg_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn nes20_sizes() {
+        // mapper $123 submapper 4, 0x102 PRG units and 3 CHR units,
+        // 8KB of PRG RAM, 8KB PRG NVRAM and 2KB CHR RAM, PAL
+        let data = header([0x02, 0x03, 0x32, 0x28, 0x41, 0x01, 0x77, 0x05, 1, 0, 0, 0]);
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.format, RomFormat::Nes20);
+        assert_eq!(h.mapper, 0x123);
+        assert_eq!(h.submapper, 4);
+        assert_eq!(h.prg_rom_size, 0x102 * PRG_ROM_UNIT);
+        assert_eq!(h.chr_rom_size, 3 * CHR_ROM_UNIT);
+        assert_eq!(h.prg_ram_size, 0x2000);
+        assert_eq!(h.prg_nvram_size, 0x2000);
+        assert_eq!(h.chr_ram_size, 0x800);
+        assert_eq!(h.chr_nvram_size, 0);
+        assert_eq!(h.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn nes20_exponent_sizes() {
+        // PRG 2^13 * 3 bytes, CHR 2^10 * 1
+        let data = header([13 << 2 | 1, 10 << 2, 0, 0x08, 0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.prg_rom_size, 0x6000);
+        assert_eq!(h.chr_rom_size, 0x400);
+
+        // too big to be real, caught when the data isn't there
+        let data = header([63 << 2 | 3, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+        let h = RomHeader::parse(&data).unwrap();
+        assert_eq!(h.prg_rom_size, usize::max_value());
+        match Rom::parse(&data) {
+            Err(RomError::Truncated { .. }) => (),
+            _ => panic!("expected a truncated ROM"),
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = header([1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data[3] = 0;
+        match RomHeader::parse(&data) {
+            Err(RomError::BadMagic) => (),
+            _ => panic!("expected bad magic"),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        match RomHeader::parse(&MAGIC[..]) {
+            Err(RomError::Truncated { expected: HEADER_LEN, actual: 4 }) => (),
+            _ => panic!("expected a truncated header"),
+        }
+
+        let data = header([2, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let full = TRAINER_LEN + 0x8000 + 0x2000;
+        assert!(Rom::parse(&rom(&data, full, 0)).is_ok());
+        match Rom::parse(&rom(&data, full - 1, 0)) {
+            Err(RomError::Truncated { expected, actual }) => {
+                assert_eq!(expected, HEADER_LEN + full);
+                assert_eq!(actual, HEADER_LEN + full - 1);
+            }
+            _ => panic!("expected a truncated ROM"),
+        }
+    }
+
+    #[test]
+    fn no_prg_rom() {
+        let data = header([0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        match RomHeader::parse(&data) {
+            Err(RomError::NoPrgRom) => (),
+            _ => panic!("expected no PRG ROM"),
+        }
+    }
+
+    #[test]
+    fn prg_rom_smaller_than_a_bank() {
+        // NES 2.0 8KB of PRG on boards with 16KB banks
+        for &mapper_number in [1u8, 2, 4].iter() {
+            let data = header([3 << 2 | 0, 0, mapper_number << 4, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+            let data = rom(&data, 8, 0);
+            let rom = Rom::parse(&data).unwrap();
+            match mapper::new_mapper(&rom.header, rom.prg_rom, rom.chr_rom) {
+                Err(RomError::PrgRomTooSmall { size: 8, min: 0x4000 }) => (),
+                _ => panic!("expected mapper {} to turn down 8 bytes of PRG", mapper_number),
+            }
+        }
+
+        // NROM copes with anything
+        let data = header([3 << 2 | 0, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+        let rom = Rom::parse(&rom(&data, 8, 0)).unwrap();
+        let mapper = mapper::new_mapper(&rom.header, rom.prg_rom, rom.chr_rom).unwrap();
+        assert_eq!(mapper.borrow().cpu_read(0xFFFC), 0);
+    }
+}
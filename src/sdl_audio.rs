@@ -0,0 +1,99 @@
+use std::cmp;
+use std::collections::VecDeque;
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+// How far the sample rate may be bent to steer the queue, 0.5% is well
+// below what anyone hears as a pitch change.
+const MAX_RATE_DELTA: f32 = 0.005;
+
+// SDL pulls samples from here on its own thread, the device lock keeps us
+// from touching the queue at the same time.
+struct Queue {
+    samples: VecDeque<f32>,
+    // repeated when the queue runs dry so an underrun doesn't click
+    last: f32,
+}
+
+impl AudioCallback for Queue {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            if let Some(next) = self.samples.pop_front() {
+                self.last = next;
+            }
+            *sample = self.last;
+        }
+    }
+}
+
+// Plays the emulator's samples. The frame limiter and the sound card run
+// off different clocks, so instead of letting the queue drift we keep it
+// near the latency target by nudging the rate the APU produces samples at.
+pub struct AudioOut {
+    device: AudioDevice<Queue>,
+    sample_rate: u32,
+    // queued samples we aim for, which is the output latency
+    target: usize,
+}
+
+impl AudioOut {
+    pub fn open(audio: &AudioSubsystem, sample_rate: u32, latency_ms: u32) -> Result<AudioOut, String> {
+        let target = (sample_rate * latency_ms / 1000) as usize;
+        // SDL's own buffer, a power of two well under the target
+        let mut buffer_len = 256;
+        while buffer_len * 4 <= target && buffer_len < 4096 {
+            buffer_len *= 2;
+        }
+
+        let desired = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(1),
+            samples: Some(buffer_len as u16),
+        };
+
+        let mut obtained_rate = sample_rate;
+        let device = try!(audio.open_playback(None, &desired, |spec| {
+            obtained_rate = spec.freq as u32;
+            Queue {
+                samples: VecDeque::new(),
+                last: 0.0,
+            }
+        }));
+        device.resume();
+
+        Ok(AudioOut {
+            device: device,
+            sample_rate: obtained_rate,
+            target: cmp::max(target, buffer_len),
+        })
+    }
+
+    // The rate the device actually runs at, which may differ from the one
+    // asked for.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Queues a frame's worth of samples and returns the rate the next frame
+    // should be produced at.
+    pub fn queue(&mut self, samples: &[f32]) -> u32 {
+        let mut queue = self.device.lock();
+        queue.samples.extend(samples.iter().cloned());
+
+        // After a stall (a window drag, a slow frame) drop what's piled up
+        // rather than lag behind for good.
+        let max_len = self.target * 4;
+        let queued = queue.samples.len();
+        if queued > max_len {
+            queue.samples.drain(..queued - max_len);
+        }
+
+        let queued = queue.samples.len() as f32;
+        let error = (queued - self.target as f32) / self.target as f32;
+        let error = error.max(-1.0).min(1.0);
+        (self.sample_rate as f32 * (1.0 - MAX_RATE_DELTA * error)) as u32
+    }
+}
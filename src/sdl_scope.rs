@@ -0,0 +1,68 @@
+use std::cmp;
+
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{BlendMode, Renderer};
+
+// How many scope points fit across the window, about 8ms.
+const WIDTH: usize = 512;
+// the APU's own channels, the cartridge's sound chip isn't sampled
+const LANES: usize = 5;
+
+// pulse1, pulse2, triangle, noise, dmc
+const COLORS: [(u8, u8, u8); LANES] = [(255, 90, 90), (255, 180, 60), (90, 200, 255), (220, 220, 220), (140, 255, 120)];
+const MUTED: (u8, u8, u8) = (90, 90, 90);
+
+// Draws an oscilloscope lane per channel over the bottom half of the
+// window, muted channels in grey. Each lane starts at a rising edge so a
+// steady note stands still instead of scrolling.
+pub fn draw(renderer: &mut Renderer, points: &[[u8; 5]], mask: u8) {
+    let (width, height) = match renderer.output_size() {
+        Ok(size) => size,
+        Err(_) => return,
+    };
+    let lane_height = height / 2 / LANES as u32;
+    let top = height - lane_height * LANES as u32;
+
+    renderer.set_blend_mode(BlendMode::Blend);
+    renderer.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    let _ = renderer.fill_rect(Rect::new(0, top as i32, width, height - top));
+    renderer.set_blend_mode(BlendMode::None);
+
+    for channel in 0..LANES {
+        let max = if channel == 4 { 127 } else { 15 };
+        let bottom = top + lane_height * (channel as u32 + 1) - 2;
+        let scale = lane_height - 4;
+
+        let start = trigger(points, channel);
+        let end = cmp::min(start + WIDTH, points.len());
+        let line: Vec<Point> = points[start..end]
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let x = i as u32 * width / WIDTH as u32;
+                let y = bottom - point[channel] as u32 * scale / max;
+                Point::new(x as i32, y as i32)
+            })
+            .collect();
+
+        let (r, g, b) = if mask & (1 << channel) != 0 { COLORS[channel] } else { MUTED };
+        renderer.set_draw_color(Color::RGB(r, g, b));
+        if line.len() > 1 {
+            let _ = renderer.draw_lines(&line);
+        }
+    }
+}
+
+// The first point where the channel rises through the middle of its range,
+// or the latest WIDTH points if it never does.
+fn trigger(points: &[[u8; 5]], channel: usize) -> usize {
+    let last_start = points.len().saturating_sub(WIDTH);
+    let low = points.iter().map(|p| p[channel]).min().unwrap_or(0);
+    let high = points.iter().map(|p| p[channel]).max().unwrap_or(0);
+    let middle = (low as u16 + high as u16) / 2;
+
+    (1..last_start + 1)
+        .find(|&i| points[i - 1][channel] as u16 <= middle && points[i][channel] as u16 > middle)
+        .unwrap_or(last_start)
+}
@@ -0,0 +1,311 @@
+use std::error::Error;
+use std::fmt;
+
+// Save states. Every part of the console writes its fields in a fixed
+// order, little endian and untagged, so anything that changes what gets
+// saved needs VERSION bumped.
+const MAGIC: &'static [u8] = b"OXST";
+pub const VERSION: u32 = 5;
+
+#[derive(Debug)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    // saved from a different ROM than the one loaded
+    WrongRom { expected: u32, actual: u32 },
+    Truncated,
+    // a value no part of the console would have saved
+    Corrupt,
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    // Starts a state with the header, rom_hash being the cartridge's.
+    pub fn new(rom_hash: u32) -> StateWriter {
+        let mut writer = StateWriter { data: Vec::new() };
+        writer.data.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+        writer.u32(rom_hash);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.u8(value as u8);
+        self.u8((value >> 8) as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.u16(value as u16);
+        self.u16((value >> 16) as u16);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.u64(value as u64);
+    }
+
+    // Length prefixed, so loading can check it against what it expects.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header and positions the reader after it.
+    pub fn new(data: &'a [u8], rom_hash: u32) -> Result<StateReader<'a>, StateError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let mut reader = StateReader {
+            data: data,
+            pos: MAGIC.len(),
+        };
+
+        let version = try!(reader.u32());
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let saved_hash = try!(reader.u32());
+        if saved_hash != rom_hash {
+            return Err(StateError::WrongRom {
+                expected: rom_hash,
+                actual: saved_hash,
+            });
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        match self.data.get(self.pos) {
+            Some(&value) => {
+                self.pos += 1;
+                Ok(value)
+            }
+            None => Err(StateError::Truncated),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match try!(self.u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let lo = try!(self.u8()) as u16;
+        let hi = try!(self.u8()) as u16;
+        Ok(hi << 8 | lo)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let lo = try!(self.u16()) as u32;
+        let hi = try!(self.u16()) as u32;
+        Ok(hi << 16 | lo)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let lo = try!(self.u32()) as u64;
+        let hi = try!(self.u32()) as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    pub fn i64(&mut self) -> Result<i64, StateError> {
+        self.u64().map(|value| value as i64)
+    }
+
+    // Fills out from a block written by StateWriter::bytes, which has to
+    // be the same length.
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let len = try!(self.u32()) as usize;
+        if len != out.len() {
+            return Err(StateError::Corrupt);
+        }
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        out.copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(())
+    }
+
+    // Anything left over means the state didn't come from this version.
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt)
+        }
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(v) => {
+                write!(f, "save state version {} isn't supported, expected {}", v, VERSION)
+            }
+            StateError::WrongRom { expected, actual } => {
+                write!(f, "save state is for ROM {:08X}, not {:08X}", actual, expected)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::BadMagic => "not a save state",
+            StateError::UnsupportedVersion(_) => "unsupported save state version",
+            StateError::WrongRom { .. } => "save state is for a different ROM",
+            StateError::Truncated => "save state is truncated",
+            StateError::Corrupt => "save state is corrupt",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: u32 = 0x12345678;
+
+    fn sample() -> Vec<u8> {
+        let mut out = StateWriter::new(HASH);
+        out.u8(0xAB);
+        out.bool(true);
+        out.u16(0xBEEF);
+        out.u32(0xDEADBEEF);
+        out.u64(0x0123456789ABCDEF);
+        out.i64(-2);
+        out.bytes(&[1, 2, 3]);
+        out.into_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let mut input = StateReader::new(&data, HASH).unwrap();
+        assert_eq!(input.u8().unwrap(), 0xAB);
+        assert_eq!(input.bool().unwrap(), true);
+        assert_eq!(input.u16().unwrap(), 0xBEEF);
+        assert_eq!(input.u32().unwrap(), 0xDEADBEEF);
+        assert_eq!(input.u64().unwrap(), 0x0123456789ABCDEF);
+        assert_eq!(input.i64().unwrap(), -2);
+        let mut bytes = [0; 3];
+        input.bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        input.finish().unwrap();
+    }
+
+    #[test]
+    fn little_endian_after_the_header() {
+        let mut out = StateWriter::new(HASH);
+        out.u32(0x11223344);
+        let data = out.into_bytes();
+        assert_eq!(&data[..4], MAGIC);
+        assert_eq!(&data[12..], &[0x44, 0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = sample();
+        data[0] = b'X';
+        match StateReader::new(&data, HASH) {
+            Err(StateError::BadMagic) => (),
+            _ => panic!("expected bad magic"),
+        }
+        match StateReader::new(b"OX", HASH) {
+            Err(StateError::BadMagic) => (),
+            _ => panic!("expected bad magic"),
+        }
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut data = sample();
+        data[4] = (VERSION + 1) as u8;
+        match StateReader::new(&data, HASH) {
+            Err(StateError::UnsupportedVersion(v)) => assert_eq!(v, VERSION + 1),
+            _ => panic!("expected an unsupported version"),
+        }
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let data = sample();
+        match StateReader::new(&data, 0x87654321) {
+            Err(StateError::WrongRom { expected: 0x87654321, actual: HASH }) => (),
+            _ => panic!("expected the wrong ROM"),
+        }
+    }
+
+    #[test]
+    fn truncated_and_leftovers() {
+        let data = sample();
+        let mut input = StateReader::new(&data[..data.len() - 1], HASH).unwrap();
+        input.u8().unwrap();
+        input.bool().unwrap();
+        input.u16().unwrap();
+        input.u32().unwrap();
+        input.u64().unwrap();
+        input.i64().unwrap();
+        match input.bytes(&mut [0; 3]) {
+            Err(StateError::Truncated) => (),
+            _ => panic!("expected a truncated state"),
+        }
+
+        let mut input = StateReader::new(&data, HASH).unwrap();
+        input.u8().unwrap();
+        match input.finish() {
+            Err(StateError::Corrupt) => (),
+            _ => panic!("expected leftovers to be corrupt"),
+        }
+    }
+
+    #[test]
+    fn corrupt_values() {
+        let mut out = StateWriter::new(HASH);
+        out.u8(2);
+        out.bytes(&[1, 2, 3]);
+        let data = out.into_bytes();
+        let mut input = StateReader::new(&data, HASH).unwrap();
+        match input.bool() {
+            Err(StateError::Corrupt) => (),
+            _ => panic!("expected 2 to be a corrupt bool"),
+        }
+        match input.bytes(&mut [0; 4]) {
+            Err(StateError::Corrupt) => (),
+            _ => panic!("expected a length mismatch to be corrupt"),
+        }
+    }
+}
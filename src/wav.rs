@@ -0,0 +1,114 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use apu::CHANNEL_NAMES;
+
+// Size of the RIFF header plus the fmt and data chunk headers.
+const HEADER_LEN: u32 = 44;
+
+// Writes mono 16 bit PCM. The header goes out with empty sizes up front,
+// finish patches them once the length is known.
+// http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter {
+    file: BufWriter<File>,
+    // samples written so far
+    len: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(try!(File::create(path)));
+
+        try!(file.write_all(b"RIFF"));
+        try!(write_u32(&mut file, HEADER_LEN - 8));
+        try!(file.write_all(b"WAVE"));
+
+        try!(file.write_all(b"fmt "));
+        try!(write_u32(&mut file, 16));
+        try!(write_u16(&mut file, 1)); // PCM
+        try!(write_u16(&mut file, 1)); // mono
+        try!(write_u32(&mut file, sample_rate));
+        try!(write_u32(&mut file, sample_rate * 2)); // bytes per second
+        try!(write_u16(&mut file, 2)); // bytes per sample
+        try!(write_u16(&mut file, 16)); // bits per sample
+
+        try!(file.write_all(b"data"));
+        try!(write_u32(&mut file, 0));
+
+        Ok(WavWriter {
+            file: file,
+            len: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let sample = sample.max(-1.0).min(1.0);
+            try!(write_u16(&mut self.file, (sample * 32767.0) as i16 as u16));
+        }
+        self.len += samples.len() as u32;
+        Ok(())
+    }
+
+    // Fills in the chunk sizes and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_len = self.len * 2;
+        try!(self.file.seek(SeekFrom::Start(4)));
+        try!(write_u32(&mut self.file, HEADER_LEN - 8 + data_len));
+        try!(self.file.seek(SeekFrom::Start(HEADER_LEN as u64 - 4)));
+        try!(write_u32(&mut self.file, data_len));
+        self.file.flush()
+    }
+}
+
+// The mix going to path, and with channels set each APU channel on its own
+// next to it, game.wav gets game-pulse1.wav, game-triangle.wav and so on.
+pub struct Recorder {
+    mix: WavWriter,
+    channels: Vec<WavWriter>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, sample_rate: u32, channels: bool) -> io::Result<Recorder> {
+        let mix = try!(WavWriter::create(path, sample_rate));
+
+        let mut writers = Vec::new();
+        if channels {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("audio");
+            for name in CHANNEL_NAMES.iter() {
+                let channel_path = path.with_file_name(format!("{}-{}.wav", stem, name));
+                writers.push(try!(WavWriter::create(&channel_path, sample_rate)));
+            }
+        }
+
+        Ok(Recorder {
+            mix: mix,
+            channels: writers,
+        })
+    }
+
+    pub fn write(&mut self, mix: &[f32], channels: &[Vec<f32>]) -> io::Result<()> {
+        try!(self.mix.write(mix));
+        for (writer, samples) in self.channels.iter_mut().zip(channels) {
+            try!(writer.write(samples));
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        try!(self.mix.finish());
+        for writer in self.channels {
+            try!(writer.finish());
+        }
+        Ok(())
+    }
+}
+
+fn write_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}